@@ -15,6 +15,6 @@ fn grpc_build_proto() -> Result<()> {
     tonic_build::configure()
         .build_client(false)
         .build_server(true)
-        .compile(&["proto/minkv.proto"], &["proto"])?;
+        .compile_protos(&["proto/minkv.proto"], &["proto"])?;
     Ok(())
 }
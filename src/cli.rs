@@ -1,2 +1,13 @@
 #![allow(clippy::module_inception)]
+pub mod aof;
+pub mod check;
+pub mod compact;
+pub mod diff;
 pub mod cli;
+pub mod dump;
+pub mod export;
+pub mod import;
+pub mod load;
+pub mod rdb;
+pub mod repair;
+pub mod stats;
@@ -0,0 +1,368 @@
+use super::{export, import, rdb};
+use crate::config::Config;
+use crate::db_store::{self, open_read_only_store, Op};
+use crate::server::Server;
+use crate::util::lock::OwnerLock;
+use anyhow::Context;
+use log::*;
+use redis_protocol::resp2::{
+    decode::decode,
+    encode::encode,
+    types::{OwnedFrame, Resp2Frame},
+};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+
+const READ_SIZE: usize = 64 * 1024;
+const MANIFEST_EXT: &str = "manifest";
+
+// 将一条命令编码为 RESP 数组追加到 out
+pub(crate) fn encode_command(out: &mut Vec<u8>, args: &[&[u8]]) -> anyhow::Result<()> {
+    let frame = OwnedFrame::Array(
+        args.iter()
+            .map(|arg| OwnedFrame::BulkString(arg.to_vec()))
+            .collect(),
+    );
+    let start = out.len();
+    out.resize(start + frame.encode_len(), 0);
+    encode(&mut out[start..], &frame)?;
+    Ok(())
+}
+
+// 从 AOF 中逐条读取 RESP 命令，跳过 redis 7 写入的 #TS 时间戳注释
+struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,  // buf 中已解析的字节数，读取新数据前才整体移除
+    offset: u64, // buf[pos] 在文件中的位置
+}
+
+impl<R: Read> FrameReader<R> {
+    fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+        self.offset += n as u64;
+    }
+
+    fn next(&mut self) -> anyhow::Result<Option<OwnedFrame>> {
+        loop {
+            let pending = &self.buf[self.pos..];
+            if pending.first() == Some(&b'#') {
+                if let Some(end) = pending.windows(2).position(|w| w == b"\r\n") {
+                    self.consume(end + 2);
+                    continue;
+                }
+            } else if let Some((frame, n)) = decode(pending)
+                .with_context(|| format!("invalid command at offset {}", self.offset))?
+            {
+                self.consume(n);
+                return Ok(Some(frame));
+            }
+
+            let mut chunk = vec![0; READ_SIZE];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                // 与 redis 的 aof-load-truncated 一致，忽略末尾写入中断的命令
+                if self.pos < self.buf.len() {
+                    warn!(
+                        "ignore truncated command at offset {} ({} bytes)",
+                        self.offset,
+                        self.buf.len() - self.pos
+                    );
+                }
+                return Ok(None);
+            }
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn command_args(frame: &OwnedFrame) -> Vec<&[u8]> {
+    match frame {
+        OwnedFrame::Array(arr) => arr
+            .iter()
+            .filter_map(|f| match f {
+                OwnedFrame::BulkString(b) => Some(b.as_slice()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaySummary {
+    pub applied: u64,
+    pub skipped: u64, // 其它 db 中的命令
+    pub expired: u64, // RDB 前导数据中已过期的 key
+}
+
+// 通过与服务相同的命令分发逐条执行 AOF 中的命令
+struct Replayer {
+    server: Server,
+    db: u64,
+    selected: u64,
+    summary: ReplaySummary,
+}
+
+impl Replayer {
+    fn apply(&mut self, frame: &OwnedFrame) -> anyhow::Result<()> {
+        let args = command_args(frame);
+        let name = args
+            .first()
+            .map(|n| String::from_utf8_lossy(n).to_uppercase())
+            .unwrap_or_default();
+        match name.as_str() {
+            "SELECT" => {
+                let db = args
+                    .get(1)
+                    .map(|db| String::from_utf8_lossy(db).to_string());
+                self.selected = db
+                    .as_deref()
+                    .and_then(|db| db.parse().ok())
+                    .with_context(|| format!("invalid SELECT {:?}", db))?;
+                return Ok(());
+            }
+            // 没有事务，MULTI 中的命令按顺序执行
+            "MULTI" | "EXEC" => return Ok(()),
+            _ => {}
+        }
+        if self.selected != self.db {
+            self.summary.skipped += 1;
+            return Ok(());
+        }
+
+        match self.server.handle_frame(frame) {
+            Ok(OwnedFrame::Error(e)) | Err(e) => {
+                anyhow::bail!("{} failed: {}", name, e)
+            }
+            Ok(_) => {
+                self.summary.applied += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // 回放一个 AOF 文件，开头可能是 RDB 格式的前导数据
+    fn replay_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        if reader.fill_buf()?.starts_with(b"REDIS") {
+            let now = crate::util::time::current_milliseconds();
+            rdb::parse(&mut reader, |entry| {
+                if entry.db != self.db {
+                    self.summary.skipped += 1;
+                    return Ok(());
+                }
+                if entry.expire_at != 0 && entry.expire_at <= now {
+                    self.summary.expired += 1;
+                    return Ok(());
+                }
+                let expire_at = entry.expire_at.to_string();
+                let mut args: Vec<&[u8]> = vec![b"SET", &entry.key, &entry.value];
+                if entry.expire_at != 0 {
+                    args.extend_from_slice(&[b"PXAT", expire_at.as_bytes()]);
+                }
+                self.apply(&OwnedFrame::Array(
+                    args.into_iter()
+                        .map(|a| OwnedFrame::BulkString(a.to_vec()))
+                        .collect(),
+                ))
+            })
+            .with_context(|| format!("{}: RDB preamble", path.display()))?;
+        }
+
+        let mut frames = FrameReader::new(reader);
+        self.selected = 0;
+        while let Some(frame) = frames.next()? {
+            let offset = frames.offset;
+            self.apply(&frame).with_context(|| {
+                format!("{}: command ending at offset {}", path.display(), offset)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// redis 7 的 AOF 目录，按 manifest 中的顺序返回 base 和 incr 文件
+fn aof_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let manifest = fs::read_dir(path)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .find(|p| p.extension().is_some_and(|ext| ext == MANIFEST_EXT))
+        .with_context(|| format!("no manifest found in {}", path.display()))?;
+
+    let (mut base, mut incr) = (Vec::new(), Vec::new());
+    for line in fs::read_to_string(&manifest)?.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = |name: &str| {
+            fields
+                .chunks(2)
+                .find(|kv| kv[0] == name)
+                .and_then(|kv| kv.get(1).copied())
+        };
+        let (Some(file), Some(kind)) = (field("file"), field("type")) else {
+            continue;
+        };
+        let seq: u64 = field("seq").and_then(|s| s.parse().ok()).unwrap_or(0);
+        match kind {
+            "b" => base.push(path.join(file)),
+            "i" => incr.push((seq, path.join(file))),
+            // 历史文件已合并到 base 中
+            _ => {}
+        }
+    }
+    incr.sort();
+    base.extend(incr.into_iter().map(|(_, file)| file));
+    Ok(base)
+}
+
+// 将 AOF 中指定 db 的命令回放到 db_dir，需在服务停止时执行
+pub fn import_aof(config: Config, path: &Path, db: u64) -> anyhow::Result<ReplaySummary> {
+    if let Some(stream) = import::connect_server(&config)? {
+        anyhow::bail!(
+            "server is running at {}, stop it before replaying an AOF",
+            stream.peer_addr()?
+        );
+    }
+    let _owner = OwnerLock::acquire(&config.owner_lock_filepath())?;
+    let files = aof_files(path)?;
+
+    let config = Arc::new(config);
+    let (tx, rx) = mpsc::channel();
+    let store = db_store::open_store(Arc::clone(&config), tx, rx)?;
+    let mut replayer = Replayer {
+        server: Server::new(config, Arc::clone(&store)),
+        db,
+        selected: 0,
+        summary: ReplaySummary::default(),
+    };
+    let result = files.iter().try_for_each(|file| replayer.replay_file(file));
+    store.write().unwrap().close();
+    result?;
+    Ok(replayer.summary)
+}
+
+// 将所有未过期的 key 写为 SET/PEXPIREAT 命令
+pub fn export_aof<W: Write>(config: Arc<Config>, out: &mut W) -> anyhow::Result<u64> {
+    let store = open_read_only_store(config)?;
+    let mut exported = 0;
+    let mut buf = Vec::new();
+    for key in store.keys() {
+        let Some(entry) = export::live_entry(&store, &key) else {
+            continue;
+        };
+        buf.clear();
+        encode_command(&mut buf, &[b"SET", &key, &entry.value])?;
+        if entry.timestamp != 0 {
+            let expire_at = entry.timestamp.to_string();
+            encode_command(&mut buf, &[b"PEXPIREAT", &key, expire_at.as_bytes()])?;
+        }
+        out.write_all(&buf)?;
+        exported += 1;
+    }
+    out.flush()?;
+    Ok(exported)
+}
+
+// minkv import-aof
+pub fn run_import(config: Config, path: &Path, db: u64) -> anyhow::Result<()> {
+    let summary = import_aof(config, path, db)?;
+    println!(
+        "applied {} commands from db {}, {} commands in other dbs skipped, {} expired keys",
+        summary.applied, db, summary.skipped, summary.expired
+    );
+    Ok(())
+}
+
+// minkv export-aof，未指定输出文件时写到标准输出
+pub fn run_export(config: Config, output: Option<&Path>) -> anyhow::Result<()> {
+    let config = Arc::new(config);
+    let exported = match output {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            let exported = export_aof(config, &mut out)?;
+            out.into_inner()?.sync_all()?;
+            exported
+        }
+        None => export_aof(config, &mut BufWriter::new(io::stdout().lock()))?,
+    };
+    eprintln!("exported {} keys", exported);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn config(dir: &Path, name: &str) -> anyhow::Result<Config> {
+        test_config(
+            &dir.join(name),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 1\n",
+        )
+    }
+
+    #[test]
+    fn aof_replay_and_export() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let expire_at = (crate::util::time::current_milliseconds() + 600_000).to_string();
+        let mut aof = Vec::new();
+        encode_command(&mut aof, &[b"SELECT", b"0"])?;
+        encode_command(&mut aof, &[b"SET", b"a", b"1"])?;
+        encode_command(&mut aof, &[b"SET", b"n", b"1"])?;
+        encode_command(&mut aof, &[b"MULTI"])?;
+        encode_command(
+            &mut aof,
+            &[b"SET", b"b", b"2", b"PXAT", expire_at.as_bytes()],
+        )?;
+        encode_command(&mut aof, &[b"EXEC"])?;
+        encode_command(&mut aof, &[b"SELECT", b"1"])?;
+        encode_command(&mut aof, &[b"SET", b"c", b"3"])?;
+        encode_command(&mut aof, &[b"SELECT", b"0"])?;
+        aof.extend_from_slice(b"#TS:1700000000\r\n");
+        encode_command(&mut aof, &[b"INCR", b"n"])?;
+        encode_command(&mut aof, &[b"DEL", b"a"])?;
+        aof.extend_from_slice(b"*3\r\n$3\r\nSET\r\n");
+        let path = dir.path().join("appendonly.aof");
+        fs::write(&path, &aof)?;
+
+        let summary = import_aof(config(dir.path(), "db")?, &path, 0)?;
+        assert_eq!(5, summary.applied);
+        assert_eq!(1, summary.skipped);
+
+        let mut out = Vec::new();
+        assert_eq!(
+            2,
+            export_aof(Arc::new(config(dir.path(), "db")?), &mut out)?
+        );
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.contains("PEXPIREAT\r\n$1\r\nb\r\n"));
+        assert!(!text.contains("\r\na\r\n"));
+
+        // 导出的命令可以再次回放
+        fs::write(&path, &out)?;
+        let summary = import_aof(config(dir.path(), "copy")?, &path, 0)?;
+        assert_eq!(3, summary.applied);
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::new_store(Arc::new(config(dir.path(), "copy")?), tx, rx)?;
+        assert_eq!(b"2".to_vec(), store.get(b"n")?);
+        assert_eq!(expire_at, store.get_entry(b"b")?.timestamp.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use crate::config::Config;
+use crate::entry::entry::EntryFile;
+use crate::entry::hint::HintFile;
+use crate::store::merge;
+use crate::util::lock::DirLock;
+use fs2::FileExt;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MERGE_LOCK_FILE: &str = "lock";
+const PROBE_FILE: &str = ".probe";
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,   // 数据损坏或无法打开，检查失败
+    Warning, // 不影响使用，但需要关注
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    pub file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct Report {
+    pub db_dir: String,
+    pub data_files: usize,
+    pub records: u64,
+    pub hint_files: usize,
+    pub hints: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    fn add(&mut self, severity: Severity, file: &str, offset: Option<u64>, message: String) {
+        self.problems.push(Problem {
+            severity,
+            file: file.to_string(),
+            offset,
+            message,
+        });
+    }
+
+    fn error(&mut self, file: &str, offset: Option<u64>, message: impl Into<String>) {
+        self.add(Severity::Error, file, offset, message.into());
+    }
+
+    fn warning(&mut self, file: &str, message: impl Into<String>) {
+        self.add(Severity::Warning, file, None, message.into());
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == severity)
+            .count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "db_dir: {}", self.db_dir)?;
+        writeln!(
+            f,
+            "checked {} data files ({} records), {} hint files ({} entries)",
+            self.data_files, self.records, self.hint_files, self.hints
+        )?;
+        for p in &self.problems {
+            let severity = match p.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            match p.offset {
+                Some(offset) => {
+                    writeln!(f, "{:<8}{} @{}: {}", severity, p.file, offset, p.message)?
+                }
+                None => writeln!(f, "{:<8}{}: {}", severity, p.file, p.message)?,
+            }
+        }
+        write!(f, "{} errors, {} warnings", self.errors(), self.warnings())
+    }
+}
+
+// 数据文件中一条完整记录的大小和 key
+struct RecordInfo {
+    size: u64,
+    key: Vec<u8>,
+}
+
+// 逐条校验数据文件中的记录，返回所有完整记录
+fn check_data_file(
+    report: &mut Report,
+    name: &str,
+    path: &Path,
+) -> io::Result<HashMap<u64, RecordInfo>> {
+    let mut entries = EntryFile::new(File::open(path)?);
+    let mut records = HashMap::new();
+    for result in entries.iter() {
+        let size = result.entry.size() as u64;
+        records.insert(
+            result.value_pos,
+            RecordInfo {
+                size,
+                key: result.entry.key,
+            },
+        );
+        report.records += 1;
+    }
+    // 损坏区间之后的完整记录照常检查
+    for range in entries.damaged() {
+        report.error(name, Some(range.offset), range.reason.to_string());
+    }
+    Ok(records)
+}
+
+// 校验 hint 文件中的每一项都指向数据文件中的完整记录
+fn check_hint_file(
+    report: &mut Report,
+    name: &str,
+    path: &Path,
+    data_name: &str,
+    records: &HashMap<u64, RecordInfo>,
+) -> io::Result<()> {
+    let mut hints = HintFile::new(File::open(path)?);
+    let mut pos = 0;
+    for hint in hints.iter() {
+        report.hints += 1;
+        match records.get(&hint.value_pos) {
+            None => report.error(
+                name,
+                Some(pos),
+                format!(
+                    "points to {} offset {} which is not a valid record",
+                    data_name, hint.value_pos
+                ),
+            ),
+            Some(record) if record.size != hint.value_size => report.error(
+                name,
+                Some(pos),
+                format!(
+                    "size {} does not match record size {}",
+                    hint.value_size, record.size
+                ),
+            ),
+            Some(record) if record.key != hint.key => {
+                report.error(name, Some(pos), "key does not match record key")
+            }
+            _ => {}
+        }
+        pos += hint.size();
+    }
+    if let Some(offset) = hints.truncated() {
+        report.error(name, Some(offset), "truncated hint");
+    }
+    Ok(())
+}
+
+// 按序号列出数据文件和 hint 文件
+#[allow(clippy::type_complexity)]
+fn list_files(
+    config: &Config,
+    report: &mut Report,
+) -> io::Result<(BTreeMap<u16, PathBuf>, BTreeMap<u16, PathBuf>)> {
+    let data_re = Regex::new(&format!(r"^{}\.(\d+)$", regex::escape(config.file()))).unwrap();
+    let hint_re = Regex::new(r"^hint\.(\d+)$").unwrap();
+
+    let mut data_files = BTreeMap::new();
+    let mut hint_files = BTreeMap::new();
+    for dir in config.data_dirs() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let (files, caps) = match (data_re.captures(&name), hint_re.captures(&name)) {
+                (Some(caps), _) => (&mut data_files, caps),
+                (_, Some(caps)) => (&mut hint_files, caps),
+                _ => continue,
+            };
+            match caps[1].parse::<u16>() {
+                Ok(seq) if seq > 0 => {
+                    if let Some(other) = files.insert(seq, path) {
+                        report.error(
+                            &name,
+                            None,
+                            format!("also exists in {}", other.parent().unwrap().display()),
+                        );
+                    }
+                }
+                _ => report.error(&name, None, "invalid file sequence"),
+            }
+        }
+    }
+    Ok((data_files, hint_files))
+}
+
+// 异常退出或合并中断后遗留的文件
+fn check_leftovers(config: &Config, report: &mut Report) {
+    let merge_dir = config.merge_dir();
+    if merge::is_committed(config) {
+        report.error(
+            &merge_dir.display().to_string(),
+            None,
+            "committed merge not installed, open the store or run repair to finish it",
+        );
+    } else if merge_dir.exists() {
+        report.error(
+            &merge_dir.display().to_string(),
+            None,
+            "orphaned merge directory, merges are blocked until it is removed",
+        );
+    }
+
+    let probe = config.data_dir().join(PROBE_FILE);
+    if probe.exists() {
+        report.warning(&probe.display().to_string(), "leftover write probe file");
+    }
+
+    let snapshot_tmp = config.snapshot_filepath().with_extension("tmp");
+    if snapshot_tmp.exists() {
+        report.warning(
+            &snapshot_tmp.display().to_string(),
+            "leftover temporary snapshot file",
+        );
+    }
+
+    // 合并使用的锁文件，未被持有时说明是遗留的
+    let merge_lock = Path::new(MERGE_LOCK_FILE);
+    if let Ok(file) = File::open(merge_lock) {
+        if file.try_lock_exclusive().is_ok() {
+            report.warning(MERGE_LOCK_FILE, "leftover merge lock file");
+        }
+    }
+}
+
+// 只读检查 db_dir，不打开存储也不修改任何文件
+pub fn check(config: &Config) -> io::Result<Report> {
+    let mut report = Report {
+        db_dir: config.data_dir().display().to_string(),
+        ..Default::default()
+    };
+    let _lock = DirLock::shared(&config.lock_filepath())?;
+
+    check_leftovers(config, &mut report);
+    let (data_files, hint_files) = list_files(config, &mut report)?;
+
+    let mut prev = 0;
+    for (seq, path) in &data_files {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if *seq != prev + 1 {
+            report.warning(
+                &name,
+                format!(
+                    "sequence gap after {}.{} (expected after a merge)",
+                    config.file(),
+                    prev
+                ),
+            );
+        }
+        prev = *seq;
+
+        let records = check_data_file(&mut report, &name, path)?;
+        report.data_files += 1;
+        if let Some(hint_path) = hint_files.get(seq) {
+            let hint_name = hint_path.file_name().unwrap().to_string_lossy().to_string();
+            check_hint_file(&mut report, &hint_name, hint_path, &name, &records)?;
+            report.hint_files += 1;
+        }
+    }
+
+    for (seq, path) in &hint_files {
+        if !data_files.contains_key(seq) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            report.error(&name, None, "hint file without data file");
+        }
+    }
+
+    let active = config.get_active_filepath();
+    if active.exists() {
+        check_data_file(&mut report, config.file(), &active)?;
+        report.data_files += 1;
+    } else {
+        report.warning(config.file(), "active data file not found");
+    }
+
+    Ok(report)
+}
+
+// minkv check，发现错误时返回 Err 以非 0 状态码退出
+pub fn run(config: &Config, json: bool) -> anyhow::Result<()> {
+    let report = check(config)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+
+    if report.errors() > 0 {
+        anyhow::bail!("check found {} errors", report.errors());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::store::store::{new_store, Op};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn check_reports_damaged_records_and_leftovers() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for i in 0..20 {
+                store.set(format!("key{:02}", i).as_bytes(), b"value", 0)?;
+            }
+            store.compaction()?;
+            store.close();
+        }
+
+        let report = check(&config)?;
+        assert_eq!(0, report.errors(), "{}", report);
+        assert!(report.hint_files > 0);
+        assert_eq!(20, report.records);
+
+        // 修改 data.1 第一条记录的最后一个字节，并留下合并目录
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(config.get_filepath_by_seq(1))?;
+        file.seek(SeekFrom::Start(30))?;
+        file.write_all(b"X")?;
+        std::fs::create_dir_all(config.merge_dir())?;
+
+        let report = check(&config)?;
+        let errors: Vec<_> = report
+            .problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .map(|p| (p.file.as_str(), p.offset, p.message.as_str()))
+            .collect();
+        assert!(errors.contains(&("data.1", Some(0), "crc mismatch")));
+        assert!(errors.contains(&(
+            "hint.1",
+            Some(0),
+            "points to data.1 offset 0 which is not a valid record"
+        )));
+        assert!(errors.iter().any(|e| e.0.ends_with(".merge")));
+        assert_eq!(3, report.errors());
+        Ok(())
+    }
+}
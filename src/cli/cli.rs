@@ -1,6 +1,18 @@
+use super::super::config::Config;
 use super::super::server;
-use clap::arg;
+use super::aof;
+use super::check;
+use super::compact;
+use super::diff;
+use super::dump;
+use super::export;
+use super::import;
+use super::load;
+use super::rdb;
+use super::repair;
+use super::stats;
 use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -23,6 +35,173 @@ enum Commands {
         #[arg(short, long, value_name = "FILE")]
         config: Option<PathBuf>,
     },
+    /// verify data and hint files without opening the database
+    Check {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// print the records of a data or hint file
+    Dump {
+        /// data or hint file to inspect
+        file: PathBuf,
+
+        /// Sets a custom config file, --latest then follows the keydir of db_dir
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// print one JSON object per line
+        #[arg(long)]
+        json: bool,
+
+        /// print keys and values as hex instead of escaped strings
+        #[arg(long)]
+        hex: bool,
+
+        /// only print records whose key matches the pattern, supports '*' and '?'
+        #[arg(short, long, value_name = "PATTERN")]
+        key: Option<String>,
+
+        /// only print the record the keydir would pick for each key
+        #[arg(long)]
+        latest: bool,
+    },
+    /// export all live keys as JSON lines, can run alongside the server
+    Export {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// write to the file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// load a file written by export, use - to read from stdin
+    Import {
+        /// JSON lines file to load
+        file: PathBuf,
+
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// import through the server at this address, defaults to the configured server if it is running
+        #[arg(long, value_name = "HOST:PORT")]
+        addr: Option<SocketAddr>,
+    },
+    /// write data and hint files directly from an export file, use - to read from stdin
+    Load {
+        /// JSON lines file to load
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// hand the files over to the running server instead of opening db_dir
+        #[arg(long)]
+        ingest: bool,
+    },
+    /// load the string keys of a Redis RDB file, run with the server stopped
+    ImportRdb {
+        /// RDB file to load
+        file: PathBuf,
+
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// Redis database to import, keys in other databases are skipped
+        #[arg(long, default_value_t = 0)]
+        db: u64,
+    },
+    /// write all live keys to an RDB file that redis-server can load
+    ExportRdb {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// RDB file to write
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// replay a Redis AOF file or directory, run with the server stopped
+    ImportAof {
+        /// AOF file, or the appendonly directory of redis 7
+        file: PathBuf,
+
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// Redis database to replay, commands for other databases are skipped
+        #[arg(long, default_value_t = 0)]
+        db: u64,
+    },
+    /// write all live keys as SET/PEXPIREAT commands in RESP format
+    ExportAof {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// write to the file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// rebuild hint files and salvage a damaged database, run with the server stopped
+    Repair {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+    /// report live and dead bytes per data file and the keyspace distribution, can run alongside the server
+    Stats {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// output the report as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// number of largest keys and key prefixes to show
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+    },
+    /// compare the live keys of two db_dir directories, such as a database and a backup
+    Diff {
+        /// first db_dir, or the config file of the first db
+        a: PathBuf,
+
+        /// second db_dir, or the config file of the second db
+        b: PathBuf,
+
+        /// output every difference as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// expire times differing by no more than this many milliseconds are treated as equal
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        ttl_tolerance: u64,
+    },
+    /// merge archived data files offline, run with the server stopped
+    Compact {
+        /// Sets a custom config file
+        #[arg(short, long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+}
+
+fn load_config(option: &Option<PathBuf>) -> anyhow::Result<Config> {
+    match option {
+        Some(file) => Config::try_from(file.as_path()),
+        None => Config::new(),
+    }
 }
 
 pub async fn parse() -> anyhow::Result<()> {
@@ -32,6 +211,61 @@ pub async fn parse() -> anyhow::Result<()> {
 
     match &cli.command {
         Some(Commands::Serve { config }) => server::start_server(config).await,
+        Some(Commands::Check { config, json }) => check::run(&load_config(config)?, *json),
+        Some(Commands::Dump {
+            file,
+            config,
+            json,
+            hex,
+            key,
+            latest,
+        }) => {
+            let config = match config {
+                Some(file) => Some(Config::try_from(file.as_path())?),
+                None => None,
+            };
+            let options = dump::Options {
+                json: *json,
+                hex: *hex,
+                key: key.clone(),
+                latest: *latest,
+            };
+            dump::run(file, config, &options)
+        }
+        Some(Commands::Export { config, output }) => {
+            export::run(load_config(config)?, output.as_deref())
+        }
+        Some(Commands::Import { file, config, addr }) => {
+            import::run(load_config(config)?, file, *addr)
+        }
+        Some(Commands::Load {
+            input,
+            config,
+            ingest,
+        }) => load::run(load_config(config)?, input, *ingest),
+        Some(Commands::ImportRdb { file, config, db }) => {
+            rdb::run_import(load_config(config)?, file, *db)
+        }
+        Some(Commands::ExportRdb { config, output }) => {
+            rdb::run_export(load_config(config)?, output)
+        }
+        Some(Commands::ImportAof { file, config, db }) => {
+            aof::run_import(load_config(config)?, file, *db)
+        }
+        Some(Commands::ExportAof { config, output }) => {
+            aof::run_export(load_config(config)?, output.as_deref())
+        }
+        Some(Commands::Repair { config }) => repair::run(&load_config(config)?),
+        Some(Commands::Stats { config, json, top }) => {
+            stats::run(load_config(config)?, *json, *top)
+        }
+        Some(Commands::Diff {
+            a,
+            b,
+            json,
+            ttl_tolerance,
+        }) => diff::run(a, b, *json, *ttl_tolerance),
+        Some(Commands::Compact { config }) => compact::run(load_config(config)?),
         None => Ok(()),
     }
 }
@@ -0,0 +1,165 @@
+use super::import;
+use crate::config::Config;
+use crate::db_store;
+use crate::store::metrics::Metrics;
+use crate::util::lock::OwnerLock;
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// 各数据目录中数据文件和 hint 文件占用的空间
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+    pub data_files: usize,
+    pub hint_files: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} data files, {} hint files, {} bytes",
+            self.data_files, self.hint_files, self.bytes
+        )
+    }
+}
+
+pub fn disk_usage(config: &Config) -> io::Result<DiskUsage> {
+    let data_re = Regex::new(&format!(r"^{}(\.\d+)?$", regex::escape(config.file()))).unwrap();
+    let hint_re = Regex::new(r"^hint\.\d+$").unwrap();
+    let mut usage = DiskUsage::default();
+    for entry in config
+        .data_dirs()
+        .into_iter()
+        .map(fs::read_dir)
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+    {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if data_re.is_match(&name) {
+            usage.data_files += 1;
+        } else if hint_re.is_match(&name) {
+            usage.hint_files += 1;
+        } else {
+            continue;
+        }
+        usage.bytes += entry.metadata()?.len();
+    }
+    Ok(usage)
+}
+
+// 服务运行、其它进程正在合并或替换文件时拒绝执行
+fn check_idle(config: &Config) -> anyhow::Result<Arc<OwnerLock>> {
+    if let Some(stream) = import::connect_server(config)? {
+        anyhow::bail!(
+            "server is running at {}, stop it first",
+            stream.peer_addr()?
+        );
+    }
+    let owner = OwnerLock::acquire(&config.owner_lock_filepath())?;
+    if config.merge_dir().exists() {
+        anyhow::bail!(
+            "{} exists, another merge is running or was interrupted (minkv repair removes it)",
+            config.merge_dir().display()
+        );
+    }
+    Ok(owner)
+}
+
+// 离线执行与合并线程相同的合并，返回合并前后的磁盘占用
+pub fn compact(config: Arc<Config>, progress: bool) -> anyhow::Result<(DiskUsage, DiskUsage)> {
+    let _owner = check_idle(&config)?;
+    let before = disk_usage(&config)?;
+
+    let (tx, rx) = mpsc::channel();
+    let store = db_store::open_store(Arc::clone(&config), tx, rx)?;
+    let result = thread::scope(|s| {
+        let handle = s.spawn(|| store.read().unwrap().compaction());
+        let mut last = Instant::now();
+        while !handle.is_finished() {
+            thread::sleep(POLL_INTERVAL);
+            if progress && last.elapsed() >= PROGRESS_INTERVAL {
+                let store = store.read().unwrap();
+                let metrics = store.metrics();
+                eprintln!(
+                    "{}/{} keys",
+                    Metrics::get(&metrics.merge_keys),
+                    Metrics::get(&metrics.merge_keys_total)
+                );
+                last = Instant::now();
+            }
+        }
+        handle.join().unwrap()
+    });
+    store.write().unwrap().close();
+    result?;
+
+    Ok((before, disk_usage(&config)?))
+}
+
+// minkv compact，输出合并前后的磁盘占用
+pub fn run(config: Config) -> anyhow::Result<()> {
+    let (before, after) = compact(Arc::new(config), true)?;
+    println!("before: {}", before);
+    println!("after:  {}", after);
+    println!(
+        "reclaimed {} bytes",
+        before.bytes.saturating_sub(after.bytes)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::db_store::Op;
+
+    #[test]
+    fn compact_shrinks_archived_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 1\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = db_store::new_store(Arc::clone(&config), tx, rx)?;
+            for round in 0..5 {
+                for n in 0..20 {
+                    let value = format!("{}-{}", n, round).into_bytes();
+                    store.set(format!("key{}", n).as_bytes(), &value, 0)?;
+                }
+            }
+            store.close();
+        }
+
+        fs::create_dir(config.merge_dir())?;
+        assert!(compact(Arc::clone(&config), false).is_err());
+        fs::remove_dir(config.merge_dir())?;
+
+        let (before, after) = compact(Arc::clone(&config), false)?;
+        assert!(after.bytes < before.bytes);
+        assert!(after.data_files < before.data_files);
+
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(20, store.len());
+        for n in 0..20 {
+            let value = format!("{}-4", n).into_bytes();
+            assert_eq!(value, store.get(format!("key{}", n).as_bytes())?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use super::export::live_entry;
+use crate::config::Config;
+use crate::db_store::{open_read_only_store, Keydir, Op, Store};
+use crate::entry::entry::Entry;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+// 一个 key 在两边的差异，过期时间为毫秒时间戳，None 表示没有过期时间
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Difference {
+    OnlyA {
+        key: String,
+    },
+    OnlyB {
+        key: String,
+    },
+    Value {
+        key: String,
+        a_size: usize,
+        b_size: usize,
+    },
+    Ttl {
+        key: String,
+        a: Option<u64>,
+        b: Option<u64>,
+    },
+}
+
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct Summary {
+    pub keys_a: u64,
+    pub keys_b: u64,
+    pub same: u64,
+    pub only_a: u64,
+    pub only_b: u64,
+    pub value: u64,
+    pub ttl: u64,
+}
+
+impl Summary {
+    pub fn differences(&self) -> u64 {
+        self.only_a + self.only_b + self.value + self.ttl
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct Report {
+    pub a: String,
+    pub b: String,
+    pub summary: Summary,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.summary;
+        writeln!(f, "a: {} ({} keys)", self.a, s.keys_a)?;
+        writeln!(f, "b: {} ({} keys)", self.b, s.keys_b)?;
+        writeln!(f, "same:          {}", s.same)?;
+        writeln!(f, "only in a:     {}", s.only_a)?;
+        writeln!(f, "only in b:     {}", s.only_b)?;
+        writeln!(f, "value differs: {}", s.value)?;
+        write!(f, "ttl differs:   {}", s.ttl)
+    }
+}
+
+fn expire_at(entry: &Entry) -> Option<u64> {
+    (entry.timestamp != 0).then_some(entry.timestamp)
+}
+
+// 参数为配置文件时按其中的 db_dir、文件名和 data_dirs 打开，否则按默认配置打开该目录
+fn side_config(path: &Path) -> anyhow::Result<Config> {
+    if path.is_file() {
+        return Config::try_from(path);
+    }
+    anyhow::ensure!(path.is_dir(), "{} is not a directory", path.display());
+    Ok(Config::for_dir(path))
+}
+
+// 文件名或 data_dirs 与配置不符时目录看起来是空的，直接报错而不是报告全部 key 都不同
+fn open_side(config: Config) -> anyhow::Result<Store<Keydir>> {
+    if !config.get_active_filepath().exists() && config.get_next_datafile_seq() == 1 {
+        anyhow::bail!(
+            "no data files named {} in {}, pass the config file if the db uses a custom file name or data_dirs",
+            config.file(),
+            config.data_dir().display()
+        );
+    }
+    Ok(open_read_only_store(Arc::new(config))?)
+}
+
+// 以只读方式打开两边逐个比较未过期的 key，过期时间相差不超过 ttl_tolerance 毫秒时视为相同
+pub fn diff(a: Config, b: Config, ttl_tolerance: u64) -> anyhow::Result<Report> {
+    let mut report = Report {
+        a: a.data_dir().display().to_string(),
+        b: b.data_dir().display().to_string(),
+        ..Default::default()
+    };
+    let store_a = open_side(a)?;
+    let store_b = open_side(b)?;
+
+    let keys: BTreeSet<Vec<u8>> = store_a.keys().into_iter().chain(store_b.keys()).collect();
+    for key in keys {
+        let name = key.escape_ascii().to_string();
+        let difference = match (live_entry(&store_a, &key), live_entry(&store_b, &key)) {
+            (None, None) => continue,
+            (Some(_), None) => {
+                report.summary.keys_a += 1;
+                report.summary.only_a += 1;
+                Difference::OnlyA { key: name }
+            }
+            (None, Some(_)) => {
+                report.summary.keys_b += 1;
+                report.summary.only_b += 1;
+                Difference::OnlyB { key: name }
+            }
+            (Some(ea), Some(eb)) => {
+                report.summary.keys_a += 1;
+                report.summary.keys_b += 1;
+                let (ta, tb) = (expire_at(&ea), expire_at(&eb));
+                if ea.value != eb.value {
+                    report.summary.value += 1;
+                    Difference::Value {
+                        key: name,
+                        a_size: ea.value.len(),
+                        b_size: eb.value.len(),
+                    }
+                } else if match (ta, tb) {
+                    (Some(ta), Some(tb)) => ta.abs_diff(tb) > ttl_tolerance,
+                    (ta, tb) => ta != tb,
+                } {
+                    report.summary.ttl += 1;
+                    Difference::Ttl {
+                        key: name,
+                        a: ta,
+                        b: tb,
+                    }
+                } else {
+                    report.summary.same += 1;
+                    continue;
+                }
+            }
+        };
+        report.differences.push(difference);
+    }
+    Ok(report)
+}
+
+// minkv diff，有差异时返回 Err 以非 0 状态码退出
+pub fn run(a: &Path, b: &Path, json: bool, ttl_tolerance: u64) -> anyhow::Result<()> {
+    let report = diff(side_config(a)?, side_config(b)?, ttl_tolerance)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+
+    if report.summary.differences() > 0 {
+        anyhow::bail!("found {} differences", report.summary.differences());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::db_store::new_store;
+    use crate::util::time;
+    use std::sync::mpsc;
+
+    #[test]
+    fn diff_reports_each_kind() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        for (path, pairs) in [
+            (&a, [("k1", "v", 0), ("k2", "v", 0), ("k3", "v", 600_000)]),
+            (&b, [("k2", "w", 0), ("k3", "v", 300_000), ("k4", "v", 0)]),
+        ] {
+            std::fs::create_dir(path)?;
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::new(Config::for_dir(path)), tx, rx)?;
+            store.set(b"same", b"v", 0)?;
+            for (key, value, ttl) in pairs {
+                let expire_at = if ttl == 0 { 0 } else { time::get_millisec(ttl) };
+                store.set(key.as_bytes(), value.as_bytes(), expire_at)?;
+            }
+            store.close();
+        }
+
+        let report = diff(Config::for_dir(&a), Config::for_dir(&b), 1000)?;
+        assert_eq!(
+            Summary {
+                keys_a: 4,
+                keys_b: 4,
+                same: 1,
+                only_a: 1,
+                only_b: 1,
+                value: 1,
+                ttl: 1,
+            },
+            report.summary
+        );
+        assert_eq!(
+            Difference::OnlyA {
+                key: "k1".to_string()
+            },
+            report.differences[0]
+        );
+        assert!(matches!(report.differences[2], Difference::Ttl { .. }));
+
+        // 自定义文件名的目录需要通过配置文件打开
+        let c = dir.path().join("c");
+        std::fs::create_dir(&c)?;
+        let config_c = test_config(&c, "file = \"custom\"\n")?;
+        let (tx, rx) = mpsc::channel();
+        let mut store = new_store(Arc::new(config_c.clone()), tx, rx)?;
+        store.set(b"same", b"v", 0)?;
+        store.close();
+        let err = diff(Config::for_dir(&a), Config::for_dir(&c), 1000).unwrap_err();
+        assert!(err.to_string().contains("no data files named data"));
+        let report = diff(Config::for_dir(&a), config_c, 1000)?;
+        assert_eq!(1, report.summary.same);
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+use crate::config::Config;
+use crate::entry::entry::{Entry, EntryFile};
+use crate::entry::hint::HintFile;
+use crate::store::store::{open_read_only_store, ACTIVE_FILE_SEQ};
+use crate::util;
+use chrono::DateTime;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const HINT_PREFIX: &str = "hint.";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub json: bool,
+    pub hex: bool,           // key 和 value 以十六进制输出
+    pub key: Option<String>, // key 匹配模式，同 KEYS 命令
+    pub latest: bool,        // 只输出 keydir 会选择的记录
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Data {
+        offset: u64,
+        size: u64,
+        crc: &'static str,
+        op: &'static str,
+        expire_at: Option<u64>, // 过期时间，毫秒
+        expired: bool,
+        key: String,
+        value: String,
+    },
+    Hint {
+        offset: u64,
+        timestamp: u64,
+        key: String,
+        entry_pos: u64,
+        entry_size: u64,
+    },
+    Damaged {
+        offset: u64,
+        len: u64,
+        reason: String,
+    },
+}
+
+fn format_expire(expire_at: Option<u64>) -> String {
+    expire_at
+        .and_then(|ms| DateTime::from_timestamp_millis(ms as i64))
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::Data {
+                offset,
+                size,
+                crc,
+                op,
+                expire_at,
+                expired,
+                key,
+                value,
+            } => write!(
+                f,
+                "@{} size={} crc={} op={} expire={}{} key=\"{}\" value=\"{}\"",
+                offset,
+                size,
+                crc,
+                op,
+                format_expire(*expire_at),
+                if *expired { " (expired)" } else { "" },
+                key,
+                value
+            ),
+            Record::Hint {
+                offset,
+                timestamp,
+                key,
+                entry_pos,
+                entry_size,
+            } => write!(
+                f,
+                "@{} ts={} key=\"{}\" entry_pos={} entry_size={}",
+                offset, timestamp, key, entry_pos, entry_size
+            ),
+            Record::Damaged {
+                offset,
+                len,
+                reason,
+            } => write!(f, "@{} damaged len={} {}", offset, len, reason),
+        }
+    }
+}
+
+// 解析出的记录，附带筛选需要的原始信息
+struct Item {
+    key: Option<Vec<u8>>, // 损坏区间没有 key
+    entry_pos: u64,       // 数据记录在数据文件中的位置
+    valid: bool,          // 加载时会使用该记录
+    deleted: bool,
+    record: Record,
+}
+
+fn encode(bytes: &[u8], hex: bool) -> String {
+    if hex {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        bytes.escape_ascii().to_string()
+    }
+}
+
+fn damaged_item(offset: u64, len: u64, reason: String) -> Item {
+    Item {
+        key: None,
+        entry_pos: offset,
+        valid: false,
+        deleted: false,
+        record: Record::Damaged {
+            offset,
+            len,
+            reason,
+        },
+    }
+}
+
+fn data_item(offset: u64, entry: Entry, crc_ok: bool, hex: bool) -> Item {
+    let record = Record::Data {
+        offset,
+        size: entry.size() as u64,
+        crc: if crc_ok { "ok" } else { "mismatch" },
+        op: entry.op_name(),
+        expire_at: (entry.timestamp != 0).then_some(entry.timestamp),
+        expired: entry.is_expired(),
+        key: encode(&entry.key, hex),
+        value: encode(&entry.value, hex),
+    };
+    Item {
+        deleted: entry.is_removed(),
+        key: Some(entry.key),
+        entry_pos: offset,
+        valid: crc_ok,
+        record,
+    }
+}
+
+type Emit<'a> = dyn FnMut(Item) -> io::Result<()> + 'a;
+
+// 逐条解析数据文件，crc 不一致的记录照常输出，无法解析的区间跳到下一条完整记录
+fn scan_data_file(path: &Path, hex: bool, emit: &mut Emit) -> io::Result<()> {
+    let mut entries = EntryFile::new(File::open(path)?).keep_crc_mismatch();
+    let mut reported = 0;
+    loop {
+        let result = entries.next();
+        // 解析到这条记录之前发现的损坏区间先输出
+        for range in &entries.damaged()[reported..] {
+            emit(damaged_item(
+                range.offset,
+                range.len,
+                range.reason.to_string(),
+            ))?;
+        }
+        reported = entries.damaged().len();
+        match result {
+            Some(result) => emit(data_item(
+                result.value_pos,
+                result.entry,
+                result.crc_ok,
+                hex,
+            ))?,
+            None => return Ok(()),
+        }
+    }
+}
+
+fn scan_hint_file(path: &Path, hex: bool, emit: &mut Emit) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut hints = HintFile::new(File::open(path)?);
+    let mut pos = 0;
+    for hint in hints.iter() {
+        let size = hint.size();
+        emit(Item {
+            record: Record::Hint {
+                offset: pos,
+                timestamp: hint.timestamp,
+                key: encode(&hint.key, hex),
+                entry_pos: hint.value_pos,
+                entry_size: hint.value_size,
+            },
+            key: Some(hint.key),
+            entry_pos: hint.value_pos,
+            valid: true,
+            deleted: false,
+        })?;
+        pos += size;
+    }
+    if let Some(offset) = hints.truncated() {
+        emit(damaged_item(
+            offset,
+            len - offset,
+            "truncated hint".to_string(),
+        ))?;
+    }
+    Ok(())
+}
+
+// 文件在数据目录中对应的 file_id，活跃文件为 0
+fn file_id(config: &Config, path: &Path) -> Option<u16> {
+    let parent = fs::canonicalize(path).ok()?.parent()?.to_path_buf();
+    let in_data_dir = config
+        .data_dirs()
+        .into_iter()
+        .any(|dir| fs::canonicalize(dir).is_ok_and(|dir| dir == parent));
+    if !in_data_dir {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    if name == config.file() {
+        return Some(ACTIVE_FILE_SEQ);
+    }
+    let data_prefix = format!("{}.", config.file());
+    name.strip_prefix(HINT_PREFIX)
+        .or_else(|| name.strip_prefix(data_prefix.as_str()))
+        .and_then(|seq| seq.parse().ok())
+}
+
+// 解析 data 或 hint 文件，逐条回调 emit，不把整个文件读入内存
+// 指定 config 时 --latest 以 db_dir 加载后的 keydir 为准，否则为同一文件内每个 key 最后一条有效记录，删除的 key 不输出
+pub fn dump<F>(
+    path: &Path,
+    config: Option<Config>,
+    options: &Options,
+    mut emit: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Record) -> io::Result<()>,
+{
+    let is_hint = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(HINT_PREFIX));
+    let scan = |emit: &mut Emit| {
+        if is_hint {
+            scan_hint_file(path, options.hex, emit)
+        } else {
+            scan_data_file(path, options.hex, emit)
+        }
+    };
+    let key_matches = |item: &Item| match &options.key {
+        Some(pattern) => item
+            .key
+            .as_ref()
+            .is_some_and(|k| util::match_key(pattern, &String::from_utf8_lossy(k))),
+        None => true,
+    };
+
+    let selected: Box<dyn Fn(&Item) -> bool> = match (options.latest, config) {
+        (false, _) => Box::new(|_| true),
+        (true, Some(config)) => {
+            let Some(file_id) = file_id(&config, path) else {
+                anyhow::bail!(
+                    "{} is not a data or hint file of {}",
+                    path.display(),
+                    config.data_dir().display()
+                );
+            };
+            let store = open_read_only_store(Arc::new(config))?;
+            Box::new(move |item| {
+                item.valid
+                    && item
+                        .key
+                        .as_ref()
+                        .and_then(|k| store.metadata(k))
+                        .is_some_and(|m| m.file_id == file_id && m.value_pos == item.entry_pos)
+            })
+        }
+        (true, None) => {
+            // 先扫描一遍，记下每个 key 最后一条有效记录的位置
+            let mut latest = HashMap::new();
+            scan(&mut |item| {
+                if let (Some(key), true) = (item.key, item.valid) {
+                    latest.insert(key, item.entry_pos);
+                }
+                Ok(())
+            })?;
+            Box::new(move |item| {
+                item.valid
+                    && !item.deleted
+                    && item
+                        .key
+                        .as_ref()
+                        .is_some_and(|k| latest.get(k) == Some(&item.entry_pos))
+            })
+        }
+    };
+
+    scan(&mut |item| {
+        if key_matches(&item) && selected(&item) {
+            emit(item.record)?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// minkv dump，每条记录输出一行
+pub fn run(path: &Path, config: Option<Config>, options: &Options) -> anyhow::Result<()> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    dump(path, config, options, |record| {
+        if options.json {
+            writeln!(out, "{}", serde_json::to_string(&record)?)
+        } else {
+            writeln!(out, "{}", record)
+        }
+    })?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::store::store::{new_store, Op};
+    use std::io::{Seek, SeekFrom};
+    use std::sync::mpsc;
+
+    fn collect(
+        path: &Path,
+        config: Option<Config>,
+        options: &Options,
+    ) -> anyhow::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        dump(path, config, options, |record| {
+            records.push(record);
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    fn keys(records: &[Record]) -> Vec<(&str, &str)> {
+        records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Data { key, value, .. } => Some((key.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dump_latest_records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = test_config(
+            &dir.path().join("db"),
+            "file_max_size = 4096\nmerge_file_num = 1000\n",
+        )?;
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::new(config.clone()), tx, rx)?;
+            store.set(b"a", b"1", 0)?;
+            store.set(b"b", b"\x00\xff", 0)?;
+            store.set(b"a", b"2", 0)?;
+            store.set(b"c", b"3", 0)?;
+            store.delete(b"c")?;
+            store.close();
+        }
+        let path = config.get_active_filepath();
+
+        let all = collect(&path, None, &Options::default())?;
+        assert_eq!(5, all.len());
+        assert_eq!(("b", "\\x00\\xff"), keys(&all)[1]);
+
+        let options = Options {
+            latest: true,
+            ..Default::default()
+        };
+        let latest = collect(&path, None, &options)?;
+        assert_eq!(vec![("b", "\\x00\\xff"), ("a", "2")], keys(&latest));
+        let with_keydir = collect(&path, Some(config.clone()), &options)?;
+        assert_eq!(latest, with_keydir);
+
+        // 修改第二条记录的 value，crc 不一致但仍可输出
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(27 + 26))?;
+        file.write_all(b"X")?;
+        let options = Options {
+            key: Some("b".to_string()),
+            hex: true,
+            ..Default::default()
+        };
+        let records = collect(&path, None, &options)?;
+        assert!(matches!(
+            &records[..],
+            [Record::Data { crc: "mismatch", key, .. }] if key == "62"
+        ));
+        Ok(())
+    }
+}
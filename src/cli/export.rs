@@ -0,0 +1,113 @@
+use crate::config::Config;
+use crate::db_store::{open_read_only_store, Op};
+use crate::entry::entry::Entry;
+use crate::util::{self, time};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// 每处理这么多个 key 输出一次进度
+pub(crate) const PROGRESS_INTERVAL: u64 = 10000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Base64,
+}
+
+// 导出文件中的一行，key 或 value 不是 UTF-8 时两者都以 base64 编码
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Line {
+    pub key: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>, // 剩余过期时间，毫秒
+}
+
+impl Line {
+    pub fn new(key: &[u8], value: &[u8], ttl_ms: Option<u64>) -> Line {
+        match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Line {
+                key: key.to_string(),
+                value: value.to_string(),
+                encoding: None,
+                ttl_ms,
+            },
+            _ => Line {
+                key: STANDARD.encode(key),
+                value: STANDARD.encode(value),
+                encoding: Some(Encoding::Base64),
+                ttl_ms,
+            },
+        }
+    }
+
+    pub fn decode(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        match self.encoding {
+            None => Ok((self.key.as_bytes().to_vec(), self.value.as_bytes().to_vec())),
+            Some(Encoding::Base64) => {
+                Ok((STANDARD.decode(&self.key)?, STANDARD.decode(&self.value)?))
+            }
+        }
+    }
+}
+
+// key 当前的记录，已过期或删除时返回 None，读取失败时记录日志后跳过
+pub(crate) fn live_entry(store: &impl Op, key: &[u8]) -> Option<Entry> {
+    match store.get_entry(key) {
+        Ok(entry) if entry.is_expired() || entry.is_removed() => None,
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            warn!("skip key {:?}: {}", util::format_bytes_as_str(key), e);
+            None
+        }
+    }
+}
+
+// 以只读方式打开 db_dir 并逐行写出所有未过期的 key，返回导出的 key 数量
+pub fn export<W: Write>(config: Arc<Config>, out: &mut W, progress: bool) -> anyhow::Result<u64> {
+    let store = open_read_only_store(config)?;
+    let keys = store.keys();
+    let mut exported = 0;
+    for (i, key) in keys.iter().enumerate() {
+        if let Some(entry) = live_entry(&store, key) {
+            let ttl_ms = (entry.timestamp != 0)
+                .then(|| entry.timestamp.saturating_sub(time::current_milliseconds()));
+            serde_json::to_writer(&mut *out, &Line::new(key, &entry.value, ttl_ms))?;
+            out.write_all(b"\n")?;
+            exported += 1;
+        }
+        if progress && (i as u64 + 1).is_multiple_of(PROGRESS_INTERVAL) {
+            eprintln!("{}/{} keys", i + 1, keys.len());
+        }
+    }
+    out.flush()?;
+
+    if progress {
+        eprintln!("exported {} keys", exported);
+    }
+    Ok(exported)
+}
+
+// minkv export，未指定输出文件时写到标准输出
+pub fn run(config: Config, output: Option<&Path>) -> anyhow::Result<()> {
+    let config = Arc::new(config);
+    match output {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            export(config, &mut out, true)?;
+            out.into_inner()?.sync_all()?;
+        }
+        None => {
+            export(config, &mut BufWriter::new(io::stdout().lock()), true)?;
+        }
+    }
+    Ok(())
+}
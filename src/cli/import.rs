@@ -0,0 +1,239 @@
+use super::aof;
+use super::export::{Line, PROGRESS_INTERVAL};
+use crate::config::Config;
+use crate::db_store::{self, Op};
+use crate::util::lock::OwnerLock;
+use crate::util::time;
+use anyhow::Context;
+use log::*;
+use redis_protocol::resp2::{decode::decode, types::OwnedFrame};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// 通过服务导入时每批发送的命令数，收到全部响应后再发送下一批
+const PIPELINE_SIZE: usize = 128;
+
+// 通过 RESP 协议向运行中的服务批量发送命令
+pub(crate) struct Client {
+    stream: TcpStream,
+    out: Vec<u8>,
+    pending: usize,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    pub(crate) fn new(stream: TcpStream) -> Client {
+        Client {
+            stream,
+            out: Vec::new(),
+            pending: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub(crate) fn send(&mut self, args: &[&[u8]]) -> anyhow::Result<()> {
+        aof::encode_command(&mut self.out, args)?;
+        self.pending += 1;
+        if self.pending >= PIPELINE_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // 发送缓存的命令并等待所有响应，任一命令失败时返回错误
+    pub(crate) fn flush(&mut self) -> anyhow::Result<()> {
+        self.stream.write_all(&self.out)?;
+        self.out.clear();
+
+        let mut chunk = [0u8; 4096];
+        while self.pending > 0 {
+            match decode(&self.buf)? {
+                Some((frame, consumed)) => {
+                    self.buf.drain(..consumed);
+                    self.pending -= 1;
+                    if let OwnedFrame::Error(e) = frame {
+                        anyhow::bail!("server returned error: {}", e);
+                    }
+                }
+                None => {
+                    let n = self.stream.read(&mut chunk)?;
+                    if n == 0 {
+                        anyhow::bail!("connection closed by server");
+                    }
+                    self.buf.extend_from_slice(&chunk[..n]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 导入目标：服务停止时直接打开 db_dir，运行时通过服务写入
+enum Target {
+    Store(Arc<RwLock<dyn Op>>),
+    Server(Client),
+}
+
+impl Target {
+    fn set(&mut self, key: &[u8], value: &[u8], expire_at: u64) -> anyhow::Result<()> {
+        match self {
+            Target::Store(store) => store.write().unwrap().set(key, value, expire_at)?,
+            Target::Server(client) => {
+                client.send(&[b"SET", key, value])?;
+                if expire_at != 0 {
+                    client.send(&[b"PEXPIREAT", key, expire_at.to_string().as_bytes()])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Target::Store(store) => store.write().unwrap().close(),
+            Target::Server(mut client) => client.flush()?,
+        }
+        Ok(())
+    }
+}
+
+// 配置中的服务正在运行时返回到服务的连接
+pub(crate) fn connect_server(config: &Config) -> anyhow::Result<Option<TcpStream>> {
+    let addr = config.get_addr()?;
+    Ok(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok())
+}
+
+// 指定 addr 时通过该服务导入；否则配置中的服务在运行时通过服务导入，未运行时直接写入 db_dir
+fn open_target(config: Config, addr: Option<SocketAddr>) -> anyhow::Result<Target> {
+    if let Some(addr) = addr {
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .with_context(|| format!("connect to {}", addr))?;
+        return Ok(Target::Server(Client::new(stream)));
+    }
+
+    if let Some(stream) = connect_server(&config)? {
+        info!(
+            "server is running at {}, import through it",
+            stream.peer_addr()?
+        );
+        return Ok(Target::Server(Client::new(stream)));
+    }
+    // 先获取所有者锁，被其它进程持有时返回错误，打开后由 store 持有到导入结束
+    let _owner = OwnerLock::acquire(&config.owner_lock_filepath())?;
+    let (tx, rx) = mpsc::channel();
+    Ok(Target::Store(db_store::open_store(
+        Arc::new(config),
+        tx,
+        rx,
+    )?))
+}
+
+// 逐行导入 export 生成的数据，保留剩余的过期时间，返回导入的 key 数量
+pub fn import<R: BufRead>(
+    config: Config,
+    addr: Option<SocketAddr>,
+    input: R,
+    progress: bool,
+) -> anyhow::Result<u64> {
+    let mut target = open_target(config, addr)?;
+    let (mut imported, mut expired) = (0u64, 0);
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Line =
+            serde_json::from_str(&line).with_context(|| format!("line {}", i + 1))?;
+        let (key, value) = record.decode().with_context(|| format!("line {}", i + 1))?;
+
+        let expire_at = match record.ttl_ms {
+            None => 0,
+            // 导出时已经到期
+            Some(0) => {
+                expired += 1;
+                continue;
+            }
+            Some(ttl) => time::get_millisec(ttl),
+        };
+        target.set(&key, &value, expire_at)?;
+        imported += 1;
+        if progress && imported.is_multiple_of(PROGRESS_INTERVAL) {
+            eprintln!("{} keys", imported);
+        }
+    }
+    target.finish()?;
+
+    if progress {
+        eprintln!("imported {} keys, {} expired", imported, expired);
+    }
+    Ok(imported)
+}
+
+// minkv import，文件为 - 时从标准输入读取
+pub fn run(config: Config, input: &Path, addr: Option<SocketAddr>) -> anyhow::Result<()> {
+    if input == Path::new("-") {
+        import(config, addr, io::stdin().lock(), true)?;
+    } else {
+        let file = File::open(input).with_context(|| format!("open {}", input.display()))?;
+        import(config, addr, BufReader::new(file), true)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::export;
+    use crate::config::test_config;
+
+    fn config(dir: &Path, name: &str) -> anyhow::Result<Config> {
+        test_config(
+            &dir.join(name),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 1\n",
+        )
+    }
+
+    #[test]
+    fn export_import_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = db_store::new_store(Arc::new(config(dir.path(), "src")?), tx, rx)?;
+            for i in 0..20 {
+                store.set(format!("key{:02}", i).as_bytes(), b"value", 0)?;
+            }
+            store.set(b"binary", &[0, 159, 146, 150], 0)?;
+            store.set(b"ttl", b"value", time::get_millisec(600_000))?;
+            store.set(b"expired", b"value", 1)?;
+            store.delete(b"key19")?;
+            store.close();
+        }
+
+        let mut out = Vec::new();
+        assert_eq!(
+            21,
+            export::export(Arc::new(config(dir.path(), "src")?), &mut out, false)?
+        );
+        let text = String::from_utf8(out.clone())?;
+        assert!(text.contains(r#""encoding":"base64""#));
+        assert!(!text.contains("key19") && !text.contains("expired"));
+
+        assert_eq!(
+            21,
+            import(config(dir.path(), "dst")?, None, &out[..], false)?
+        );
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::new_store(Arc::new(config(dir.path(), "dst")?), tx, rx)?;
+        assert_eq!(21, store.len());
+        assert_eq!(vec![0, 159, 146, 150], store.get(b"binary")?);
+        let ttl = store.get_entry(b"ttl")?.timestamp;
+        assert!(ttl > time::get_millisec(590_000) && ttl <= time::get_millisec(600_000));
+        Ok(())
+    }
+}
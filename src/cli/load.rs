@@ -0,0 +1,219 @@
+use super::export::{Line, PROGRESS_INTERVAL};
+use super::import::{self, Client};
+use crate::config::Config;
+use crate::db_store;
+use crate::entry::entry::Entry;
+use crate::entry::hint::Hint;
+use crate::store::file;
+use crate::util::lock::OwnerLock;
+use crate::util::time;
+use anyhow::Context;
+use chrono::Utc;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+
+// 按 file_max_size 切分，在暂存目录中写入数据文件和对应的 hint 文件
+struct LoadWriter<'a> {
+    config: &'a Config,
+    seq: u16,
+    data: BufWriter<File>,
+    hint: BufWriter<File>,
+    size: usize,
+    tstamp: u64,
+}
+
+impl<'a> LoadWriter<'a> {
+    fn create(config: &'a Config, seq: u16, tstamp: u64) -> io::Result<LoadWriter<'a>> {
+        Ok(LoadWriter {
+            config,
+            seq,
+            data: file::new_writer(&config.get_load_filepath_by_seq(seq))?,
+            hint: file::new_writer(&config.get_load_hint_filepath_by_seq(seq))?,
+            size: 0,
+            tstamp,
+        })
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.data.into_inner()?.sync_all()?;
+        self.hint.into_inner()?.sync_all()
+    }
+
+    // 同一个 key 的多条记录都写入 hint，加载时以最后一条为准
+    fn write(&mut self, entry: Entry) -> io::Result<()> {
+        if self.size > 0 && self.size + entry.size() > self.config.file_max_size() {
+            let seq = self
+                .seq
+                .checked_add(1)
+                .ok_or_else(|| io::Error::other("too many load files, increase file_max_size"))?;
+            let next = LoadWriter::create(self.config, seq, self.tstamp)?;
+            std::mem::replace(self, next).finish()?;
+        }
+
+        let offset = self.size as u64;
+        self.data.write_all(&entry.as_bytes())?;
+        let hint = Hint {
+            timestamp: self.tstamp,
+            key_size: entry.key_size,
+            value_size: entry.size() as u64,
+            value_pos: offset,
+            key: entry.key.clone(),
+        };
+        let hint_bytes: Vec<u8> = hint.into();
+        self.hint.write_all(&hint_bytes)?;
+        self.size += entry.size();
+        Ok(())
+    }
+}
+
+// 将 export 格式的数据写成暂存目录中的数据文件和 hint 文件，不经过 keydir，返回写入的 key 数量
+pub fn load<R: BufRead>(config: &Config, input: R, progress: bool) -> anyhow::Result<u64> {
+    // 上次中断遗留的暂存文件
+    let dir = config.load_dir();
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+
+    let mut writer = LoadWriter::create(config, 1, Utc::now().timestamp() as u64)?;
+    let (mut loaded, mut expired) = (0u64, 0);
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Line =
+            serde_json::from_str(&line).with_context(|| format!("line {}", i + 1))?;
+        let (key, value) = record.decode().with_context(|| format!("line {}", i + 1))?;
+
+        let expire_at = match record.ttl_ms {
+            None => 0,
+            // 导出时已经到期
+            Some(0) => {
+                expired += 1;
+                continue;
+            }
+            Some(ttl) => time::get_millisec(ttl),
+        };
+        writer.write(Entry::new(key, value, expire_at))?;
+        loaded += 1;
+        if progress && loaded.is_multiple_of(PROGRESS_INTERVAL) {
+            eprintln!("{} keys", loaded);
+        }
+    }
+    let files = writer.seq;
+    writer.finish()?;
+
+    if progress {
+        eprintln!(
+            "loaded {} keys into {} files, {} expired",
+            loaded, files, expired
+        );
+    }
+    Ok(loaded)
+}
+
+// 指定 ingest 时服务必须在运行，否则服务必须已停止，返回到服务的连接
+fn check_server(config: &Config, ingest: bool) -> anyhow::Result<Option<TcpStream>> {
+    let addr = config.get_addr()?;
+    match import::connect_server(config)? {
+        Some(_) if !ingest => anyhow::bail!(
+            "server is running at {}, use --ingest or stop it first",
+            addr
+        ),
+        None if ingest => anyhow::bail!("server is not running at {}", addr),
+        stream => Ok(stream),
+    }
+}
+
+// 服务停止时打开 db_dir 导入暂存文件，否则发送 INGEST 由运行中的服务导入
+fn ingest(config: Config, ingest: bool) -> anyhow::Result<()> {
+    match check_server(&config, ingest)? {
+        Some(stream) => {
+            let mut client = Client::new(stream);
+            client.send(&[b"INGEST"])?;
+            client.flush()?;
+            eprintln!("ingested into server at {}", config.get_addr()?);
+        }
+        None => {
+            let (tx, rx) = mpsc::channel();
+            let store = db_store::open_store(Arc::new(config), tx, rx)?;
+            let mut store = store.write().unwrap();
+            let result = store.ingest();
+            store.close();
+            eprintln!("ingested {} records", result?);
+        }
+    }
+    Ok(())
+}
+
+// minkv load，文件为 - 时从标准输入读取
+pub fn run(config: Config, input: &Path, ingest_server: bool) -> anyhow::Result<()> {
+    // 先确认服务状态，避免写完数据后才失败
+    check_server(&config, ingest_server)?;
+    // 服务停止时从写入暂存文件到导入完成一直持有所有者锁
+    let _owner = if ingest_server {
+        None
+    } else {
+        Some(OwnerLock::acquire(&config.owner_lock_filepath())?)
+    };
+    if input == Path::new("-") {
+        load(&config, io::stdin().lock(), true)?;
+    } else {
+        let file = File::open(input).with_context(|| format!("open {}", input.display()))?;
+        load(&config, BufReader::new(file), true)?;
+    }
+    ingest(config, ingest_server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::db_store::Op;
+
+    #[test]
+    fn load_and_open() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 1\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = db_store::new_store(Arc::clone(&config), tx, rx)?;
+            store.set(b"old", b"value", 0)?;
+            store.set(b"key00", b"old", 0)?;
+            store.close();
+        }
+
+        let mut input = String::new();
+        for i in 0..20 {
+            input.push_str(&format!(
+                "{{\"key\":\"key{:02}\",\"value\":\"value\"}}\n",
+                i
+            ));
+        }
+        input.push_str("{\"key\":\"key01\",\"value\":\"new\"}\n");
+        input.push_str("{\"key\":\"ttl\",\"value\":\"value\",\"ttl_ms\":600000}\n");
+        input.push_str("{\"key\":\"expired\",\"value\":\"value\",\"ttl_ms\":0}\n");
+        assert_eq!(22, load(&config, input.as_bytes(), false)?);
+        assert!(config.get_load_filepath_by_seq(2).exists());
+        ingest((*config).clone(), false)?;
+        assert!(!config.load_dir().exists());
+
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(22, store.len());
+        assert_eq!(b"value".to_vec(), store.get(b"old")?);
+        assert_eq!(b"value".to_vec(), store.get(b"key00")?);
+        assert_eq!(b"new".to_vec(), store.get(b"key01")?);
+        let ttl = store.get_entry(b"ttl")?.timestamp;
+        assert!(ttl > time::get_millisec(590_000) && ttl <= time::get_millisec(600_000));
+        Ok(())
+    }
+}
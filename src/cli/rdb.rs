@@ -0,0 +1,510 @@
+use super::export;
+use super::import;
+use crate::config::Config;
+use crate::db_store::{self, open_read_only_store, Op};
+use crate::util::lock::OwnerLock;
+use crate::util::time;
+use anyhow::Context;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+
+// 导出时写入的 RDB 版本，redis 5.0 及以上版本都可以加载
+const RDB_VERSION: u32 = 9;
+// 可以解析的最高 RDB 版本
+const MAX_RDB_VERSION: u32 = 12;
+
+const OP_FUNCTION2: u8 = 0xf5;
+const OP_FUNCTION_PRE_GA: u8 = 0xf6;
+const OP_MODULE_AUX: u8 = 0xf7;
+const OP_IDLE: u8 = 0xf8;
+const OP_FREQ: u8 = 0xf9;
+const OP_AUX: u8 = 0xfa;
+const OP_RESIZEDB: u8 = 0xfb;
+const OP_EXPIRETIME_MS: u8 = 0xfc;
+const OP_EXPIRETIME: u8 = 0xfd;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+// redis 使用的 crc64 (Jones)，按位反转的多项式
+const CRC64_POLY: u64 = 0x95ac9329ac4bc9b5;
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        crc = CRC64_TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn type_name(t: u8) -> String {
+    match t {
+        1 | 10 | 14 | 18 => "list".to_string(),
+        2 | 11 | 20 => "set".to_string(),
+        3 | 5 | 12 | 17 => "zset".to_string(),
+        4 | 9 | 13 | 16 => "hash".to_string(),
+        6 | 7 => "module".to_string(),
+        15 | 19 | 21 => "stream".to_string(),
+        t => format!("type {}", t),
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+// LZF 每 3 字节的回溯最多展开为 264 字节
+const LZF_MAX_EXPANSION: u64 = 88;
+
+// 解压 LZF 压缩的字符串，len 来自文件，先按压缩数据长度校验
+fn lzf_decompress(input: &[u8], len: u64) -> io::Result<Vec<u8>> {
+    if len > input.len() as u64 * LZF_MAX_EXPANSION {
+        return Err(invalid("lzf length exceeds compressed data"));
+    }
+    let len = len as usize;
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            // 原样复制 ctrl + 1 个字节
+            let run = input
+                .get(ip..ip + ctrl + 1)
+                .ok_or_else(|| invalid("truncated lzf literal"))?;
+            if out.len() + run.len() > len {
+                return Err(invalid("lzf length mismatch"));
+            }
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+            continue;
+        }
+
+        // 复制之前输出的一段数据
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(ip).ok_or_else(|| invalid("truncated lzf"))? as usize;
+            ip += 1;
+        }
+        let low = *input.get(ip).ok_or_else(|| invalid("truncated lzf"))? as usize;
+        ip += 1;
+        let back = ((ctrl & 0x1f) << 8) + low + 1;
+        let start = out
+            .len()
+            .checked_sub(back)
+            .ok_or_else(|| invalid("lzf back reference out of range"))?;
+        if out.len() + run + 2 > len {
+            return Err(invalid("lzf length mismatch"));
+        }
+        for i in 0..run + 2 {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != len {
+        return Err(invalid("lzf length mismatch"));
+    }
+    Ok(out)
+}
+
+// 长度编码，特殊编码表示整数或压缩字符串
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+// 边读取边计算 crc64
+struct RdbReader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    // n 来自文件，按实际读到的数据分配，避免损坏的长度导致大量分配
+    fn bytes(&mut self, n: u64) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(n).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated RDB string",
+            ));
+        }
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn length_or_encoding(&mut self) -> io::Result<Length> {
+        let first = self.u8()?;
+        let len = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => (((first & 0x3f) as u64) << 8) | self.u8()? as u64,
+            2 if first == 0x80 => u32::from_be_bytes(self.array()?) as u64,
+            2 if first == 0x81 => u64::from_be_bytes(self.array()?),
+            3 => return Ok(Length::Encoded(first & 0x3f)),
+            _ => return Err(invalid(format!("invalid length byte {:#x}", first))),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid("unexpected encoded length")),
+        }
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => self.bytes(len),
+            Length::Encoded(ENC_INT8) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_INT32) => {
+                Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                let compressed = self.bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            Length::Encoded(e) => Err(invalid(format!("unknown string encoding {}", e))),
+        }
+    }
+}
+
+// RDB 中的一个字符串 key
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub expire_at: u64, // 过期时间，毫秒，0 表示不过期
+}
+
+// 解析 RDB 文件，遇到字符串以外的类型时返回错误
+pub fn parse<R, F>(input: R, mut f: F) -> anyhow::Result<()>
+where
+    R: Read,
+    F: FnMut(RdbEntry) -> anyhow::Result<()>,
+{
+    let mut r = RdbReader {
+        inner: input,
+        crc: 0,
+    };
+    let magic = r.array::<9>()?;
+    if &magic[..5] != b"REDIS" {
+        anyhow::bail!("not a redis RDB file");
+    }
+    let version: u32 = std::str::from_utf8(&magic[5..])
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .context("invalid RDB version")?;
+    if version == 0 || version > MAX_RDB_VERSION {
+        anyhow::bail!(
+            "unsupported RDB version {}, supported up to {}",
+            version,
+            MAX_RDB_VERSION
+        );
+    }
+
+    let (mut db, mut expire_at) = (0, 0);
+    loop {
+        match r.u8()? {
+            OP_EOF => {
+                if version >= 5 {
+                    let computed = r.crc;
+                    let mut checksum = [0u8; 8];
+                    r.inner.read_exact(&mut checksum)?;
+                    // 校验和为 0 表示保存时关闭了校验
+                    let checksum = u64::from_le_bytes(checksum);
+                    if checksum != 0 && checksum != computed {
+                        anyhow::bail!("RDB checksum mismatch");
+                    }
+                }
+                return Ok(());
+            }
+            OP_SELECTDB => db = r.length()?,
+            OP_RESIZEDB => {
+                r.length()?;
+                r.length()?;
+            }
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_EXPIRETIME_MS => expire_at = u64::from_le_bytes(r.array()?),
+            OP_EXPIRETIME => expire_at = u32::from_le_bytes(r.array()?) as u64 * 1000,
+            OP_IDLE => {
+                r.length()?;
+            }
+            OP_FREQ => {
+                r.u8()?;
+            }
+            OP_MODULE_AUX => anyhow::bail!("RDB contains module data, which is not supported"),
+            OP_FUNCTION2 | OP_FUNCTION_PRE_GA => {
+                anyhow::bail!("RDB contains functions, which are not supported")
+            }
+            TYPE_STRING => {
+                let key = r.string()?;
+                let value = r.string()?;
+                f(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expire_at,
+                })?;
+                expire_at = 0;
+            }
+            t if t < OP_FUNCTION2 => {
+                let key = r.string()?;
+                anyhow::bail!(
+                    "key {:?} in db {} is a {}, only strings can be imported",
+                    String::from_utf8_lossy(&key),
+                    db,
+                    type_name(t)
+                );
+            }
+            op => anyhow::bail!("unknown RDB opcode {:#x}", op),
+        }
+    }
+}
+
+// 边写入边计算 crc64
+struct RdbWriter<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn length(&mut self, len: u64) -> io::Result<()> {
+        if len < 1 << 6 {
+            self.write(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if len <= u32::MAX as u64 {
+            self.write(&[0x80])?;
+            self.write(&(len as u32).to_be_bytes())
+        } else {
+            self.write(&[0x81])?;
+            self.write(&len.to_be_bytes())
+        }
+    }
+
+    fn string(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.length(bytes.len() as u64)?;
+        self.write(bytes)
+    }
+}
+
+// 将 db_dir 中所有未过期的 key 写为 db 0 中的字符串，返回写入的 key 数量
+pub fn export_rdb<W: Write>(config: Arc<Config>, out: W) -> anyhow::Result<u64> {
+    let store = open_read_only_store(config)?;
+    let mut w = RdbWriter { inner: out, crc: 0 };
+    w.write(format!("REDIS{:04}", RDB_VERSION).as_bytes())?;
+    w.write(&[OP_AUX])?;
+    w.string(b"redis-bits")?;
+    w.string(b"64")?;
+    w.write(&[OP_SELECTDB])?;
+    w.length(0)?;
+
+    let mut exported = 0;
+    for key in store.keys() {
+        let Some(entry) = export::live_entry(&store, &key) else {
+            continue;
+        };
+        if entry.timestamp != 0 {
+            w.write(&[OP_EXPIRETIME_MS])?;
+            w.write(&entry.timestamp.to_le_bytes())?;
+        }
+        w.write(&[TYPE_STRING])?;
+        w.string(&key)?;
+        w.string(&entry.value)?;
+        exported += 1;
+    }
+    w.write(&[OP_EOF])?;
+    let checksum = w.crc;
+    w.inner.write_all(&checksum.to_le_bytes())?;
+    w.inner.flush()?;
+    Ok(exported)
+}
+
+// 导入结果
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    pub expired: u64,
+    pub other_db: u64, // 其它 db 中跳过的 key
+}
+
+// 将 RDB 文件中指定 db 的字符串导入 db_dir，需在服务停止时执行
+// 先完整解析一遍，文件中包含不支持的类型时不写入任何数据
+pub fn import_rdb(config: Config, path: &Path, db: u64) -> anyhow::Result<ImportSummary> {
+    if let Some(stream) = import::connect_server(&config)? {
+        anyhow::bail!(
+            "server is running at {}, stop it before importing an RDB file",
+            stream.peer_addr()?
+        );
+    }
+    let _owner = OwnerLock::acquire(&config.owner_lock_filepath())?;
+    let open = || -> anyhow::Result<BufReader<File>> {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        Ok(BufReader::new(file))
+    };
+    parse(open()?, |_| Ok(()))?;
+
+    let mut summary = ImportSummary::default();
+    let (tx, rx) = mpsc::channel();
+    let store = db_store::open_store(Arc::new(config), tx, rx)?;
+    let now = time::current_milliseconds();
+    let result = parse(open()?, |entry| {
+        if entry.db != db {
+            summary.other_db += 1;
+        } else if entry.expire_at != 0 && entry.expire_at <= now {
+            summary.expired += 1;
+        } else {
+            store
+                .write()
+                .unwrap()
+                .set(&entry.key, &entry.value, entry.expire_at)?;
+            summary.imported += 1;
+        }
+        Ok(())
+    });
+    store.write().unwrap().close();
+    result?;
+    Ok(summary)
+}
+
+// minkv import-rdb
+pub fn run_import(config: Config, path: &Path, db: u64) -> anyhow::Result<()> {
+    let summary = import_rdb(config, path, db)?;
+    println!(
+        "imported {} keys from db {}, {} expired, {} keys in other dbs skipped",
+        summary.imported, db, summary.expired, summary.other_db
+    );
+    Ok(())
+}
+
+// minkv export-rdb，先写临时文件，完成后再 rename
+pub fn run_export(config: Config, output: &Path) -> anyhow::Result<()> {
+    let tmp = output.with_extension("rdb.tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    let exported = export_rdb(Arc::new(config), &mut out)?;
+    out.into_inner()?.sync_all()?;
+    fs::rename(&tmp, output)?;
+    println!("exported {} keys to {}", exported, output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn config(dir: &Path, name: &str) -> anyhow::Result<Config> {
+        test_config(
+            &dir.join(name),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 1\n",
+        )
+    }
+
+    #[test]
+    fn rdb_round_trip() -> anyhow::Result<()> {
+        assert_eq!(0xe9c6d914c4b8d9ca, crc64(0, b"123456789"));
+
+        let dir = tempfile::tempdir()?;
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = db_store::new_store(Arc::new(config(dir.path(), "src")?), tx, rx)?;
+            for i in 0..100 {
+                store.set(format!("key{:02}", i).as_bytes(), &vec![b'v'; i * 200], 0)?;
+            }
+            store.set(b"ttl", b"value", time::get_millisec(600_000))?;
+            store.delete(b"key99")?;
+            store.close();
+        }
+        let rdb = dir.path().join("dump.rdb");
+        run_export(config(dir.path(), "src")?, &rdb)?;
+
+        let summary = import_rdb(config(dir.path(), "dst")?, &rdb, 0)?;
+        assert_eq!(100, summary.imported);
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::new_store(Arc::new(config(dir.path(), "dst")?), tx, rx)?;
+        assert_eq!(vec![b'v'; 98 * 200], store.get(b"key98")?);
+        assert!(store.get_entry(b"ttl")?.timestamp > time::current_milliseconds());
+
+        // LZF 压缩和整数编码的字符串，以及一个 list 类型的 key
+        let mut data = b"REDIS0009\xfe\x00".to_vec();
+        data.extend_from_slice(b"\x00\x03lzf\xc3\x04\x08\x00a\xa0\x00");
+        data.extend_from_slice(b"\x00\xc0\x07\xc1\x39\x30");
+        data.extend_from_slice(b"\x01\x04list");
+        let mut entries = Vec::new();
+        let err = parse(&data[..], |e| {
+            entries.push((e.key, e.value));
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                (b"lzf".to_vec(), b"aaaaaaaa".to_vec()),
+                (b"7".to_vec(), b"12345".to_vec())
+            ],
+            entries
+        );
+        assert!(err.to_string().contains("\"list\" in db 0 is a list"));
+
+        // 损坏的长度不会按文件中的长度分配内存
+        let data = b"REDIS0009\x00\x81\xff\xff\xff\xff\xff\xff\xff\xffkey";
+        assert!(parse(&data[..], |_| Ok(())).is_err());
+        let data = b"REDIS0009\x00\xc3\x04\x81\xff\xff\xff\xff\xff\xff\xff\xff\x00a\xa0\x00";
+        let err = parse(&data[..], |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("lzf length"));
+        Ok(())
+    }
+}
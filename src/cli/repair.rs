@@ -0,0 +1,323 @@
+use crate::config::Config;
+use crate::entry::entry::{EntryFile, EntryParseResult};
+use crate::entry::hint::Hint;
+use crate::store::{merge, quarantine};
+use crate::util::lock::{DirLock, OwnerLock};
+use chrono::Utc;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const PROBE_FILE: &str = ".probe";
+const REPAIR_LOG: &str = "repair.log";
+
+// 修复过程中的所有改动
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub changes: Vec<String>,
+}
+
+impl Summary {
+    fn add(&mut self, change: String) {
+        self.changes.push(change);
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().to_string()
+}
+
+// 写临时文件后 rename，避免修复中断留下半个文件
+fn write_atomic<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let tmp = path.with_file_name(format!("{}.repair", file_name(path)));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    write(&mut writer)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// 隔离无法解析的区间：只有末尾损坏时截断，否则只保留完整记录重写文件
+fn repair_data_file(
+    config: &Config,
+    path: &Path,
+    summary: &mut Summary,
+) -> io::Result<(Vec<EntryParseResult>, bool)> {
+    let mut entries = EntryFile::new(File::open(path)?);
+    let mut records: Vec<EntryParseResult> = entries.iter().collect();
+    let damaged = entries.take_damaged();
+    if damaged.is_empty() {
+        return Ok((records, false));
+    }
+
+    let name = file_name(path);
+    quarantine::log_damaged(path, &damaged);
+    quarantine::save(config, path, &damaged)?;
+    summary.add(format!(
+        "{}: {} damaged ranges ({} bytes) saved to {}",
+        name,
+        damaged.len(),
+        damaged.iter().map(|r| r.len).sum::<u64>(),
+        config.quarantine_dir().display()
+    ));
+
+    if damaged.len() == 1 && damaged[0].tail {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(damaged[0].offset)?;
+        file.sync_all()?;
+        summary.add(format!(
+            "{}: truncated torn tail at {}",
+            name, damaged[0].offset
+        ));
+    } else {
+        let mut offset = 0;
+        for record in records.iter_mut() {
+            record.value_pos = offset;
+            offset += record.entry.size() as u64;
+        }
+        write_atomic(path, |w| {
+            for record in &records {
+                w.write_all(&record.entry.as_bytes())?;
+            }
+            Ok(())
+        })?;
+        summary.add(format!(
+            "{}: rewritten without damaged ranges, {} records kept",
+            name,
+            records.len()
+        ));
+    }
+    Ok((records, true))
+}
+
+// hint 无法表示删除，包含删除记录的文件不生成 hint，启动时回放数据文件
+fn rebuild_hint(
+    hint_path: &Path,
+    records: &[EntryParseResult],
+    summary: &mut Summary,
+) -> io::Result<()> {
+    let name = file_name(hint_path);
+    if records.iter().any(|r| r.entry.is_removed()) {
+        if hint_path.exists() {
+            fs::remove_file(hint_path)?;
+            summary.add(format!("{}: removed, data file contains deletes", name));
+        }
+        return Ok(());
+    }
+
+    // 同一文件中重复的 key 只保留最后一条
+    let mut latest = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        latest.insert(record.entry.key.as_slice(), i);
+    }
+
+    let mut bytes = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        if latest[record.entry.key.as_slice()] != i {
+            continue;
+        }
+        let hint = Hint {
+            timestamp: record.entry.timestamp,
+            key_size: record.entry.key_size,
+            value_size: record.entry.size() as u64,
+            value_pos: record.value_pos,
+            key: record.entry.key.clone(),
+        };
+        let hint_bytes: Vec<u8> = hint.into();
+        bytes.extend_from_slice(&hint_bytes);
+    }
+
+    if fs::read(hint_path).is_ok_and(|old| old == bytes) {
+        return Ok(());
+    }
+    write_atomic(hint_path, |w| w.write_all(&bytes))?;
+    summary.add(format!(
+        "{}: regenerated with {} entries",
+        name,
+        latest.len()
+    ));
+    Ok(())
+}
+
+fn list_files(dirs: &[&Path], re: &Regex) -> io::Result<BTreeMap<u16, PathBuf>> {
+    let mut files = BTreeMap::new();
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let seq = re
+                .captures(&file_name(&path))
+                .and_then(|caps| caps[1].parse::<u16>().ok());
+            if let Some(seq) = seq {
+                files.insert(seq, path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn remove_leftover(path: &Path, what: &str, summary: &mut Summary) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else if path.exists() {
+        fs::remove_file(path)?;
+    } else {
+        return Ok(());
+    }
+    summary.add(format!("{}: removed {}", path.display(), what));
+    Ok(())
+}
+
+// 修复 db_dir，需在服务停止时执行
+pub fn repair(config: &Config) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let _owner = OwnerLock::acquire(&config.owner_lock_filepath())?;
+    let _lock = DirLock::exclusive(&config.lock_filepath())?;
+
+    // 已提交的合并继续完成替换，否则删除
+    let merge_dir = config.merge_dir();
+    let leftover = merge_dir.exists();
+    if merge::recover(config)? {
+        summary.add(format!("{}: finished committed merge", merge_dir.display()));
+    } else if leftover {
+        summary.add(format!(
+            "{}: removed interrupted merge",
+            merge_dir.display()
+        ));
+    }
+    remove_leftover(
+        &config.data_dir().join(PROBE_FILE),
+        "write probe file",
+        &mut summary,
+    )?;
+    remove_leftover(
+        &config.snapshot_filepath().with_extension("tmp"),
+        "temporary snapshot",
+        &mut summary,
+    )?;
+
+    let data_re = Regex::new(&format!(r"^{}\.(\d+)$", regex::escape(config.file()))).unwrap();
+    let hint_re = Regex::new(r"^hint\.(\d+)$").unwrap();
+    let data_files = list_files(&config.data_dirs(), &data_re)?;
+    let hint_files = list_files(&config.data_dirs(), &hint_re)?;
+
+    let mut data_changed = false;
+    for (seq, path) in &data_files {
+        let (records, changed) = repair_data_file(config, path, &mut summary)?;
+        data_changed |= changed;
+        rebuild_hint(
+            &config.get_hint_filepath_by_seq(*seq),
+            &records,
+            &mut summary,
+        )?;
+    }
+    for (seq, path) in &hint_files {
+        if !data_files.contains_key(seq) {
+            remove_leftover(path, "hint file without data file", &mut summary)?;
+        }
+    }
+
+    let active = config.get_active_filepath();
+    if active.exists() {
+        data_changed |= repair_data_file(config, &active, &mut summary)?.1;
+    }
+
+    // 数据文件内容变化后，快照和持久化索引中的位置已失效
+    if data_changed {
+        remove_leftover(
+            &config.snapshot_filepath(),
+            "stale keydir snapshot",
+            &mut summary,
+        )?;
+        remove_leftover(&config.keydir_dir(), "stale keydir index", &mut summary)?;
+    }
+
+    if !summary.changes.is_empty() {
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.data_dir().join(REPAIR_LOG))?;
+        for change in &summary.changes {
+            writeln!(log, "{}\t{}", Utc::now().to_rfc3339(), change)?;
+        }
+    }
+    Ok(summary)
+}
+
+// minkv repair，输出改动摘要
+pub fn run(config: &Config) -> anyhow::Result<()> {
+    let summary = repair(config)?;
+    if summary.changes.is_empty() {
+        println!("nothing to repair in {}", config.data_dir().display());
+        return Ok(());
+    }
+    for change in &summary.changes {
+        println!("{}", change);
+    }
+    println!(
+        "{} changes, see {}",
+        summary.changes.len(),
+        config.data_dir().join(REPAIR_LOG).display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::check;
+    use crate::config::test_config;
+    use crate::store::store::{new_store, Op};
+    use std::io::{Seek, SeekFrom};
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn repair_salvages_damaged_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for i in 0..20 {
+                store.set(format!("key{:02}", i).as_bytes(), b"value", 0)?;
+            }
+            store.delete(b"key19")?;
+            store.close();
+        }
+
+        // data.1 第一条记录损坏，data.2 末尾写入中断，并遗留合并目录
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(config.get_filepath_by_seq(1))?;
+        file.seek(SeekFrom::Start(30))?;
+        file.write_all(b"X")?;
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(config.get_filepath_by_seq(2))?;
+        file.write_all(&[1, 2, 3])?;
+        std::fs::create_dir_all(config.merge_dir())?;
+        assert!(check::check(&config)?.errors() > 0);
+
+        let summary = repair(&config)?;
+        assert!(!summary.changes.is_empty());
+        assert!(config.get_hint_filepath_by_seq(1).exists());
+        assert!(!config.snapshot_filepath().exists());
+        let report = check::check(&config)?;
+        assert_eq!(0, report.errors(), "{}", report);
+        assert!(repair(&config)?.changes.is_empty());
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert!(store.get(b"key00").is_err());
+        assert!(store.get(b"key19").is_err());
+        assert_eq!(b"value".to_vec(), store.get(b"key01")?);
+        assert_eq!(18, store.len());
+        Ok(())
+    }
+}
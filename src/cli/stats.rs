@@ -0,0 +1,355 @@
+use crate::config::Config;
+use crate::entry::entry::{Entry, EntryFile};
+use crate::store::store::{open_read_only_store, Keydir, Store, ACTIVE_FILE_SEQ};
+use crate::util::lock::DirLock;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::sync::Arc;
+
+// 第一个分隔符之前（含分隔符）的部分作为 key 前缀
+const PREFIX_DELIMITERS: &[u8] = b":/";
+
+// 单个数据文件中有效与无效记录的统计，有效记录即 keydir 当前指向且未过期的记录
+#[derive(Debug, Serialize, Default)]
+pub struct FileStats {
+    pub file: String,
+    pub bytes: u64,
+    pub live_records: u64,
+    pub live_bytes: u64,
+    pub dead_records: u64, // 被覆盖的旧值、删除记录和已过期的记录
+    pub dead_bytes: u64,
+    pub damaged_bytes: u64,
+}
+
+impl FileStats {
+    fn garbage_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
+        }
+        (self.bytes - self.live_bytes) as f64 / self.bytes as f64
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Bucket {
+    pub le: u64, // 大小不超过 le 的数量，le 为 2 的幂
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct KeySize {
+    pub key: String,
+    pub value_size: u64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Prefix {
+    pub prefix: String,
+    pub keys: u64,
+    pub bytes: u64, // key 和 value 的总大小
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct Report {
+    pub db_dir: String,
+    pub files: Vec<FileStats>,
+    pub keys: u64,
+    pub ttl_keys: u64,
+    pub expired_keys: u64, // 最后一条记录已过期的 key
+    pub key_sizes: Vec<Bucket>,
+    pub value_sizes: Vec<Bucket>,
+    pub largest_keys: Vec<KeySize>,
+    pub prefixes: Vec<Prefix>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "db_dir: {}", self.db_dir)?;
+        writeln!(f, "files:")?;
+        for s in &self.files {
+            write!(
+                f,
+                "  {:<12}{:>12} bytes  live {} ({} bytes)  dead {} ({} bytes)  garbage {:.1}%",
+                s.file,
+                s.bytes,
+                s.live_records,
+                s.live_bytes,
+                s.dead_records,
+                s.dead_bytes,
+                s.garbage_ratio() * 100.0
+            )?;
+            if s.damaged_bytes > 0 {
+                write!(f, "  damaged {} bytes", s.damaged_bytes)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "keys: {} (ttl {}, expired {})",
+            self.keys, self.ttl_keys, self.expired_keys
+        )?;
+        for (name, buckets) in [
+            ("key sizes", &self.key_sizes),
+            ("value sizes", &self.value_sizes),
+        ] {
+            writeln!(f, "{}:", name)?;
+            for b in buckets {
+                writeln!(f, "  <= {:<10}{}", b.le, b.count)?;
+            }
+        }
+        writeln!(f, "largest keys:")?;
+        for k in &self.largest_keys {
+            writeln!(f, "  {:<12}{}", k.value_size, k.key)?;
+        }
+        write!(f, "top prefixes:")?;
+        for p in &self.prefixes {
+            write!(f, "\n  {:<20}{} keys  {} bytes", p.prefix, p.keys, p.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// 按 2 的幂分桶
+#[derive(Default)]
+struct Histogram(BTreeMap<u64, u64>);
+
+impl Histogram {
+    fn add(&mut self, size: usize) {
+        *self.0.entry((size as u64).next_power_of_two()).or_default() += 1;
+    }
+
+    fn buckets(self) -> Vec<Bucket> {
+        self.0
+            .into_iter()
+            .map(|(le, count)| Bucket { le, count })
+            .collect()
+    }
+}
+
+// 遍历有效记录时累计的 keyspace 统计
+struct Keyspace {
+    top: usize,
+    keys: u64,
+    ttl_keys: u64,
+    expired: HashSet<Vec<u8>>,
+    key_sizes: Histogram,
+    value_sizes: Histogram,
+    largest: BinaryHeap<Reverse<(u64, Vec<u8>)>>,
+    prefixes: HashMap<Vec<u8>, (u64, u64)>,
+}
+
+impl Keyspace {
+    fn new(top: usize) -> Keyspace {
+        Keyspace {
+            top,
+            keys: 0,
+            ttl_keys: 0,
+            expired: HashSet::new(),
+            key_sizes: Histogram::default(),
+            value_sizes: Histogram::default(),
+            largest: BinaryHeap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    // 按文件顺序记录每个 key 的最后一条记录是否已过期
+    fn track_expired(&mut self, entry: &Entry) {
+        if entry.is_expired() && !entry.is_removed() {
+            self.expired.insert(entry.key.clone());
+        } else {
+            self.expired.remove(&entry.key);
+        }
+    }
+
+    fn add(&mut self, entry: Entry) {
+        self.keys += 1;
+        if entry.timestamp != 0 {
+            self.ttl_keys += 1;
+        }
+        self.key_sizes.add(entry.key.len());
+        self.value_sizes.add(entry.value.len());
+
+        if let Some(i) = entry.key.iter().position(|b| PREFIX_DELIMITERS.contains(b)) {
+            let prefix = self.prefixes.entry(entry.key[..=i].to_vec()).or_default();
+            prefix.0 += 1;
+            prefix.1 += (entry.key.len() + entry.value.len()) as u64;
+        }
+
+        // 最小堆只保留最大的 top 个
+        let value_size = entry.value.len() as u64;
+        if self.largest.len() < self.top {
+            self.largest.push(Reverse((value_size, entry.key)));
+        } else if self
+            .largest
+            .peek()
+            .is_some_and(|Reverse((min, _))| value_size > *min)
+        {
+            self.largest.pop();
+            self.largest.push(Reverse((value_size, entry.key)));
+        }
+    }
+
+    fn finish(self, report: &mut Report) {
+        report.keys = self.keys;
+        report.ttl_keys = self.ttl_keys;
+        report.expired_keys = self.expired.len() as u64;
+        report.key_sizes = self.key_sizes.buckets();
+        report.value_sizes = self.value_sizes.buckets();
+        report.largest_keys = self
+            .largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((value_size, key))| KeySize {
+                key: key.escape_ascii().to_string(),
+                value_size,
+            })
+            .collect();
+
+        let mut prefixes: Vec<_> = self.prefixes.into_iter().collect();
+        prefixes.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(&b.0)));
+        report.prefixes = prefixes
+            .into_iter()
+            .take(self.top)
+            .map(|(prefix, (keys, bytes))| Prefix {
+                prefix: prefix.escape_ascii().to_string(),
+                keys,
+                bytes,
+            })
+            .collect();
+    }
+}
+
+// 逐条读取数据文件，与 keydir 对比区分有效和无效记录
+fn scan_file(
+    store: &Store<Keydir>,
+    file_id: u16,
+    file: File,
+    name: String,
+    keyspace: &mut Keyspace,
+) -> std::io::Result<FileStats> {
+    let mut stats = FileStats {
+        file: name,
+        bytes: file.metadata()?.len(),
+        ..Default::default()
+    };
+    let mut entries = EntryFile::new(file);
+    for record in entries.iter() {
+        let size = record.entry.size() as u64;
+        keyspace.track_expired(&record.entry);
+        let live = !record.entry.is_expired()
+            && store
+                .metadata(&record.entry.key)
+                .is_some_and(|m| m.file_id == file_id && m.value_pos == record.value_pos);
+        if live {
+            stats.live_records += 1;
+            stats.live_bytes += size;
+            keyspace.add(record.entry);
+        } else {
+            stats.dead_records += 1;
+            stats.dead_bytes += size;
+        }
+    }
+    stats.damaged_bytes = entries.damaged().iter().map(|r| r.len).sum();
+    Ok(stats)
+}
+
+// 只读打开 db_dir 统计各数据文件的有效数据和 keyspace 分布，可以在服务运行时执行
+pub fn stats(config: Arc<Config>, top: usize) -> anyhow::Result<Report> {
+    // 统计期间阻止合并替换文件
+    let _lock = DirLock::shared(&config.lock_filepath())?;
+    let store = open_read_only_store(Arc::clone(&config))?;
+
+    let mut report = Report {
+        db_dir: config.data_dir().display().to_string(),
+        ..Default::default()
+    };
+    let mut keyspace = Keyspace::new(top);
+    for seq in 1..config.get_next_datafile_seq() {
+        let path = config.get_filepath_by_seq(seq);
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        report
+            .files
+            .push(scan_file(&store, seq, file, name, &mut keyspace)?);
+    }
+    let file = File::open(config.get_active_filepath())?;
+    report.files.push(scan_file(
+        &store,
+        ACTIVE_FILE_SEQ,
+        file,
+        config.file().to_string(),
+        &mut keyspace,
+    )?);
+    keyspace.finish(&mut report);
+    Ok(report)
+}
+
+// minkv stats
+pub fn run(config: Config, json: bool, top: usize) -> anyhow::Result<()> {
+    let report = stats(Arc::new(config), top)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::store::store::{new_store, Op};
+    use crate::util::time;
+    use std::sync::mpsc;
+
+    #[test]
+    fn stats_live_and_dead_records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for i in 0..10 {
+                store.set(format!("user:{}", i).as_bytes(), b"old", 0)?;
+            }
+            for i in 0..10 {
+                store.set(format!("user:{}", i).as_bytes(), b"new", 0)?;
+            }
+            store.set(b"big", &[b'x'; 100], 0)?;
+            store.set(b"ttl", b"value", time::get_millisec(600_000))?;
+            store.set(b"expired", b"value", 1)?;
+            store.delete(b"user:9")?;
+            store.close();
+        }
+
+        let report = stats(Arc::clone(&config), 2)?;
+        assert_eq!(11, report.keys);
+        assert_eq!((1, 1), (report.ttl_keys, report.expired_keys));
+        let live: u64 = report.files.iter().map(|s| s.live_records).sum();
+        let dead: u64 = report.files.iter().map(|s| s.dead_records).sum();
+        assert_eq!((11, 13), (live, dead));
+        assert_eq!(
+            vec![Bucket { le: 128, count: 1 }],
+            report.value_sizes[report.value_sizes.len() - 1..]
+        );
+        assert_eq!("big", report.largest_keys[0].key);
+        assert_eq!(
+            Prefix {
+                prefix: "user:".to_string(),
+                keys: 9,
+                bytes: 9 * 9
+            },
+            report.prefixes[0]
+        );
+        Ok(())
+    }
+}
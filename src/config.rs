@@ -1,9 +1,12 @@
+use crate::store::disk::DEFAULT_CACHE_PAGES;
+use crate::store::file::OpenMode;
 use anyhow::Result;
 use regex::Regex;
 use serde::Deserialize;
 use std::io::{Error, ErrorKind};
 use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -12,12 +15,22 @@ use std::{
 #[derive(Debug, Deserialize)]
 struct FileConfig {
     db_dir: Option<String>,
+    data_dirs: Option<Vec<String>>,
     file: Option<String>,
     file_max_size: Option<u32>,
     sync_keys: Option<u32>,
+    durability: Option<String>,
+    preallocate: Option<bool>,
+    o_dsync: Option<bool>,
+    o_direct: Option<bool>,
     merge_file_num: Option<u32>,
+    max_disk_bytes: Option<u64>,
+    shutdown_timeout: Option<u64>,
     server: Option<FileConfigServer>,
     grpc: Option<FileConfigServer>,
+    keydir: Option<FileConfigKeydir>,
+    scrub: Option<FileConfigScrub>,
+    compaction: Option<FileConfigCompaction>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +39,31 @@ struct FileConfigServer {
     port: Option<u32>,
 }
 
+#[derive(Debug, Deserialize)]
+struct FileConfigKeydir {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    prefix_compression: Option<bool>,
+    cache_pages: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileConfigScrub {
+    enabled: Option<bool>,
+    rate: Option<u64>,
+    interval: Option<u64>,
+    action: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileConfigCompaction {
+    rate: Option<u64>,
+    slowdown_files: Option<u64>,
+    stall_files: Option<u64>,
+    slowdown_dead_bytes: Option<u64>,
+    stall_dead_bytes: Option<u64>,
+}
+
 impl TryFrom<&Path> for FileConfig {
     type Error = anyhow::Error;
 
@@ -81,6 +119,22 @@ impl TryFrom<&Path> for FileConfig {
             }
         }
 
+        if let Some(durability) = &config.durability {
+            Durability::try_from(durability.as_str())?;
+        }
+
+        if let Some(keydir) = &config.keydir {
+            if let Some(kind) = &keydir.kind {
+                KeydirKind::try_from(kind.as_str())?;
+            }
+        }
+
+        if let Some(scrub) = &config.scrub {
+            if let Some(action) = &scrub.action {
+                ScrubAction::try_from(action.as_str())?;
+            }
+        }
+
         Ok(config)
     }
 }
@@ -88,12 +142,22 @@ impl TryFrom<&Path> for FileConfig {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     db_dir: String,
+    data_dirs: Vec<String>, // 额外的数据目录，新归档和合并生成的文件放在占用最少的目录
     file: String,
     file_max_size: usize, // 字节
     sync_keys: u32,
+    durability: Durability,
+    preallocate: bool, // 预分配 active file 到 file_max_size
+    o_dsync: bool,
+    o_direct: bool,
     server: ConfigServer,
     merge_file_num: usize,
+    max_disk_bytes: u64,   // 数据和 hint 文件占用空间上限，0 表示不限制
+    shutdown_timeout: u64, // 秒
     grpc: Option<ConfigServer>,
+    keydir: ConfigKeydir,
+    scrub: ConfigScrub,
+    compaction: ConfigCompaction,
 }
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct ConfigServer {
@@ -101,6 +165,180 @@ pub struct ConfigServer {
     port: u32,
 }
 
+// 写入确认前是否需要落盘
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    #[default]
+    Periodic, // 按 sync_keys 定期同步
+    Always,   // 每批写入同步后再返回
+}
+
+impl TryFrom<&str> for Durability {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "periodic" => Ok(Durability::Periodic),
+            "always" => Ok(Durability::Always),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid durability {}", value),
+            )
+            .into()),
+        }
+    }
+}
+
+// keydir 索引实现
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeydirKind {
+    #[default]
+    Hash, // HashMap
+    Compact, // 内存紧凑型
+    Disk,    // 磁盘型
+}
+
+impl TryFrom<&str> for KeydirKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "hash" => Ok(KeydirKind::Hash),
+            "compact" => Ok(KeydirKind::Compact),
+            "disk" => Ok(KeydirKind::Disk),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid keydir type {}", value),
+            )
+            .into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConfigKeydir {
+    kind: KeydirKind,
+    prefix_compression: bool,
+    cache_pages: usize,
+}
+
+impl Default for ConfigKeydir {
+    fn default() -> Self {
+        ConfigKeydir {
+            kind: KeydirKind::Hash,
+            prefix_compression: false,
+            cache_pages: DEFAULT_CACHE_PAGES,
+        }
+    }
+}
+
+impl ConfigKeydir {
+    pub fn kind(&self) -> KeydirKind {
+        self.kind
+    }
+
+    pub fn prefix_compression(&self) -> bool {
+        self.prefix_compression
+    }
+
+    pub fn cache_pages(&self) -> usize {
+        self.cache_pages
+    }
+}
+
+// 校验发现损坏数据后的处理方式
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScrubAction {
+    #[default]
+    Alert, // 只记录日志和指标
+    Quarantine, // 同时从 keydir 中移除损坏的记录
+}
+
+impl TryFrom<&str> for ScrubAction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "alert" => Ok(ScrubAction::Alert),
+            "quarantine" => Ok(ScrubAction::Quarantine),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid scrub action {}", value),
+            )
+            .into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConfigScrub {
+    enabled: bool,
+    rate: u64,     // 每秒校验字节数
+    interval: u64, // 两轮校验间隔秒数
+    action: ScrubAction,
+}
+
+impl Default for ConfigScrub {
+    fn default() -> Self {
+        ConfigScrub {
+            enabled: false,
+            rate: 4 * 1024 * 1024,
+            interval: 24 * 3600,
+            action: ScrubAction::Alert,
+        }
+    }
+}
+
+impl ConfigScrub {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn action(&self) -> ScrubAction {
+        self.action
+    }
+}
+
+// 合并限速及写入背压，阈值为 0 表示不启用
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ConfigCompaction {
+    rate: u64,                // 合并每秒复制的字节数
+    slowdown_files: u64,      // 未合并的归档文件数达到该值时减慢写入
+    stall_files: u64,         // 未合并的归档文件数达到该值时暂停写入
+    slowdown_dead_bytes: u64, // 归档文件中的无效数据达到该值时减慢写入
+    stall_dead_bytes: u64,    // 归档文件中的无效数据达到该值时暂停写入
+}
+
+impl ConfigCompaction {
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn slowdown_files(&self) -> u64 {
+        self.slowdown_files
+    }
+
+    pub fn stall_files(&self) -> u64 {
+        self.stall_files
+    }
+
+    pub fn slowdown_dead_bytes(&self) -> u64 {
+        self.slowdown_dead_bytes
+    }
+
+    pub fn stall_dead_bytes(&self) -> u64 {
+        self.stall_dead_bytes
+    }
+}
+
 impl ConfigServer {
     pub fn get_addr(&self) -> anyhow::Result<SocketAddr> {
         let addr_str = format!("{}:{}", self.address, self.port);
@@ -119,15 +357,25 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             db_dir: "./dbdata".to_string(),
+            data_dirs: Vec::new(),
             file: String::from("data"),
             file_max_size: 1024 * 100,
             sync_keys: 0,
+            durability: Durability::Periodic,
+            preallocate: false,
+            o_dsync: false,
+            o_direct: false,
             server: ConfigServer {
                 address: "127.0.0.1".to_string(),
                 port: 6380,
             },
             grpc: None,
             merge_file_num: 10,
+            max_disk_bytes: 0,
+            shutdown_timeout: 30,
+            keydir: ConfigKeydir::default(),
+            scrub: ConfigScrub::default(),
+            compaction: ConfigCompaction::default(),
         }
     }
 }
@@ -143,6 +391,10 @@ impl TryFrom<&Path> for Config {
             default_config.db_dir = value;
         }
 
+        if let Some(value) = config.data_dirs {
+            default_config.data_dirs = value;
+        }
+
         if let Some(value) = config.file {
             default_config.file = value;
         }
@@ -154,10 +406,34 @@ impl TryFrom<&Path> for Config {
             default_config.merge_file_num = value as usize;
         }
 
+        if let Some(value) = config.max_disk_bytes {
+            default_config.max_disk_bytes = value;
+        }
+
+        if let Some(value) = config.shutdown_timeout {
+            default_config.shutdown_timeout = value;
+        }
+
         if let Some(value) = config.sync_keys {
             default_config.sync_keys = value
         }
 
+        if let Some(value) = config.durability {
+            default_config.durability = Durability::try_from(value.as_str())?;
+        }
+
+        if let Some(value) = config.preallocate {
+            default_config.preallocate = value
+        }
+
+        if let Some(value) = config.o_dsync {
+            default_config.o_dsync = value
+        }
+
+        if let Some(value) = config.o_direct {
+            default_config.o_direct = value
+        }
+
         if let Some(server) = config.server {
             if let Some(server_address) = server.address {
                 default_config.server.address = server_address
@@ -178,6 +454,51 @@ impl TryFrom<&Path> for Config {
             default_config.grpc = Some(config);
         }
 
+        if let Some(keydir) = config.keydir {
+            if let Some(kind) = keydir.kind {
+                default_config.keydir.kind = KeydirKind::try_from(kind.as_str())?;
+            }
+            if let Some(prefix_compression) = keydir.prefix_compression {
+                default_config.keydir.prefix_compression = prefix_compression;
+            }
+            if let Some(cache_pages) = keydir.cache_pages {
+                default_config.keydir.cache_pages = cache_pages;
+            }
+        }
+
+        if let Some(scrub) = config.scrub {
+            if let Some(enabled) = scrub.enabled {
+                default_config.scrub.enabled = enabled;
+            }
+            if let Some(rate) = scrub.rate {
+                default_config.scrub.rate = rate;
+            }
+            if let Some(interval) = scrub.interval {
+                default_config.scrub.interval = interval;
+            }
+            if let Some(action) = scrub.action {
+                default_config.scrub.action = ScrubAction::try_from(action.as_str())?;
+            }
+        }
+
+        if let Some(compaction) = config.compaction {
+            if let Some(rate) = compaction.rate {
+                default_config.compaction.rate = rate;
+            }
+            if let Some(value) = compaction.slowdown_files {
+                default_config.compaction.slowdown_files = value;
+            }
+            if let Some(value) = compaction.stall_files {
+                default_config.compaction.stall_files = value;
+            }
+            if let Some(value) = compaction.slowdown_dead_bytes {
+                default_config.compaction.slowdown_dead_bytes = value;
+            }
+            if let Some(value) = compaction.stall_dead_bytes {
+                default_config.compaction.stall_dead_bytes = value;
+            }
+        }
+
         default_config.check()?;
 
         Ok(default_config)
@@ -185,6 +506,12 @@ impl TryFrom<&Path> for Config {
 }
 
 const MERGE_DIR: &str = ".merge";
+const LOAD_DIR: &str = ".load";
+const KEYDIR_DIR: &str = ".keydir";
+const SNAPSHOT: &str = "snapshot";
+const LOCK: &str = "LOCK";
+const OWNER: &str = "OWNER";
+const QUARANTINE_DIR: &str = "quarantine";
 const HINT: &str = "hint";
 
 impl Config {
@@ -196,33 +523,43 @@ impl Config {
 
     fn check(&self) -> anyhow::Result<()> {
         // check if the datadir exists
-        {
-            let path = self.data_dir();
+        for path in self.data_dirs() {
             if !path.exists() {
                 fs::create_dir_all(path).unwrap_or_else(|_| panic!("Failed to create directory: {:?}", path));
             }
         }
 
-        // check if the merge dir exists，测试是否可写
-        {
-            let path = self.merge_dir();
-            if !path.exists() {
-                fs::create_dir_all(&path).unwrap_or_else(|_| panic!("Failed to create directory: {:?}", path));
-            }
-            fs::remove_dir_all(path).unwrap();
-        }
+        // 合并目录可能正被运行中的服务使用，由写入进程启动时清理
 
         Ok(())
     }
 
+    // 其它配置使用默认值，用于只读打开备份等目录
+    pub fn for_dir(dir: &Path) -> Config {
+        Config {
+            db_dir: dir.to_string_lossy().to_string(),
+            ..Config::default()
+        }
+    }
+
     pub fn get_addr(&self) -> Result<SocketAddr> {
         self.server.get_addr()
     }
 
+    // 清理异常退出时遗留的合并目录
     pub fn merge_cleanup(&self) {
-        let dir = self.merge_dir();
-        let path = Path::new(&dir);
-        fs::remove_dir(path).unwrap();
+        self.remove_merge_dirs().unwrap();
+    }
+
+    // 删除各数据目录中的合并目录，db_dir 中的合并目录标记合并正在进行，最后删除
+    pub fn remove_merge_dirs(&self) -> std::io::Result<()> {
+        for dir in self.data_dirs().into_iter().rev() {
+            match fs::remove_dir_all(self.merge_dir_in(dir)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
     pub fn file(&self) -> &str {
@@ -233,24 +570,119 @@ impl Config {
         Path::new(&self.db_dir)
     }
 
+    // 存放归档数据文件和 hint 文件的所有目录，db_dir 在最前
+    pub fn data_dirs(&self) -> Vec<&Path> {
+        let mut dirs = vec![self.data_dir()];
+        dirs.extend(self.data_dirs.iter().map(Path::new));
+        dirs
+    }
+
+    // 依次在各数据目录的 sub 子目录中查找文件，都不存在时返回 db_dir 中的路径
+    fn locate(&self, sub: &str, filename: &str) -> PathBuf {
+        if !self.data_dirs.is_empty() {
+            for dir in self.data_dirs() {
+                let path = dir.join(sub).join(filename);
+                if path.exists() {
+                    return path;
+                }
+            }
+        }
+        self.data_dir().join(sub).join(filename)
+    }
+
+    // 目录中数据文件、hint 文件以及合并中的文件的总大小
+    fn dir_usage(&self, dir: &Path) -> u64 {
+        let re = Regex::new(&format!(r"^({}|{})\.\d+$", regex::escape(&self.file), HINT)).unwrap();
+        let size = |dir: &Path, all: bool| -> u64 {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return 0;
+            };
+            entries
+                .flatten()
+                .filter(|entry| all || re.is_match(&entry.file_name().to_string_lossy()))
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum()
+        };
+        size(dir, false) + size(&dir.join(MERGE_DIR), true)
+    }
+
+    // 占用最少的数据目录，新的 active file 和合并生成的文件放在该目录
+    pub fn placement_dir(&self) -> &Path {
+        self.data_dirs()
+            .into_iter()
+            .min_by_key(|dir| self.dir_usage(dir))
+            .unwrap()
+    }
+
     pub fn merge_dir(&self) -> PathBuf {
-        self.data_dir().join(MERGE_DIR)
+        self.merge_dir_in(self.data_dir())
+    }
+
+    pub fn merge_dir_in(&self, dir: &Path) -> PathBuf {
+        dir.join(MERGE_DIR)
+    }
+
+    // minkv load 生成的文件先写入该目录，导入时再移到 db_dir
+    pub fn load_dir(&self) -> PathBuf {
+        self.data_dir().join(LOAD_DIR)
+    }
+
+    pub fn keydir_dir(&self) -> PathBuf {
+        self.data_dir().join(KEYDIR_DIR)
+    }
+
+    pub fn quarantine_dir(&self) -> PathBuf {
+        self.data_dir().join(QUARANTINE_DIR)
     }
 
+    pub fn lock_filepath(&self) -> PathBuf {
+        self.data_dir().join(LOCK)
+    }
+
+    pub fn owner_lock_filepath(&self) -> PathBuf {
+        self.data_dir().join(OWNER)
+    }
+
+    pub fn snapshot_filepath(&self) -> PathBuf {
+        self.data_dir().join(SNAPSHOT)
+    }
+
+    // 合并文件写在所在数据目录的合并目录中，替换时在同一目录内改名
     pub fn get_merge_filepath_by_seq(&self, idx: u16) -> PathBuf {
-        self.merge_dir().join(idx.to_string())
+        self.locate(MERGE_DIR, &idx.to_string())
     }
 
     pub fn get_merge_hint_filepath_by_seq(&self, idx: u16) -> PathBuf {
-        let mut path = self.merge_dir().join(idx.to_string());
+        self.locate(MERGE_DIR, &format!("{}.{}", idx, HINT))
+    }
+
+    pub fn get_merge_filepath_in(&self, dir: &Path, idx: u16) -> PathBuf {
+        dir.join(MERGE_DIR).join(idx.to_string())
+    }
+
+    pub fn get_merge_hint_filepath_in(&self, dir: &Path, idx: u16) -> PathBuf {
+        dir.join(MERGE_DIR).join(format!("{}.{}", idx, HINT))
+    }
+
+    pub fn get_load_filepath_by_seq(&self, idx: u16) -> PathBuf {
+        self.load_dir().join(idx.to_string())
+    }
+
+    pub fn get_load_hint_filepath_by_seq(&self, idx: u16) -> PathBuf {
+        let mut path = self.load_dir().join(idx.to_string());
         path.set_extension(HINT);
         path
     }
 
+    // hint 文件与数据文件在同一目录
     pub fn get_hint_filepath_by_seq(&self, idx: u16) -> PathBuf {
-        let filename = format!("{}.{}", HINT, idx);
-        let file = self.data_dir().join(filename);
-        file
+        let path = self.get_filepath_by_seq(idx);
+        self.get_hint_filepath_in(path.parent().unwrap_or(self.data_dir()), idx)
+    }
+
+    pub fn get_hint_filepath_in(&self, dir: &Path, idx: u16) -> PathBuf {
+        dir.join(format!("{}.{}", HINT, idx))
     }
 
     pub fn file_max_size(&self) -> usize {
@@ -258,25 +690,32 @@ impl Config {
     }
 
     pub fn get_filepath_by_seq(&self, idx: u16) -> PathBuf {
-        let filename = format!("{}.{}", self.file, idx);
-        let file = self.data_dir().join(filename);
-        file
+        self.locate("", &format!("{}.{}", self.file, idx))
     }
 
+    pub fn get_filepath_in(&self, dir: &Path, idx: u16) -> PathBuf {
+        dir.join(format!("{}.{}", self.file, idx))
+    }
+
+    // active file 所在目录，归档时在该目录内改名
     pub fn get_active_filepath(&self) -> PathBuf {
-        self.data_dir().join(self.file())
+        self.locate("", self.file())
+    }
+
+    // 新的 active file 的路径，位于占用最少的数据目录
+    pub fn get_new_active_filepath(&self) -> PathBuf {
+        self.placement_dir().join(self.file())
     }
 
     pub fn get_next_datafile_seq(&self) -> u16 {
-        let dir_path = self.data_dir();
         let pattern = format!(r"{}\.(\d+)", self.file); // 动态生成正则表达式
 
         // 创建正则表达式
         let re = Regex::new(&pattern).unwrap();
 
-        // 读取目录中的所有文件
+        // 读取所有数据目录中的文件
         let mut max_number = 0;
-        for entry in fs::read_dir(dir_path).unwrap() {
+        for entry in self.data_dirs().into_iter().flat_map(|dir| fs::read_dir(dir).unwrap()) {
             let entry = entry.unwrap();
             let file_name = entry.file_name();
             let file_name_str = file_name.to_string_lossy();
@@ -307,13 +746,57 @@ impl Config {
         self.merge_file_num
     }
 
+    pub fn max_disk_bytes(&self) -> u64 {
+        self.max_disk_bytes
+    }
+
     pub fn get_sync_keys_num(&self) -> u32 {
         self.sync_keys
     }
 
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn preallocate(&self) -> bool {
+        self.preallocate
+    }
+
+    // active file 写入句柄的打开方式
+    pub fn open_mode(&self) -> OpenMode {
+        OpenMode {
+            dsync: self.o_dsync,
+            direct: self.o_direct,
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
     pub fn get_grpc(&self) -> &Option<ConfigServer> {
         &self.grpc
     }
+
+    pub fn get_scrub(&self) -> &ConfigScrub {
+        &self.scrub
+    }
+
+    pub fn get_compaction(&self) -> &ConfigCompaction {
+        &self.compaction
+    }
+
+    pub fn get_keydir(&self) -> &ConfigKeydir {
+        &self.keydir
+    }
+}
+
+// 测试用：在 db_dir 旁写入配置文件并加载，extra 为 db_dir 之外的配置行
+#[cfg(test)]
+pub(crate) fn test_config(db_dir: &Path, extra: &str) -> anyhow::Result<Config> {
+    let path = db_dir.with_extension("toml");
+    fs::write(&path, format!("db_dir = {:?}\n{}", db_dir.to_str().unwrap(), extra))?;
+    Config::try_from(path.as_path())
 }
 
 #[cfg(test)]
@@ -356,6 +839,25 @@ mod tests {
         assert_eq!(config.file_max_size, 1024 * 100);
         assert_eq!(config.server.address, String::from("127.0.0.1"));
         assert_eq!(config.server.port, 7788);
+        assert_eq!(config.keydir.kind(), super::KeydirKind::Hash);
+        assert_eq!(config.shutdown_timeout(), std::time::Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[test]
+    fn config_keydir_from_file() -> anyhow::Result<()> {
+        let mut tmpfile = NamedTempFile::new()?;
+        let config_content = r#"
+                    db_dir = "mysql_data"
+                    [keydir]
+                    type = "compact"
+                    prefix_compression = true
+                "#;
+        writeln!(tmpfile, "{}", config_content)?;
+
+        let config = super::Config::try_from(tmpfile.path())?;
+        assert_eq!(config.get_keydir().kind(), super::KeydirKind::Compact);
+        assert!(config.get_keydir().prefix_compression());
         Ok(())
     }
 }
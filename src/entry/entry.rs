@@ -3,7 +3,7 @@ use crc32fast::Hasher;
 use log::*;
 use std::fmt;
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[repr(u8)]
@@ -68,181 +68,354 @@ pub struct Entry {
 pub struct EntryParseResult {
     pub value_pos: u64,
     pub entry: Entry,
+    pub crc_ok: bool, // 只有 keep_crc_mismatch 时才可能为 false
+}
+
+// 数据文件中无法解析的区间
+#[derive(Debug, Clone, PartialEq)]
+pub struct DamagedRange {
+    pub offset: u64,
+    pub len: u64,
+    pub tail: bool,           // 之后没有完整记录，多为写入中断
+    pub key: Option<Vec<u8>>, // 从损坏记录头部尽量解析出的 key
+    pub reason: RecordError,  // 区间开头的记录无法解析的原因
+}
+
+// 查找下一条完整记录时每次读入的字节数
+const RESYNC_WINDOW: usize = 64 * 1024;
+
+// 损坏区间之后的查找结果
+enum Resync {
+    Record(u64),   // 下一条完整记录的位置
+    ZeroTail(u64), // 从该位置起全为 0，之后没有记录
+    End,           // 直到文件末尾都没有完整记录
+}
+
+// 逐条解析数据文件，遇到损坏的记录时跳到下一条可以通过 crc 校验的记录
+pub struct EntryFile {
+    file: BufReader<File>,
+    offset: u64,
+    len: u64,
+    damaged: Vec<DamagedRange>,
+    keep_crc_mismatch: bool,
 }
 
-pub struct EntryFile(File, usize);
 impl EntryFile {
     pub fn new(file: File) -> EntryFile {
-        EntryFile(file, 0)
+        EntryFile::with_offset(file, 0)
+    }
+
+    // 从指定位置开始解析，用于只回放追加的数据
+    pub fn with_offset(file: File, offset: u64) -> EntryFile {
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        EntryFile {
+            file: BufReader::new(file),
+            offset,
+            len,
+            damaged: Vec::new(),
+            keep_crc_mismatch: false,
+        }
+    }
+
+    // 长度和 op 完整但 crc 不一致的记录照常返回，不计入损坏区间，用于排查
+    pub fn keep_crc_mismatch(mut self) -> EntryFile {
+        self.keep_crc_mismatch = true;
+        self
     }
 
     pub fn iter(&mut self) -> &mut Self {
         self
     }
-}
 
-// Iterator
-impl Iterator for EntryFile {
-    type Item = EntryParseResult;
+    // 解析过程中跳过的损坏区间
+    pub fn damaged(&self) -> &[DamagedRange] {
+        &self.damaged
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    pub fn take_damaged(&mut self) -> Vec<DamagedRange> {
+        std::mem::take(&mut self.damaged)
+    }
+
+    // 顺序读取时目标位置在缓冲区内，不丢弃已读入的数据
+    fn seek(&mut self, offset: u64) -> io::Result<()> {
+        let current = self.file.stream_position()?;
+        self.file.seek_relative(offset as i64 - current as i64)
+    }
+
+    // 读取 offset 处的记录，记录不完整或 op 无效时返回原因，crc 由调用方判断
+    fn read_at(&mut self, offset: u64) -> Result<Entry, RecordError> {
         let header_size = Entry::default().header_size();
+        if offset + header_size as u64 > self.len {
+            return Err(RecordError::TruncatedHeader);
+        }
         let mut buffer = vec![0; header_size];
+        self.seek(offset)
+            .and_then(|_| self.file.read_exact(&mut buffer))
+            .map_err(|_| RecordError::TruncatedHeader)?;
 
-        if self.0.read_exact(&mut buffer).is_ok() {
-            let crc = u32::from_le_bytes(
-                buffer[..4]
-                    .try_into()
-                    .map_err(|_| "Invalid CRC data")
-                    .unwrap(),
-            );
-            let timestamp = u64::from_le_bytes(
-                buffer[4..12]
-                    .try_into()
-                    .map_err(|_| "Invalid timestamp data")
-                    .unwrap(),
-            );
-            let key_size = u32::from_le_bytes(
-                buffer[12..16]
-                    .try_into()
-                    .map_err(|_| "Invalid key_size data")
-                    .unwrap(),
-            );
-            let value_size = u64::from_le_bytes(
-                buffer[16..24]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            let op = Op::from_le_bytes(
-                buffer[24..25]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            )
-            .unwrap();
+        let size = record_size(&buffer).ok_or(RecordError::TruncatedRecord)?;
+        if offset.checked_add(size).is_none_or(|end| end > self.len) {
+            return Err(RecordError::TruncatedRecord);
+        }
+        if Op::from_u8(buffer[24]).is_none() {
+            return Err(RecordError::InvalidOp);
+        }
+        buffer.resize(size as usize, 0);
+        self.file
+            .read_exact(&mut buffer[header_size..])
+            .map_err(|_| RecordError::TruncatedRecord)?;
+        Entry::try_from(buffer).map_err(|_| RecordError::TruncatedRecord)
+    }
 
-            // key
-            let mut key = vec![0; key_size as usize];
-            self.0.read_exact(&mut key).unwrap();
-
-            // value
-            let mut value = vec![0; value_size as usize];
-            self.0.read_exact(&mut value).unwrap();
-
-            let entry = Entry {
-                crc,
-                timestamp,
-                key_size,
-                value_size,
-                op,
-                key,
-                value,
-            };
+    // from 之后是否全为 0
+    fn zero_tail(&mut self, from: u64) -> bool {
+        self.next_nonzero(from).is_none()
+    }
 
-            let result = EntryParseResult {
-                value_pos: self.1 as u64,
-                entry,
-            };
-            self.1 += header_size + key_size as usize + value_size as usize;
+    // from 之后第一个不为 0 的字节位置，读取失败时按不为 0 处理
+    fn next_nonzero(&mut self, from: u64) -> Option<u64> {
+        if self.seek(from).is_err() {
+            return Some(from);
+        }
+        let mut buffer = vec![0; RESYNC_WINDOW];
+        let mut pos = from;
+        loop {
+            match self.file.read(&mut buffer) {
+                Ok(0) => return None,
+                Ok(n) => match buffer[..n].iter().position(|&b| b != 0) {
+                    Some(i) => return Some(pos + i as u64),
+                    None => pos += n as u64,
+                },
+                Err(_) => return Some(pos),
+            }
+        }
+    }
 
-            Some(result)
-        } else {
-            None
+    // 从 offset 开始读取最多 n 字节追加到 buffer
+    fn read_into(&mut self, offset: u64, n: usize, buffer: &mut Vec<u8>) -> Option<()> {
+        self.seek(offset).ok()?;
+        (&mut self.file).take(n as u64).read_to_end(buffer).ok()?;
+        Some(())
+    }
+
+    // 分块计算 crc，校验超出查找窗口的记录
+    fn check_record_at(&mut self, offset: u64, size: u64) -> bool {
+        let mut crc = [0; 4];
+        if self.seek(offset).is_err()
+            || self.file.read_exact(&mut crc).is_err()
+        {
+            return false;
         }
+        let mut hasher = Hasher::new();
+        let mut buffer = vec![0; RESYNC_WINDOW];
+        let mut rest = size - 4;
+        while rest > 0 {
+            let n = rest.min(buffer.len() as u64) as usize;
+            if self.file.read_exact(&mut buffer[..n]).is_err() {
+                return false;
+            }
+            hasher.update(&buffer[..n]);
+            rest -= n as u64;
+        }
+        hasher.finalize() == u32::from_le_bytes(crc)
     }
-}
 
-impl From<EntryFile> for Vec<EntryParseResult> {
-    fn from(mut val: EntryFile) -> Self {
-        // crc (4 bytes) | timestamp (8 bytes) | key_size (4 bytes) | value_size (8 bytes) | op (1 bytes) | key | value
+    // 从 from 开始逐字节查找下一条完整的记录，每次只读入一个窗口
+    fn resync(&mut self, from: u64) -> Resync {
         let header_size = Entry::default().header_size();
-        let mut buffer = vec![0; header_size];
-
-        let _ = val.0.seek(SeekFrom::Start(0));
+        let mut window = Vec::with_capacity(RESYNC_WINDOW);
+        let mut base = from;
+        // 已知的下一个不为 0 的字节位置
+        let mut nonzero = None;
+        while base < self.len {
+            let loaded = base + window.len() as u64;
+            if self.read_into(loaded, RESYNC_WINDOW - window.len(), &mut window).is_none() {
+                return Resync::End;
+            }
+            let window_end = base + window.len() as u64;
+            // 文件比打开时短时读不满窗口，同样按文件末尾处理
+            let at_eof = window_end >= self.len || window.len() < RESYNC_WINDOW;
+
+            // 窗口末尾连续的 0，之后全为 0 时损坏区间到此为止
+            let zeros = window.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            let mut zero_tail = false;
+            if zeros < window.len() {
+                if at_eof {
+                    zero_tail = true;
+                } else {
+                    if nonzero.is_none_or(|n| n < window_end) {
+                        nonzero = self.next_nonzero(window_end);
+                    }
+                    zero_tail = nonzero.is_none();
+                }
+            }
 
-        let mut offset: usize = 0;
-        let mut results = Vec::new();
-        loop {
-            //  crc = 4 |  time = 8 |  ksize = 4 | vsize = 8 | op = 1
-            match val.0.read_exact(&mut buffer) {
-                Ok(()) => {
-                    // 读取成功，处理 buffer 的内容
-                    //debug!("Successfully read {} bytes", buffer.len());
-                    // 处理 buffer 内容的其他操作
+            // 窗口中记录头完整的位置，最后不足一个记录头的部分留到下一个窗口
+            let candidates = if at_eof {
+                window.len()
+            } else {
+                window.len() + 1 - header_size
+            };
+            for i in 0..candidates {
+                if zero_tail && i >= zeros {
+                    return Resync::ZeroTail(base + i as u64);
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    // 文件结束EOF，处理不完整数据
-                    // debug!("Reached end of file");
-                    break; // 退出循环
+                let offset = base + i as u64;
+                let bytes = &window[i..];
+                if bytes.len() < header_size || Op::from_u8(bytes[24]).is_none() {
+                    continue;
                 }
-                Err(e) => {
-                    // 处理其他 I/O 错误
-                    debug!("Error reading file: {}", e);
-                    break; // 退出循环
+                let size = match record_size(bytes) {
+                    Some(size) if offset.checked_add(size).is_some_and(|e| e <= self.len) => size,
+                    _ => continue,
+                };
+                let found = if size <= bytes.len() as u64 {
+                    check_record(&bytes[..size as usize]).is_ok()
+                } else {
+                    self.check_record_at(offset, size)
+                };
+                if found {
+                    return Resync::Record(offset);
                 }
             }
+            if at_eof {
+                break;
+            }
+            window.drain(..candidates);
+            base += candidates as u64;
+            // 完整的记录头中总有不为 0 的字节，中间大段的 0 直接跳过
+            if let Some(n) = nonzero.filter(|&n| n > window_end) {
+                let skip_to = n + 1 - header_size as u64;
+                if skip_to > base && window.iter().all(|&b| b == 0) {
+                    window.clear();
+                    base = skip_to;
+                }
+            }
+        }
+        Resync::End
+    }
 
-            let crc = u32::from_le_bytes(
-                buffer[..4]
-                    .try_into()
-                    .map_err(|_| "Invalid CRC data")
-                    .unwrap(),
-            );
-            let timestamp = u64::from_le_bytes(
-                buffer[4..12]
-                    .try_into()
-                    .map_err(|_| "Invalid timestamp data")
-                    .unwrap(),
-            );
-            let key_size = u32::from_le_bytes(
-                buffer[12..16]
-                    .try_into()
-                    .map_err(|_| "Invalid key_size data")
-                    .unwrap(),
-            );
-            let value_size = u64::from_le_bytes(
-                buffer[16..24]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            let op = Op::from_le_bytes(
-                buffer[24..25]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            )
-            .unwrap();
+    // 损坏记录的 key 长度可信时读取 key
+    fn damaged_key(&mut self, offset: u64, end: u64) -> Option<Vec<u8>> {
+        let header_size = Entry::default().header_size() as u64;
+        let mut buffer = vec![0; header_size as usize];
+        self.seek(offset).ok()?;
+        self.file.read_exact(&mut buffer).ok()?;
 
-            // key
-            let mut key = vec![0; key_size as usize];
-            val.0.read_exact(&mut key).unwrap();
-
-            // value
-            let mut value = vec![0; value_size as usize];
-            val.0.read_exact(&mut value).unwrap();
-
-            let entry = Entry {
-                crc,
-                timestamp,
-                key_size,
-                value_size,
-                op,
-                key,
-                value,
-            };
+        let key_size = u32::from_le_bytes(buffer[12..16].try_into().ok()?) as u64;
+        if key_size == 0 || offset + header_size + key_size > end {
+            return None;
+        }
+        let mut key = vec![0; key_size as usize];
+        self.file.read_exact(&mut key).ok()?;
+        Some(key)
+    }
+}
+
+// 根据记录头计算整条记录的大小
+fn record_size(header: &[u8]) -> Option<u64> {
+    let header_size = Entry::default().header_size();
+    if header.len() < header_size {
+        return None;
+    }
+    let key_size = u32::from_le_bytes(header[12..16].try_into().ok()?) as u64;
+    let value_size = u64::from_le_bytes(header[16..24].try_into().ok()?);
+    (header_size as u64 + key_size).checked_add(value_size)
+}
+
+// 记录校验失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError {
+    TruncatedHeader,
+    TruncatedRecord,
+    InvalidOp,
+    CrcMismatch,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            RecordError::TruncatedHeader => "truncated record header",
+            RecordError::TruncatedRecord => "record size exceeds file",
+            RecordError::InvalidOp => "invalid op byte",
+            RecordError::CrcMismatch => "crc mismatch",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+// 校验 bytes 开头的一条记录，成功时返回记录大小，不复制数据
+fn check_record(bytes: &[u8]) -> Result<u64, RecordError> {
+    if bytes.len() < Entry::default().header_size() {
+        return Err(RecordError::TruncatedHeader);
+    }
+    let size = record_size(bytes).ok_or(RecordError::TruncatedRecord)?;
+    if size > bytes.len() as u64 {
+        return Err(RecordError::TruncatedRecord);
+    }
+    if Op::from_u8(bytes[24]).is_none() {
+        return Err(RecordError::InvalidOp);
+    }
+    let crc = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    if crc32fast::hash(&bytes[4..size as usize]) != crc {
+        return Err(RecordError::CrcMismatch);
+    }
+    Ok(size)
+}
 
-            results.push(EntryParseResult {
-                value_pos: offset as u64,
-                entry,
-            });
-            offset += header_size + key_size as usize + value_size as usize;
+// Iterator
+impl Iterator for EntryFile {
+    type Item = EntryParseResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset >= self.len {
+            return None;
         }
 
-        results
+        let reason = match self.read_at(offset) {
+            Ok(entry) if entry.is_valid() || self.keep_crc_mismatch => {
+                self.offset += entry.size() as u64;
+                return Some(EntryParseResult {
+                    value_pos: offset,
+                    crc_ok: entry.is_valid(),
+                    entry,
+                });
+            }
+            Ok(_) => RecordError::CrcMismatch,
+            Err(reason) => reason,
+        };
+
+        // 剩余部分全为 0 时视为文件结束
+        if self.zero_tail(offset) {
+            debug!("zero padding from {}", offset);
+            self.offset = self.len;
+            return None;
+        }
+
+        // 当前记录损坏，跳到下一条完整的记录继续解析，之后的预分配区域不计入损坏区间
+        let (next, end) = match self.resync(offset + 1) {
+            Resync::Record(pos) => (Some(pos), pos),
+            Resync::ZeroTail(pos) => (None, pos),
+            Resync::End => (None, self.len),
+        };
+        let key = self.damaged_key(offset, end);
+        debug!("damaged range [{}, {}), resync to {:?}", offset, end, next);
+        self.damaged.push(DamagedRange {
+            offset,
+            len: end - offset,
+            tail: next.is_none(),
+            key,
+            reason,
+        });
+        self.offset = next.unwrap_or(self.len);
+        self.next()
+    }
+}
+
+impl From<EntryFile> for Vec<EntryParseResult> {
+    fn from(val: EntryFile) -> Self {
+        val.collect()
     }
 }
 
@@ -340,6 +513,14 @@ impl Entry {
         matches!(self.op, Op::Del)
     }
 
+    pub fn op_name(&self) -> &'static str {
+        match self.op {
+            Op::Add => "add",
+            Op::Put => "put",
+            Op::Del => "del",
+        }
+    }
+
     // crc (4 bytes) | timestamp (8 bytes) | key_size (4 bytes) | value_size (8 bytes) | op (1 bytes) | key | value
     pub fn header_size(&self) -> usize {
         let header_size: usize = 2 * std::mem::size_of::<u32>()
@@ -448,8 +629,9 @@ impl From<Entry> for Vec<u8> {
 
 #[cfg(test)]
 mod tests {
-    use super::Op;
-    use crate::entry::entry::Entry;
+    use super::{Op, RESYNC_WINDOW};
+    use crate::entry::entry::{Entry, EntryFile, EntryParseResult};
+    use std::io::{Seek, Write};
 
     #[test]
     fn entry_new() {
@@ -473,8 +655,8 @@ mod tests {
         let entry = Entry::new(key1.to_vec(), value1.to_vec(), 0);
         let e_key = &entry.key;
         let e_value = &entry.value;
-        let e_key_size = entry.key_size as u32;
-        let e_value_size = entry.value_size as u64;
+        let e_key_size = entry.key_size;
+        let e_value_size = entry.value_size;
         let e_timestamp = entry.timestamp;
         let op = Op::Add;
         assert_eq!(key1.to_vec(), *e_key);
@@ -500,4 +682,72 @@ mod tests {
         assert_eq!(op, ins.op);
         assert!(ins.is_valid());
     }
+
+    #[test]
+    fn entry_file_resync_after_damaged_record() {
+        let mut file = tempfile::tempfile().unwrap();
+        let first = Entry::new(b"a".to_vec(), b"1".to_vec(), 0);
+        let second = Entry::new(b"b".to_vec(), b"2".to_vec(), 0);
+        let third = Entry::new(b"c".to_vec(), b"3".to_vec(), 0);
+        let second_pos = first.size() as u64;
+        let mut damaged = second.as_bytes();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        file.write_all(&first.as_bytes()).unwrap();
+        file.write_all(&damaged).unwrap();
+        file.write_all(&third.as_bytes()).unwrap();
+        // 末尾写入中断的记录
+        file.write_all(&Entry::new(b"d".to_vec(), b"4".to_vec(), 0).as_bytes()[..10])
+            .unwrap();
+        file.rewind().unwrap();
+
+        let mut entries = EntryFile::new(file);
+        let result: Vec<EntryParseResult> = entries.iter().collect();
+        let keys: Vec<_> = result.iter().map(|r| r.entry.key.clone()).collect();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], keys);
+
+        let ranges = entries.damaged();
+        assert_eq!(2, ranges.len());
+        assert_eq!(second_pos, ranges[0].offset);
+        assert_eq!(second.size() as u64, ranges[0].len);
+        assert_eq!(Some(b"b".to_vec()), ranges[0].key);
+        assert!(!ranges[0].tail);
+        assert!(ranges[1].tail);
+    }
+
+    #[test]
+    fn entry_file_resync_stops_at_zero_padding() {
+        let mut file = tempfile::tempfile().unwrap();
+        let first = Entry::new(b"a".to_vec(), b"1".to_vec(), 0);
+        let big = Entry::new(b"big".to_vec(), vec![7; 3 * RESYNC_WINDOW], 0);
+        let mut damaged = Entry::new(b"b".to_vec(), vec![1; 100], 0).as_bytes();
+        damaged[30] ^= 0xff;
+        file.write_all(&first.as_bytes()).unwrap();
+        file.write_all(&damaged).unwrap();
+        // 超过一个窗口的无法解析的数据
+        file.write_all(&vec![0xab; RESYNC_WINDOW + 100]).unwrap();
+        file.write_all(&vec![0; 2 * RESYNC_WINDOW + 7]).unwrap();
+        let big_pos = file.stream_position().unwrap();
+        file.write_all(&big.as_bytes()).unwrap();
+        let tail_pos = file.stream_position().unwrap();
+        file.write_all(&damaged).unwrap();
+        // 预分配的区域
+        file.write_all(&vec![0; 2 * RESYNC_WINDOW]).unwrap();
+        file.rewind().unwrap();
+
+        let mut entries = EntryFile::new(file);
+        let result: Vec<EntryParseResult> = entries.iter().collect();
+        let keys: Vec<_> = result.iter().map(|r| r.entry.key.clone()).collect();
+        assert_eq!(vec![b"a".to_vec(), b"big".to_vec()], keys);
+        assert_eq!(big_pos, result[1].value_pos);
+
+        let ranges = entries.damaged();
+        assert_eq!(2, ranges.len());
+        assert_eq!(first.size() as u64, ranges[0].offset);
+        assert_eq!(big_pos, ranges[0].offset + ranges[0].len);
+        assert!(!ranges[0].tail);
+        assert_eq!(tail_pos, ranges[1].offset);
+        assert_eq!(damaged.len() as u64, ranges[1].len);
+        assert!(ranges[1].tail);
+    }
 }
@@ -1,6 +1,6 @@
 use std::fmt;
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::{self, BufReader, Read};
 
 #[derive(Default)]
 pub struct Hint {
@@ -14,7 +14,7 @@ pub struct Hint {
 // Hint => bytes
 impl From<Hint> for Vec<u8> {
     fn from(val: Hint) -> Self {
-        let mut result: Vec<u8> = Vec::with_capacity(28 + val.key_size as usize);
+        let mut result: Vec<u8> = Vec::with_capacity(HINT_HEADER_SIZE + val.key_size as usize);
 
         // 将各个字段的字节添加到结果中
         result.extend_from_slice(&val.timestamp.to_le_bytes());
@@ -28,16 +28,61 @@ impl From<Hint> for Vec<u8> {
 }
 
 // | timestamp (8 bytes) | key_size (4 bytes) | value_size (8 bytes) | value_pos (8 bytes)  | key |
-pub struct HintFile(File);
+pub const HINT_HEADER_SIZE: usize = 28;
+
+impl Hint {
+    // 在 hint 文件中占用的字节数
+    pub fn size(&self) -> u64 {
+        (HINT_HEADER_SIZE + self.key.len()) as u64
+    }
+}
+
+pub struct HintFile {
+    file: BufReader<File>,
+    offset: u64,
+    len: u64,
+    truncated: Option<u64>,
+}
 
 impl HintFile {
     pub fn new(file: File) -> HintFile {
-        HintFile(file)
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        HintFile {
+            file: BufReader::new(file),
+            offset: 0,
+            len,
+            truncated: None,
+        }
     }
-    
+
     pub fn iter(&mut self) -> &mut Self {
         self
     }
+
+    // 末尾不完整的一项的位置，多为写入中断
+    pub fn truncated(&self) -> Option<u64> {
+        self.truncated
+    }
+
+    // 读满 buffer，文件结束时返回 false，只读到一部分时记录为不完整
+    fn read_full(&mut self, buffer: &mut [u8], started: bool) -> bool {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.file.read(&mut buffer[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        if read < buffer.len() {
+            if started || read > 0 {
+                self.truncated = Some(self.offset);
+            }
+            return false;
+        }
+        true
+    }
 }
 
 fn format_bytes_as_str(bytes: &[u8]) -> String {
@@ -63,121 +108,44 @@ impl fmt::Debug for Hint {
 // Iterator
 impl Iterator for HintFile {
     type Item = Hint;
-    
+
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = vec![0; 28];
-        if self.0.read_exact(&mut buffer).is_ok() {
-            let timestamp = u64::from_le_bytes(
-                buffer[0..8]
-                    .try_into()
-                    .map_err(|_| "Invalid timestamp data")
-                    .unwrap(),
-            );
-            let key_size = u32::from_le_bytes(
-                buffer[8..12]
-                    .try_into()
-                    .map_err(|_| "Invalid key_size data")
-                    .unwrap(),
-            );
-            let value_size = u64::from_le_bytes(
-                buffer[12..20]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            // value_pos
-            let value_pos = u64::from_le_bytes(
-                buffer[20..28]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            // key 再次读取指定长度的字节
-            let mut key = vec![0; key_size as usize];
-            self.0.read_exact(&mut key).unwrap();
-
-            let hint = Hint {
-                timestamp,
-                key_size,
-                value_size,
-                value_pos,
-                key,
-            };
-            
-            Some(hint)
-        } else {
-            None
+        if self.truncated.is_some() {
+            return None;
         }
+        let mut buffer = [0; HINT_HEADER_SIZE];
+        if !self.read_full(&mut buffer, false) {
+            return None;
+        }
+        let field = |start: usize| u64::from_le_bytes(buffer[start..start + 8].try_into().unwrap());
+        let key_size = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        // key 长度超出文件时不分配内存
+        if self.offset + (HINT_HEADER_SIZE as u64) + key_size as u64 > self.len {
+            self.truncated = Some(self.offset);
+            return None;
+        }
+
+        // key 再次读取指定长度的字节
+        let mut key = vec![0; key_size as usize];
+        if !self.read_full(&mut key, true) {
+            return None;
+        }
+
+        let hint = Hint {
+            timestamp: field(0),
+            key_size,
+            value_size: field(12),
+            value_pos: field(20),
+            key,
+        };
+        self.offset += hint.size();
+        Some(hint)
     }
 }
 
 impl From<HintFile> for Vec<Hint> {
-    fn from(mut val: HintFile) -> Self {
-        let mut buffer = vec![0; 28];
-        let mut hits_result = Vec::new();
-        loop {
-            //  crc = 4 |  time = 8 |  ksize = 4 | vsize = 8
-            match val.0.read_exact(&mut buffer) {
-                Ok(()) => {
-                    // 读取成功，处理 buffer 的内容
-                    // println!("Successfully read {} bytes", buffer.len());
-                    // 处理 buffer 内容的其他操作
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    // 文件结束EOF，处理不完整数据
-                    println!("Reached end of file");
-                    break; // 退出循环
-                }
-                Err(e) => {
-                    // 处理其他 I/O 错误
-                    eprintln!("Error reading file: {}", e);
-                    break; // 退出循环
-                }
-            }
-
-            let timestamp = u64::from_le_bytes(
-                buffer[0..8]
-                    .try_into()
-                    .map_err(|_| "Invalid timestamp data")
-                    .unwrap(),
-            );
-            let key_size = u32::from_le_bytes(
-                buffer[8..12]
-                    .try_into()
-                    .map_err(|_| "Invalid key_size data")
-                    .unwrap(),
-            );
-            let value_size = u64::from_le_bytes(
-                buffer[12..20]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            // value_pos
-            let value_pos = u64::from_le_bytes(
-                buffer[20..28]
-                    .try_into()
-                    .map_err(|_| "Invalid value_size data")
-                    .unwrap(),
-            );
-
-            // key 再次读取指定长度的字节
-            let mut key = vec![0; key_size as usize];
-            val.0.read_exact(&mut key).unwrap();
-
-            hits_result.push(Hint {
-                timestamp,
-                key_size,
-                value_size,
-                value_pos,
-                key,
-            })
-        }
-
-        hits_result
+    fn from(val: HintFile) -> Self {
+        val.collect()
     }
 }
 
@@ -1,15 +1,46 @@
 use std::fmt;
+use std::io;
 
 #[derive(Debug)]
 pub enum OpError {
     KeyNotFound,
     ReadSizeNotMatch,
     ValueInvalid,
-    LockFailed
+    LockFailed,
+    Io(io::Error),       // 读写文件失败
+    Corruption(String),  // 数据文件内容损坏
+    ReadOnly,            // 存储处于只读状态
+}
+
+impl OpError {
+    // key 不存在或已删除、过期
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, OpError::KeyNotFound | OpError::ValueInvalid)
+    }
 }
 
 impl fmt::Display for OpError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            OpError::Io(e) => write!(f, "I/O error: {}", e),
+            OpError::Corruption(msg) => write!(f, "data corruption: {}", msg),
+            OpError::ReadOnly => write!(f, "store is read-only"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::error::Error for OpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OpError {
+    fn from(e: io::Error) -> Self {
+        OpError::Io(e)
     }
 }
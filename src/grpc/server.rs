@@ -1,5 +1,6 @@
 use crate::db_store;
 use crate::util;
+use crate::OpError;
 use grpc_minkv::store_server::Store;
 use grpc_minkv::{
     AppendRequest, AppendResponse, DecrRequest, DecrResponse, DelRequest, DelResponse,
@@ -27,13 +28,54 @@ pub struct StoreImpl {
     store: Arc<RwLock<dyn db_store::Op>>,
 }
 
+// 存储错误转换为 gRPC 状态码
+impl From<OpError> for Status {
+    fn from(e: OpError) -> Self {
+        let code = match &e {
+            OpError::KeyNotFound | OpError::ValueInvalid => tonic::Code::NotFound,
+            OpError::ReadOnly => tonic::Code::FailedPrecondition,
+            OpError::Corruption(_) => tonic::Code::DataLoss,
+            OpError::Io(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+                ) =>
+            {
+                tonic::Code::ResourceExhausted
+            }
+            _ => tonic::Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+// tonic::Status 较大，与 tonic 生成的接口一致直接返回
+#[allow(clippy::result_large_err)]
 impl StoreImpl {
     pub fn new(store: Arc<RwLock<dyn db_store::Op>>) -> StoreImpl {
         StoreImpl { store }
     }
+
+    // 读改写的请求在写入完成前持有写锁，放到阻塞线程执行，不占用 tokio worker
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(&dyn db_store::Op) -> Result<T, Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || {
+            // 读取前等待已提交的写入完成，避免基于旧值写入
+            let store = store.write().unwrap();
+            store.flush_writes()?;
+            f(&*store)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+    }
 }
 
 #[tonic::async_trait]
+#[allow(clippy::result_large_err)]
 impl Store for StoreImpl {
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         debug!("gRPC Got a request: {:?}", request);
@@ -48,10 +90,11 @@ impl Store for StoreImpl {
                 };
                 Ok(Response::new(resp))
             }
-            Err(e) => {
+            Err(e) if e.is_not_found() => {
                 debug!("the Key NotFound {:?}", e);
                 Err(Status::new(tonic::Code::NotFound, "Key NotFound"))
             }
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -62,8 +105,9 @@ impl Store for StoreImpl {
         let key = req.key.as_bytes().to_vec();
         let value = req.value.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        store.set(&key, &value, 0);
+        // 只在提交时持有读锁，异步等待写入完成
+        let write = self.store.read().unwrap().submit_set(&key, &value, 0)?;
+        write.wait().await?;
 
         Ok(Response::new(SetResponse {}))
     }
@@ -83,19 +127,27 @@ impl Store for StoreImpl {
             let store = self.store.read().unwrap();
             for key in req.keys {
                 let key = key.as_bytes().to_vec();
-                if store.get(&key).is_ok() {
-                    count += 1;
-                    keys.push(key);
+                match store.get(&key) {
+                    Ok(_) => {
+                        count += 1;
+                        keys.push(key);
+                    }
+                    Err(e) if e.is_not_found() => {}
+                    Err(e) => return Err(e.into()),
                 }
             }
         }
 
-        if !keys.is_empty() {
-            let mut store = self.store.write().unwrap();
+        let mut writes = Vec::with_capacity(keys.len());
+        {
+            let store = self.store.read().unwrap();
             for k in keys {
-                store.delete(&k);
+                writes.push(store.submit_delete(&k)?);
             }
         }
+        for write in writes {
+            write.wait().await?;
+        }
 
         Ok(Response::new(DelResponse { num: count }))
     }
@@ -115,8 +167,10 @@ impl Store for StoreImpl {
         let store = self.store.read().unwrap();
         for key in req.keys {
             let key = key.as_bytes().to_vec();
-            if store.get(&key).is_ok() {
-                count += 1;
+            match store.get(&key) {
+                Ok(_) => count += 1,
+                Err(e) if e.is_not_found() => {}
+                Err(e) => return Err(e.into()),
             }
         }
 
@@ -133,19 +187,22 @@ impl Store for StoreImpl {
         let key = req.key.as_bytes().to_vec();
         let value = req.value.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        let resp = match store.get(&key) {
-            Ok(val) => Ok(Response::new(GetSetResponse {
-                result: String::from_utf8(val).unwrap(),
-            })),
-            Err(e) => {
-                debug!("getset {:?}", e);
-                Err(Status::new(tonic::Code::NotFound, "NotFound"))
-            }
-        };
-        store.set(&key, &value, 0);
+        self.blocking(move |store| {
+            let resp = match store.get(&key) {
+                Ok(val) => Ok(Response::new(GetSetResponse {
+                    result: String::from_utf8(val).unwrap(),
+                })),
+                Err(e) if e.is_not_found() => {
+                    debug!("getset {:?}", e);
+                    Err(Status::new(tonic::Code::NotFound, "NotFound"))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            store.set(&key, &value, 0)?;
 
-        resp
+            resp
+        })
+        .await
     }
 
     async fn m_set(&self, request: Request<MSetRequest>) -> Result<Response<MSetResponse>, Status> {
@@ -158,13 +215,20 @@ impl Store for StoreImpl {
             ));
         }
 
-        let mut store = self.store.write().unwrap();
-        for item in req.items {
-            let key = item.key.as_bytes().to_vec();
-            let value = item.value.as_bytes().to_vec();
+        // 全部提交后再一起等待，写锁保证这一组写入连续排队
+        let mut writes = Vec::with_capacity(req.items.len());
+        {
+            let store = self.store.write().unwrap();
+            for item in req.items {
+                let key = item.key.as_bytes().to_vec();
+                let value = item.value.as_bytes().to_vec();
 
-            // set
-            store.set(&key, &value, 0);
+                // set
+                writes.push(store.submit_set(&key, &value, 0)?);
+            }
+        }
+        for write in writes {
+            write.wait().await?;
         }
 
         Ok(Response::new(MSetResponse {}))
@@ -183,11 +247,13 @@ impl Store for StoreImpl {
             let store = self.store.read().unwrap();
             for key in req.keys {
                 let k = key.as_bytes().to_vec();
-                if let Ok(value) = store.get(&k) {
-                    items.push(Item {
+                match store.get(&k) {
+                    Ok(value) => items.push(Item {
                         key,
                         value: String::from_utf8(value).unwrap(),
-                    });
+                    }),
+                    Err(e) if e.is_not_found() => {}
+                    Err(e) => return Err(e.into()),
                 }
             }
         }
@@ -205,24 +271,27 @@ impl Store for StoreImpl {
         let key = req.key.as_bytes().to_vec();
         let value = req.value.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        let resp = match store.get(&key) {
-            Ok(mut val) => {
-                val.extend(value);
-                store.set(&key, &val, 0);
-                AppendResponse {
-                    len: val.len() as i32,
+        self.blocking(move |store| {
+            let resp = match store.get(&key) {
+                Ok(mut val) => {
+                    val.extend(value);
+                    store.set(&key, &val, 0)?;
+                    AppendResponse {
+                        len: val.len() as i32,
+                    }
                 }
-            }
-            Err(_) => {
-                store.set(&key, &value, 0);
-                AppendResponse {
-                    len: value.len() as i32,
+                Err(e) if e.is_not_found() => {
+                    store.set(&key, &value, 0)?;
+                    AppendResponse {
+                        len: value.len() as i32,
+                    }
                 }
-            }
-        };
+                Err(e) => return Err(e.into()),
+            };
 
-        Ok(Response::new(resp))
+            Ok(Response::new(resp))
+        })
+        .await
     }
 
     async fn incr(&self, request: Request<IncrRequest>) -> Result<Response<IncrResponse>, Status> {
@@ -231,26 +300,29 @@ impl Store for StoreImpl {
         let req = request.into_inner();
         let key = req.key.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
-            Ok(val) => {
-                // convert to a number
-                let s = String::from_utf8_lossy(&val);
-                match s.parse::<i64>() {
-                    Ok(mut n) => {
-                        n += 1;
-                        store.set(&key, &n.to_string().into_bytes(), 0);
-                        Ok(Response::new(IncrResponse { num: n as i32 }))
+        self.blocking(move |store| {
+            match store.get(&key) {
+                Ok(val) => {
+                    // convert to a number
+                    let s = String::from_utf8_lossy(&val);
+                    match s.parse::<i64>() {
+                        Ok(mut n) => {
+                            n += 1;
+                            store.set(&key, &n.to_string().into_bytes(), 0)?;
+                            Ok(Response::new(IncrResponse { num: n as i32 }))
+                        }
+                        Err(_) => Err(Status::new(tonic::Code::Unavailable, "Unavailable")),
                     }
-                    Err(_) => Err(Status::new(tonic::Code::Unavailable, "Unavailable")),
                 }
+                Err(e) if e.is_not_found() => {
+                    let n: i32 = 1;
+                    store.set(&key, &n.to_string().into_bytes(), 0)?;
+                    Ok(Response::new(IncrResponse { num: n }))
+                }
+                Err(e) => Err(e.into()),
             }
-            Err(_) => {
-                let n: i32 = 1;
-                store.set(&key, &n.to_string().into_bytes(), 0);
-                Ok(Response::new(IncrResponse { num: n }))
-            }
-        }
+        })
+        .await
     }
 
     async fn decr(&self, request: Request<DecrRequest>) -> Result<Response<DecrResponse>, Status> {
@@ -259,26 +331,29 @@ impl Store for StoreImpl {
         let req = request.into_inner();
         let key = req.key.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
-            Ok(val) => {
-                // convert to a number
-                let s = String::from_utf8_lossy(&val);
-                match s.parse::<i64>() {
-                    Ok(mut n) => {
-                        n -= 1;
-                        store.set(&key, &n.to_string().into_bytes(), 0);
-                        Ok(Response::new(DecrResponse { num: n as i32 }))
+        self.blocking(move |store| {
+            match store.get(&key) {
+                Ok(val) => {
+                    // convert to a number
+                    let s = String::from_utf8_lossy(&val);
+                    match s.parse::<i64>() {
+                        Ok(mut n) => {
+                            n -= 1;
+                            store.set(&key, &n.to_string().into_bytes(), 0)?;
+                            Ok(Response::new(DecrResponse { num: n as i32 }))
+                        }
+                        Err(_) => Err(Status::new(tonic::Code::Unavailable, "Unavailable")),
                     }
-                    Err(_) => Err(Status::new(tonic::Code::Unavailable, "Unavailable")),
                 }
+                Err(e) if e.is_not_found() => {
+                    let n: i32 = -1;
+                    store.set(&key, &n.to_string().into_bytes(), 0)?;
+                    Ok(Response::new(DecrResponse { num: n }))
+                }
+                Err(e) => Err(e.into()),
             }
-            Err(_) => {
-                let n: i32 = -1;
-                store.set(&key, &n.to_string().into_bytes(), 0);
-                Ok(Response::new(DecrResponse { num: n }))
-            }
-        }
+        })
+        .await
     }
 
     async fn expire(
@@ -296,14 +371,17 @@ impl Store for StoreImpl {
         }
 
         let millisec = util::time::get_millisec_from_sec(value);
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
+        self.blocking(move |store| match store.get(&key) {
             Ok(val) => {
-                store.set(&key, &val, millisec);
+                store.set(&key, &val, millisec)?;
                 Ok(Response::new(ExpireResponse { result: 1 }))
             }
-            Err(_) => Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument")),
-        }
+            Err(e) if e.is_not_found() => {
+                Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .await
     }
 
     async fn expire_at(
@@ -321,14 +399,17 @@ impl Store for StoreImpl {
         }
 
         let millisec = util::time::sec_to_millisec(value);
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
+        self.blocking(move |store| match store.get(&key) {
             Ok(val) => {
-                store.set(&key, &val, millisec);
+                store.set(&key, &val, millisec)?;
                 Ok(Response::new(ExpireAtResponse { result: 1 }))
             }
-            Err(_) => Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument")),
-        }
+            Err(e) if e.is_not_found() => {
+                Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .await
     }
 
     async fn p_expire(
@@ -346,14 +427,17 @@ impl Store for StoreImpl {
         }
 
         let millisec = util::time::get_millisec(value);
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
+        self.blocking(move |store| match store.get(&key) {
             Ok(val) => {
-                store.set(&key, &val, millisec);
+                store.set(&key, &val, millisec)?;
                 Ok(Response::new(PExpireResponse { result: 1 }))
             }
-            Err(_) => Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument")),
-        }
+            Err(e) if e.is_not_found() => {
+                Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .await
     }
 
     async fn p_expire_at(
@@ -370,14 +454,17 @@ impl Store for StoreImpl {
             return Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument"));
         }
 
-        let mut store = self.store.write().unwrap();
-        match store.get(&key) {
+        self.blocking(move |store| match store.get(&key) {
             Ok(val) => {
-                store.set(&key, &val, value);
+                store.set(&key, &val, value)?;
                 Ok(Response::new(PExpireAtResponse { result: 1 }))
             }
-            Err(_) => Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument")),
-        }
+            Err(e) if e.is_not_found() => {
+                Err(Status::new(tonic::Code::InvalidArgument, "InvalidArgument"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .await
     }
 
     async fn ttl(&self, request: Request<TtlRequest>) -> Result<Response<TtlResponse>, Status> {
@@ -402,7 +489,8 @@ impl Store for StoreImpl {
 
                 // Ok(Response::new(TtlResponse{result: 1 }))
             }
-            Err(_) => Err(Status::new(tonic::Code::NotFound, "NotFound")),
+            Err(e) if e.is_not_found() => Err(Status::new(tonic::Code::NotFound, "NotFound")),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -426,7 +514,8 @@ impl Store for StoreImpl {
                     }))
                 }
             }
-            Err(_) => Err(Status::new(tonic::Code::NotFound, "NotFound")),
+            Err(e) if e.is_not_found() => Err(Status::new(tonic::Code::NotFound, "NotFound")),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -439,18 +528,21 @@ impl Store for StoreImpl {
         let req = request.into_inner();
         let key = req.key.as_bytes().to_vec();
 
-        let mut store = self.store.write().unwrap();
-        match store.get_entry(&key) {
-            Ok(entry) => {
-                if entry.timestamp == 0 {
-                    // 未设置过期时间
-                    Ok(Response::new(PersistResponse { result: -1 }))
-                } else {
-                    store.set(&key, &entry.value, 0);
-                    Ok(Response::new(PersistResponse { result: 1 }))
+        self.blocking(move |store| {
+            match store.get_entry(&key) {
+                Ok(entry) => {
+                    if entry.timestamp == 0 {
+                        // 未设置过期时间
+                        Ok(Response::new(PersistResponse { result: -1 }))
+                    } else {
+                        store.set(&key, &entry.value, 0)?;
+                        Ok(Response::new(PersistResponse { result: 1 }))
+                    }
                 }
+                Err(e) if e.is_not_found() => Err(Status::new(tonic::Code::NotFound, "NotFound")),
+                Err(e) => Err(e.into()),
             }
-            Err(_) => Err(Status::new(tonic::Code::NotFound, "NotFound")),
-        }
+        })
+        .await
     }
 }
@@ -13,7 +13,8 @@ async fn main() {
         Ok(_) => {}
         Err(e) => {
             error!("{:?}", e);
-            eprintln!("{:?}", e)
+            eprintln!("{:?}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -2,7 +2,10 @@ use super::config;
 use super::grpc_server::StoreImpl;
 use crate::db_store;
 use crate::grpc_server::grpc_minkv::store_server::StoreServer;
+use crate::store::metrics::Metrics;
 use crate::util;
+use crate::util::lock::OwnerLock;
+use crate::OpError;
 use log::*;
 use redis_protocol::resp2::{
     decode::decode,
@@ -16,15 +19,145 @@ use std::sync::mpsc;
 use std::sync::Arc;
 use std::{
     // io::{Read, Write},
-    sync::RwLock,
+    sync::{RwLock, RwLockWriteGuard},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::signal;
-use tokio::sync::Notify;
+use tokio::sync::watch;
 use tokio::task::JoinSet;
 
+const READONLY_ERROR: &str = "READONLY You can't write against a read only store";
+
+// 存储错误以 -ERR 返回给客户端，只读状态返回 -READONLY
+impl From<OpError> for String {
+    fn from(e: OpError) -> Self {
+        match e {
+            OpError::ReadOnly => READONLY_ERROR.to_string(),
+            e => e.to_string(),
+        }
+    }
+}
+
+// 解析 SET key value [EX|PX|EXAT|PXAT n]，返回 key、value 和过期时间
+fn parse_set(arr: &[OwnedFrame]) -> Result<(&[u8], &[u8], u64), String> {
+    if arr.len() < 3 || arr.len().is_multiple_of(2) {
+        return Err("(error) ERR syntax error".to_string());
+    }
+    let key = match &arr[1] {
+        OwnedFrame::BulkString(bulk) => bulk,
+        _ => return Err("Invalid SET command format".to_string()),
+    };
+    let value = match &arr[2] {
+        OwnedFrame::BulkString(bulk) => bulk,
+        _ => return Err("Invalid SET command format".to_string()),
+    };
+
+    // 支持 EX/PX/EXAT/PXAT 选项，AOF 中带过期时间的 SET 以 PXAT 记录
+    let mut timestamp = 0;
+    for pair in arr[3..].chunks(2) {
+        let (option, num_str) = match pair {
+            [OwnedFrame::BulkString(option), OwnedFrame::BulkString(num)] => {
+                (String::from_utf8_lossy(option).to_uppercase(), num)
+            }
+            _ => return Err("(error) ERR syntax error".to_string()),
+        };
+        let value = match String::from_utf8_lossy(num_str).parse::<u64>() {
+            Ok(value) if value > 0 && value <= u64::MAX / 1000 => value,
+            Ok(_) => return Err("(error) ERR invalid expire time in 'set' command".to_string()),
+            Err(_) => return Err("(error) ERR value is not an integer or out of range".to_string()),
+        };
+        timestamp = match option.as_str() {
+            "EX" => util::time::get_millisec_from_sec(value),
+            "PX" => util::time::get_millisec(value),
+            "EXAT" => util::time::sec_to_millisec(value),
+            "PXAT" => value,
+            _ => return Err("(error) ERR syntax error".to_string()),
+        };
+    }
+    Ok((key, value, timestamp))
+}
+
+fn parse_del(arr: &[OwnedFrame]) -> Result<&[u8], String> {
+    // 检查参数个数，SET 命令应该有三个参数: SET, key, value
+    if arr.len() != 2 {
+        return Err("Invalid SET command format: expected 2 arguments".to_string());
+    }
+    match &arr[1] {
+        OwnedFrame::BulkString(bulk) => Ok(bulk),
+        _ => Err("Invalid SET command format".to_string()),
+    }
+}
+
+// 请求的响应，已提交的写入在发送前等待完成
+enum Reply {
+    Ready(Result<OwnedFrame, String>),
+    Pending(db_store::PendingWrite, OwnedFrame),
+}
+
+// 按请求顺序等待并发送响应，一次写入 socket
+async fn write_replies(stream: &mut TcpStream, replies: &mut Vec<Reply>) {
+    if replies.is_empty() {
+        return;
+    }
+    let mut buf = Vec::new();
+    for reply in replies.drain(..) {
+        let result = match reply {
+            Reply::Ready(result) => result,
+            Reply::Pending(write, resp) => write.wait().await.map(|_| resp).map_err(String::from),
+        };
+        match result {
+            Ok(resp) => {
+                let start = buf.len();
+                buf.resize(start + resp.encode_len(), 0);
+                encode(&mut buf[start..], &resp).unwrap();
+            }
+            Err(e) => {
+                if e.starts_with("READONLY ") {
+                    buf.extend_from_slice(format!("-{}\r\n", e).as_bytes());
+                } else {
+                    buf.extend_from_slice(format!("-ERR {}\r\n", e).as_bytes());
+                }
+            }
+        }
+    }
+    stream.write_all(&buf).await.unwrap();
+}
+
+// 会写入的命令，可能在持有存储锁时等待写入线程，不能在 tokio worker 上执行
+const WRITE_COMMANDS: [&str; 16] = [
+    "SET",
+    "DEL",
+    "GETSET",
+    "MSET",
+    "APPEND",
+    "INCR",
+    "DECR",
+    "INCRBY",
+    "DECRBY",
+    "EXPIRE",
+    "EXPIREAT",
+    "PEXPIRE",
+    "PEXPIREAT",
+    "PERSIST",
+    "WRITABLE",
+    "INGEST",
+];
+
+fn writes_store(frame: &OwnedFrame) -> bool {
+    match frame {
+        OwnedFrame::Array(arr) => match arr.first() {
+            Some(OwnedFrame::BulkString(name)) => {
+                let name = String::from_utf8_lossy(name).to_uppercase();
+                WRITE_COMMANDS.contains(&name.as_str())
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 pub struct Server {
     config: Arc<config::Config>,
     store: Arc<RwLock<dyn db_store::Op>>,
@@ -35,15 +168,27 @@ impl Server {
         Server { config, store }
     }
 
-    async fn handle_client_connection(&self, mut stream: TcpStream) {
+    async fn handle_client_connection(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
         let mut buffer = [0u8; 4096];
+        let mut pending = Vec::new();
+        let mut replies = Vec::new();
 
         loop {
-            // 读取客户端发送的数据
-            let n = match stream.read(&mut buffer).await {
-                Ok(size) => size,
-                Err(e) => {
-                    error!("Failed to read from stream: {}", e);
+            // 读取客户端发送的数据，关闭服务时只在两个请求之间断开连接
+            let n = tokio::select! {
+                result = stream.read(&mut buffer) => match result {
+                    Ok(size) => size,
+                    Err(e) => {
+                        error!("Failed to read from stream: {}", e);
+                        return;
+                    }
+                },
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    debug!("connection closed by shutdown");
                     return;
                 }
             };
@@ -52,32 +197,75 @@ impl Server {
                 break; // 连接已关闭
             }
 
-            // 解析 RESP 帧
-            match decode(&buffer[..n]) {
-                Ok(Some((frame, _))) => match self.handle_frame(&frame) {
-                    Ok(resp) => {
-                        let mut buf = vec![0; resp.encode_len()];
-                        encode(&mut buf, &resp).unwrap();
-                        stream.write_all(&buf).await.unwrap();
-                    }
+            // 解析 RESP 帧，一次读取可能包含多个请求，也可能只有请求的一部分
+            // start 之前的数据已解析，处理完本次读取的请求后再一起移除
+            pending.extend_from_slice(&buffer[..n]);
+            let mut start = 0;
+            loop {
+                let (frame, consumed) = match decode(&pending[start..]) {
+                    Ok(Some(result)) => result,
+                    // 数据不完整，等待更多数据
+                    Ok(None) => break,
                     Err(e) => {
-                        let error_message = format!("-ERR {}\r\n", e);
-                        stream.write_all(error_message.as_bytes()).await.unwrap();
+                        error!("Error decoding frame: {}", e);
+                        write_replies(&mut stream, &mut replies).await;
+                        return;
                     }
-                },
-                Ok(None) => {
-                    // 数据不完整，需要更多数据
-                    error!("数据不完整");
+                };
+                start += consumed;
+
+                // SET 和 DEL 只提交给写入线程，本次读取的请求都提交后再一起等待
+                if let Some(reply) = self.submit_frame(&frame) {
+                    replies.push(reply);
                     continue;
                 }
-                Err(e) => {
-                    error!("Error decoding frame: {}", e);
-                    return;
-                }
+                // 其它命令可能读取刚提交的写入，先等待之前的写入完成
+                write_replies(&mut stream, &mut replies).await;
+                let result = if writes_store(&frame) {
+                    // 读改写的命令在写入完成前持有锁，放到阻塞线程执行
+                    let server = Arc::clone(&self);
+                    tokio::task::spawn_blocking(move || server.handle_frame(&frame))
+                        .await
+                        .unwrap_or_else(|e| Err(e.to_string()))
+                } else {
+                    self.handle_frame(&frame)
+                };
+                replies.push(Reply::Ready(result));
             }
+            pending.drain(..start);
+            write_replies(&mut stream, &mut replies).await;
         }
     }
 
+    // 可以只提交不等待的写入命令，其它命令返回 None
+    fn submit_frame(&self, frame: &OwnedFrame) -> Option<Reply> {
+        let OwnedFrame::Array(arr) = frame else {
+            return None;
+        };
+        let command = self.get_command_name(frame)?.to_uppercase();
+        let submitted = match command.as_str() {
+            "SET" => parse_set(arr).and_then(|(key, value, timestamp)| {
+                let store = self.store.read().unwrap();
+                Ok(store.submit_set(key, value, timestamp)?)
+            }),
+            "DEL" => {
+                parse_del(arr).and_then(|key| Ok(self.store.read().unwrap().submit_delete(key)?))
+            }
+            _ => return None,
+        };
+        Some(match submitted {
+            Ok(write) => Reply::Pending(write, OwnedFrame::SimpleString(b"OK".to_vec())),
+            Err(e) => Reply::Ready(Err(e)),
+        })
+    }
+
+    // 读改写的命令持有写锁，读取前等待已提交的 SET、DEL 写入完成，避免基于旧值写入覆盖已确认的写入
+    fn lock_for_update(&self) -> Result<RwLockWriteGuard<'_, dyn db_store::Op>, String> {
+        let store = self.store.write().unwrap();
+        store.flush_writes()?;
+        Ok(store)
+    }
+
     fn get_command_name(&self, frame: &OwnedFrame) -> Option<String> {
         if let OwnedFrame::Array(arr) = frame {
             if let Some(OwnedFrame::BulkString(bulk)) = arr.first() {
@@ -89,7 +277,7 @@ impl Server {
         None
     }
 
-    fn handle_frame(&self, frame: &OwnedFrame) -> Result<OwnedFrame, String> {
+    pub(crate) fn handle_frame(&self, frame: &OwnedFrame) -> Result<OwnedFrame, String> {
         let command = match self.get_command_name(frame) {
             Some(cmd) => cmd,
             _ => return Err("(error) ERR unknown command".to_string()),
@@ -100,20 +288,11 @@ impl Server {
         match command.to_uppercase().as_str() {
             "SET" => {
                 if let OwnedFrame::Array(arr) = frame {
-                    if arr.len() != 3 {
-                        return Err("(error) ERR syntax error".to_string());
-                    }
-                    let key = match &arr[1] {
-                        OwnedFrame::BulkString(bulk) => bulk,
-                        _ => return Err("Invalid SET command format".to_string()),
-                    };
-                    let value = match &arr[2] {
-                        OwnedFrame::BulkString(bulk) => bulk,
-                        _ => return Err("Invalid SET command format".to_string()),
-                    };
+                    let (key, value, timestamp) = parse_set(arr)?;
 
-                    let mut store = self.store.write().unwrap();
-                    store.set(key, value, 0);
+                    // 读锁即可，并发的写入由写入线程合并提交
+                    let store = self.store.read().unwrap();
+                    store.set(key, value, timestamp)?;
 
                     Ok(OwnedFrame::SimpleString(b"OK".to_vec()))
                 } else {
@@ -136,10 +315,11 @@ impl Server {
                     let store = self.store.read().unwrap();
                     match store.get(key) {
                         Ok(val) => Ok(OwnedFrame::BulkString(val)),
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid GET command format".to_string())
@@ -147,17 +327,9 @@ impl Server {
             }
             "DEL" => {
                 if let OwnedFrame::Array(arr) = frame {
-                    // 检查参数个数，SET 命令应该有三个参数: SET, key, value
-                    if arr.len() != 2 {
-                        return Err("Invalid SET command format: expected 2 arguments".to_string());
-                    }
-                    let key = match &arr[1] {
-                        OwnedFrame::BulkString(bulk) => bulk,
-                        _ => return Err("Invalid SET command format".to_string()),
-                    };
-
-                    let mut store = self.store.write().unwrap();
-                    store.delete(key);
+                    let key = parse_del(arr)?;
+                    let store = self.store.read().unwrap();
+                    store.delete(key)?;
                     // store.set(key.clone(), value.clone());
                     Ok(OwnedFrame::SimpleString(b"OK".to_vec()))
                 } else {
@@ -177,8 +349,10 @@ impl Server {
                     for key in &arr[1..] {
                         if let OwnedFrame::BulkString(bulk) = key {
                             // 处理 BulkString 变体
-                            if store.get(bulk).is_ok() {
-                                count += 1
+                            match store.get(bulk) {
+                                Ok(_) => count += 1,
+                                Err(e) if e.is_not_found() => {}
+                                Err(e) => return Err(e.to_string()),
                             }
                         }
                     }
@@ -203,12 +377,13 @@ impl Server {
                         _ => return Err("Invalid GETSET command format".to_string()),
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     let old_value = match store.get(key) {
                         Ok(val) => Ok(OwnedFrame::BulkString(val)),
-                        Err(_) => Ok(OwnedFrame::Null),
+                        Err(e) if e.is_not_found() => Ok(OwnedFrame::Null),
+                        Err(e) => return Err(e.to_string()),
                     };
-                    store.set(key, value, 0);
+                    store.set(key, value, 0)?;
 
                     old_value
                 } else {
@@ -223,7 +398,7 @@ impl Server {
                         );
                     }
                     // OwnedFrame::BulkString(val)
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     for i in 0..arr.len() / 2 {
                         let key = match &arr[i * 2 + 1] {
                             OwnedFrame::BulkString(bulk) => bulk,
@@ -236,7 +411,7 @@ impl Server {
                         };
 
                         // set
-                        store.set(key, value, 0);
+                        store.set(key, value, 0)?;
                     }
 
                     Ok(OwnedFrame::SimpleString(b"OK".to_vec()))
@@ -259,7 +434,8 @@ impl Server {
                         if let OwnedFrame::BulkString(bulk) = key {
                             match store.get(bulk) {
                                 Ok(value) => result.push(OwnedFrame::BulkString(value)),
-                                Err(_) => result.push(OwnedFrame::Null),
+                                Err(e) if e.is_not_found() => result.push(OwnedFrame::Null),
+                                Err(e) => return Err(e.to_string()),
                             };
                         }
                     }
@@ -283,18 +459,19 @@ impl Server {
                         _ => return Err("Invalid APPEND command format".to_string()),
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(mut val) => {
                             val.extend(value);
-                            store.set(key, &val, 0);
+                            store.set(key, &val, 0)?;
                             Ok(OwnedFrame::Integer(val.len() as i64))
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
-                            store.set(key, value, 0);
+                            store.set(key, value, 0)?;
                             Ok(OwnedFrame::Integer(value.len() as i64))
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid APPEND command format".to_string())
@@ -313,7 +490,7 @@ impl Server {
                         _ => return Err("Invalid INCR command format".to_string()),
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
                             // convert to a number
@@ -321,7 +498,7 @@ impl Server {
                             match s.parse::<i64>() {
                                 Ok(mut n) => {
                                     n += 1;
-                                    store.set(key, &n.to_string().into_bytes(), 0);
+                                    store.set(key, &n.to_string().into_bytes(), 0)?;
                                     Ok(OwnedFrame::Integer(n))
                                 }
                                 Err(_) => Ok(OwnedFrame::Error(
@@ -330,10 +507,11 @@ impl Server {
                                 )),
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid INCR command format".to_string())
@@ -352,7 +530,7 @@ impl Server {
                         _ => return Err("Invalid DECR command format".to_string()),
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
                             // convert to a number
@@ -360,7 +538,7 @@ impl Server {
                             match s.parse::<i64>() {
                                 Ok(mut n) => {
                                     n -= 1;
-                                    store.set(key, &n.to_string().into_bytes(), 0);
+                                    store.set(key, &n.to_string().into_bytes(), 0)?;
                                     Ok(OwnedFrame::Integer(n))
                                 }
                                 Err(_) => Ok(OwnedFrame::Error(
@@ -369,10 +547,11 @@ impl Server {
                                 )),
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid DECR command format".to_string())
@@ -405,7 +584,7 @@ impl Server {
                         }
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
                             // convert to a number
@@ -413,7 +592,7 @@ impl Server {
                             match s.parse::<i64>() {
                                 Ok(mut n) => {
                                     n += value;
-                                    store.set(key, &n.to_string().into_bytes(), 0);
+                                    store.set(key, &n.to_string().into_bytes(), 0)?;
                                     Ok(OwnedFrame::Integer(n))
                                 }
                                 Err(_) => Ok(OwnedFrame::Error(
@@ -422,10 +601,11 @@ impl Server {
                                 )),
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid INCRBY command format".to_string())
@@ -458,7 +638,7 @@ impl Server {
                         }
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
                             // convert to a number
@@ -466,7 +646,7 @@ impl Server {
                             match s.parse::<i64>() {
                                 Ok(mut n) => {
                                     n -= value;
-                                    store.set(key, &n.to_string().into_bytes(), 0);
+                                    store.set(key, &n.to_string().into_bytes(), 0)?;
                                     Ok(OwnedFrame::Integer(n))
                                 }
                                 Err(_) => Ok(OwnedFrame::Error(
@@ -475,10 +655,11 @@ impl Server {
                                 )),
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid DECRBY command format".to_string())
@@ -517,16 +698,17 @@ impl Server {
                     }
 
                     let millisec = util::time::get_millisec_from_sec(value);
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
-                            store.set(key, &val, millisec);
+                            store.set(key, &val, millisec)?;
                             Ok(OwnedFrame::Integer(1))
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid EXPIRE command format".to_string())
@@ -567,16 +749,17 @@ impl Server {
                     }
 
                     let millisec = util::time::sec_to_millisec(value);
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
-                            store.set(key, &val, millisec);
+                            store.set(key, &val, millisec)?;
                             Ok(OwnedFrame::Integer(1))
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid EXPIREAT command format".to_string())
@@ -617,16 +800,17 @@ impl Server {
                     }
 
                     let millisec = util::time::get_millisec(value);
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
-                            store.set(key, &val, millisec);
+                            store.set(key, &val, millisec)?;
                             Ok(OwnedFrame::Integer(1))
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid PEXPIRE command format".to_string())
@@ -666,16 +850,17 @@ impl Server {
                         ));
                     }
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get(key) {
                         Ok(val) => {
-                            store.set(key, &val, value);
+                            store.set(key, &val, value)?;
                             Ok(OwnedFrame::Integer(1))
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Null)
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid PEXPIREAT command format".to_string())
@@ -694,7 +879,7 @@ impl Server {
                         _ => return Err("Invalid TTL command format".to_string()),
                     };
 
-                    let store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get_entry(key) {
                         Ok(entry) => {
                             if entry.timestamp == 0 {
@@ -708,10 +893,11 @@ impl Server {
                                 ))
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Integer(-2))
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid TTL command format".to_string())
@@ -730,7 +916,7 @@ impl Server {
                         _ => return Err("Invalid PTTL command format".to_string()),
                     };
 
-                    let store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get_entry(key) {
                         Ok(entry) => {
                             if entry.timestamp == 0 {
@@ -745,10 +931,11 @@ impl Server {
                                     as i64))
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Integer(-2))
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid PTTL command format".to_string())
@@ -768,21 +955,22 @@ impl Server {
                         _ => return Err("Invalid PERSIST command format".to_string()),
                     };
 
-                    let mut store = self.store.write().unwrap();
+                    let store = self.lock_for_update()?;
                     match store.get_entry(key) {
                         Ok(entry) => {
                             if entry.timestamp == 0 {
                                 // 未设置过期时间
                                 Ok(OwnedFrame::Integer(0))
                             } else {
-                                store.set(key, &entry.value, 0);
+                                store.set(key, &entry.value, 0)?;
                                 Ok(OwnedFrame::Integer(1))
                             }
                         }
-                        Err(e) => {
+                        Err(e) if e.is_not_found() => {
                             debug!("get value occur error {:?}", e);
                             Ok(OwnedFrame::Integer(0))
                         }
+                        Err(e) => Err(e.to_string()),
                     }
                 } else {
                     Err("Invalid PERSIST command format".to_string())
@@ -839,6 +1027,61 @@ impl Server {
                     Err("Invalid PING command format".to_string())
                 }
             }
+            "INFO" => {
+                let store = self.store.read().unwrap();
+                let metrics = store.metrics();
+                let info = format!(
+                    "# Server\r\nminkv_version:{}\r\n\r\n\
+                     # Keyspace\r\nkeys:{}\r\nkeydir_memory:{}\r\n\r\n\
+                     # Persistence\r\nreadonly:{}\r\nreadonly_reason:{}\r\ndisk_bytes:{}\r\n\
+                     max_disk_bytes:{}\r\nquota_rejections:{}\r\n\r\n\
+                     # Stats\r\nwrites:{}\r\nwrite_errors:{}\r\nwrite_batches:{}\r\nreadonly_events:{}\r\n\r\n\
+                     # Scrub\r\nscrub_passes:{}\r\nscrub_last_pass:{}\r\nscrub_bytes:{}\r\n\
+                     scrub_corrupt_records:{}\r\nscrub_keydir_mismatches:{}\r\n\
+                     scrub_quarantined_keys:{}\r\n\r\n\
+                     # Compaction\r\nmerges:{}\r\nmerge_keys:{}\r\nmerge_keys_total:{}\r\n\
+                     unmerged_files:{}\r\ndead_bytes:{}\r\nwrite_slowdowns:{}\r\n\
+                     write_stalls:{}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    store.len(),
+                    store.memory_usage(),
+                    metrics.is_readonly() as u8,
+                    metrics.readonly_reason().unwrap_or_default(),
+                    Metrics::get(&metrics.disk_bytes),
+                    self.config.max_disk_bytes(),
+                    Metrics::get(&metrics.quota_rejections),
+                    Metrics::get(&metrics.writes),
+                    Metrics::get(&metrics.write_errors),
+                    Metrics::get(&metrics.write_batches),
+                    Metrics::get(&metrics.readonly_events),
+                    Metrics::get(&metrics.scrub_passes),
+                    Metrics::get(&metrics.scrub_last_pass),
+                    Metrics::get(&metrics.scrub_bytes),
+                    Metrics::get(&metrics.scrub_corrupt_records),
+                    Metrics::get(&metrics.scrub_keydir_mismatches),
+                    Metrics::get(&metrics.scrub_quarantined_keys),
+                    Metrics::get(&metrics.merges),
+                    Metrics::get(&metrics.merge_keys),
+                    Metrics::get(&metrics.merge_keys_total),
+                    Metrics::get(&metrics.unmerged_files),
+                    Metrics::get(&metrics.dead_bytes),
+                    Metrics::get(&metrics.write_slowdowns),
+                    Metrics::get(&metrics.write_stalls),
+                );
+                Ok(OwnedFrame::BulkString(info.into_bytes()))
+            }
+            // 释放磁盘空间后恢复写入
+            "WRITABLE" => {
+                let mut store = self.store.write().unwrap();
+                store.set_writable()?;
+                Ok(OwnedFrame::SimpleString(b"OK".to_vec()))
+            }
+            // 导入 minkv load --ingest 暂存的数据文件
+            "INGEST" => {
+                let mut store = self.store.write().unwrap();
+                let count = store.ingest()?;
+                Ok(OwnedFrame::Integer(count as i64))
+            }
             "CLIENT" => {
                 if let OwnedFrame::Array(arr) = frame {
                     if arr.len() < 2 {
@@ -856,11 +1099,17 @@ impl Server {
         }
     }
 
-    pub async fn server_start(self: Arc<Self>, notify: Arc<Notify>) -> anyhow::Result<()> {
+    pub async fn server_start(
+        self: Arc<Self>,
+        shutdown: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
         let addr = self.config.get_addr()?;
         let listener = TcpListener::bind(addr).await?;
         println!("Listening on {}", addr);
 
+        // 所有连接任务，关闭时等待其处理完当前请求
+        let mut connections = JoinSet::new();
+        let mut stop = shutdown.clone();
         loop {
             tokio::select! {
                 accept_result = listener.accept() => {
@@ -868,8 +1117,9 @@ impl Server {
                         Ok((stream, _)) => {
                             debug!("New connection: {}", stream.peer_addr().unwrap());
                             let server_clone = Arc::clone(&self);
-                            tokio::spawn(async move {
-                                server_clone.handle_client_connection(stream).await;
+                            let shutdown = shutdown.clone();
+                            connections.spawn(async move {
+                                server_clone.handle_client_connection(stream, shutdown).await;
                             });
                         },
                         Err(e) => {
@@ -877,19 +1127,25 @@ impl Server {
                         }
                     }
                 },
-                _ = notify.notified() => {
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = stop.wait_for(|stop| *stop) => {
                     println!("Shutdown signal received. Stopping server...");
                     break;
                 }
             }
         }
+
+        // 停止接收新连接
+        drop(listener);
+        info!("draining {} connections", connections.len());
+        while connections.join_next().await.is_some() {}
         Ok(())
     }
 }
 
 pub async fn start_server(option: &Option<PathBuf>) -> anyhow::Result<()> {
-    // 创建一个 Notify 对象，用于通知所有任务停止
-    let notify = Arc::new(Notify::new());
+    // 关闭信号，晚于通知开始等待的任务也能收到
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     let conf = if let Some(file) = option {
         config::Config::try_from(file.as_path())?
@@ -901,9 +1157,10 @@ pub async fn start_server(option: &Option<PathBuf>) -> anyhow::Result<()> {
     // common core data
     let the_config = Arc::new(conf);
 
+    // 运行期间一直持有数据目录的所有者锁，离线写入工具无法同时修改
+    let _owner = OwnerLock::acquire(&the_config.owner_lock_filepath())?;
     let (tx, rx) = mpsc::channel();
-    let store = db_store::new_store(Arc::clone(&the_config), tx, rx);
-    let store = Arc::new(RwLock::new(store));
+    let store = db_store::open_store(Arc::clone(&the_config), tx, rx)?;
 
     // joinset
     let mut join_set = JoinSet::new();
@@ -916,52 +1173,140 @@ pub async fn start_server(option: &Option<PathBuf>) -> anyhow::Result<()> {
     //     server.server_start();
     // });
 
-    let notify_clone = Arc::clone(&notify);
+    let shutdown = shutdown_rx.clone();
     join_set.spawn(async move {
         // 使用 block_in_place 处理阻塞操作
-        server.server_start(notify_clone).await.unwrap();
+        server.server_start(shutdown).await.unwrap();
     });
 
     // 添加 gRPC 服务器任务（如果配置存在）
     if let Some(grpc_config) = the_config.get_grpc() {
         let store_clone = Arc::clone(&store);
         let addr = grpc_config.get_addr()?;
-        let notify_clone = Arc::clone(&notify);
+        let shutdown = shutdown_rx.clone();
         join_set.spawn(async move {
-            run_grpc_server(addr, store_clone, notify_clone)
-                .await
-                .unwrap();
+            run_grpc_server(addr, store_clone, shutdown).await.unwrap();
         });
     }
 
-    // 监听 Ctrl+C 信号
-    signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
-    info!("Received Ctrl+C, shutting down...");
+    // 监听 SIGTERM/SIGINT 信号
+    let name = wait_for_signal().await;
+    info!("Received {}, shutting down...", name);
 
     // 通知所有任务停止
-    notify.notify_waiters();
+    shutdown_tx.send_replace(true);
+
+    // 等待正在处理的请求完成，超时后中断
+    let timeout = the_config.shutdown_timeout();
+    let mut result = Ok(());
+    let drain = async {
+        while let Some(res) = join_set.join_next().await {
+            if let Err(e) = res {
+                error!("server task failed: {:?}", e);
+            }
+        }
+    };
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        warn!("requests not finished within {:?}, abort them", timeout);
+        join_set.abort_all();
+        while join_set.join_next().await.is_some() {}
+        result = Err(anyhow::anyhow!(
+            "shutdown timed out after {:?}, in-flight requests aborted",
+            timeout
+        ));
+    }
 
-    // 等待所有任务完成
-    while let Some(Ok(_)) = join_set.join_next().await {}
+    // 停止合并线程并同步 active file，可能阻塞
+    tokio::task::spawn_blocking(move || store.write().unwrap().close()).await?;
+    info!("store closed");
 
-    Ok(())
+    result
+}
+
+// 返回收到的信号名称
+async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal as unix_signal, SignalKind};
+        let mut sigterm =
+            unix_signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+        "Ctrl+C"
+    }
 }
 
 // gRPC server
 async fn run_grpc_server(
     addr: SocketAddr,
     store: Arc<RwLock<dyn db_store::Op>>,
-    notify: Arc<Notify>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
     println!("gRPC Server Listening on {:?}", addr);
 
+    // tonic 停止接收新请求后会等待处理中的请求完成
     tonic::transport::Server::builder()
         .add_service(StoreServer::new(StoreImpl::new(store)))
-        .serve_with_shutdown(addr, async {
-            notify.notified().await;
+        .serve_with_shutdown(addr, async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
         })
         // .serve(addr)
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Reply, Server};
+    use crate::config::test_config;
+    use crate::store::store as db_store;
+    use redis_protocol::resp2::types::OwnedFrame;
+    use std::sync::{mpsc, Arc};
+
+    fn command(args: &[&[u8]]) -> OwnedFrame {
+        OwnedFrame::Array(
+            args.iter()
+                .map(|arg| OwnedFrame::BulkString(arg.to_vec()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn incr_sees_submitted_set() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+        let (tx, rx) = mpsc::channel();
+        let store = db_store::open_store(Arc::clone(&config), tx, rx)?;
+        let server = Arc::new(Server::new(config, Arc::clone(&store)));
+
+        for round in 0..100i64 {
+            // SET 只提交给写入线程，尚未等待完成时执行 INCR
+            let value = (round * 10).to_string();
+            let Some(Reply::Pending(write, _)) =
+                server.submit_frame(&command(&[b"SET", b"counter", value.as_bytes()]))
+            else {
+                panic!("SET was not submitted");
+            };
+            let incr = Arc::clone(&server);
+            let result = tokio::task::spawn_blocking(move || {
+                incr.handle_frame(&command(&[b"INCR", b"counter"]))
+            })
+            .await?;
+            assert_eq!(OwnedFrame::Integer(round * 10 + 1), result.unwrap());
+            write.wait().await?;
+            assert_eq!(
+                (round * 10 + 1).to_string().into_bytes(),
+                store.read().unwrap().get(b"counter")?
+            );
+        }
+        store.write().unwrap().close();
+        Ok(())
+    }
+}
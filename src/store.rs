@@ -1,3 +1,13 @@
 #![allow(clippy::module_inception)]
+pub mod compact;
+pub mod disk;
 pub mod file;
+pub mod ingest;
+pub mod loader;
+pub mod merge;
+pub mod metrics;
+pub mod quarantine;
+pub mod scrub;
+pub mod snapshot;
 pub mod store;
+pub mod writer;
@@ -0,0 +1,613 @@
+use super::store::{Metadata, OpKeydir, ACTIVE_FILE_SEQ};
+use crate::OpError;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hasher};
+use std::io;
+
+// 内存紧凑型 keydir
+//
+// key 统一存放在固定大小的 slab 中，索引为开放寻址哈希表，每个槽位只保存
+// key 在 slab 中的位置和压缩后的 Metadata，避免每个 key 一次堆分配。
+// 开启前缀压缩后，key 按第一个分隔符拆分为 前缀 + 后缀，前缀只保存一份。
+// 位置或大小超过 1TiB 的 Metadata 不压缩，单独保存在 wide 中。
+
+const SLAB_SIZE: usize = 64 * 1024;
+const MAX_LOAD_FACTOR: f64 = 0.8;
+const MIN_CAPACITY: usize = 16;
+const PREFIX_DELIMITERS: &[u8] = b":/";
+const NO_PREFIX: u16 = 0;
+const MAX_PREFIXES: usize = u16::MAX as usize;
+
+// 槽位状态，借用 slab 编号的最大两个值
+const SLOT_EMPTY: u32 = u32::MAX;
+const SLOT_DELETED: u32 = u32::MAX - 1;
+
+// slab (4 bytes) | offset (4 bytes) | len (4 bytes) | prefix (2 bytes) | metadata (18 bytes)
+const SLOT_SIZE: usize = 32;
+
+// file_id (2 bytes) | value_pos (5 bytes) | value_sz (5 bytes) | tstamp (6 bytes)
+const PACKED_METADATA_SIZE: usize = 18;
+const MAX_40_BITS: u64 = (1 << 40) - 1;
+const MAX_48_BITS: u64 = (1 << 48) - 1;
+// value_sz 为该值时 value_pos 为 wide 中的下标
+const WIDE_MARKER: u64 = MAX_40_BITS;
+
+#[derive(Clone, Copy)]
+struct Slot([u8; SLOT_SIZE]);
+
+impl Slot {
+    fn empty() -> Slot {
+        let mut slot = Slot([0; SLOT_SIZE]);
+        slot.set_slab(SLOT_EMPTY);
+        slot
+    }
+
+    fn slab(&self) -> u32 {
+        u32::from_le_bytes(self.0[0..4].try_into().unwrap())
+    }
+
+    fn set_slab(&mut self, slab: u32) {
+        self.0[0..4].copy_from_slice(&slab.to_le_bytes());
+    }
+
+    fn offset(&self) -> usize {
+        u32::from_le_bytes(self.0[4..8].try_into().unwrap()) as usize
+    }
+
+    fn len(&self) -> usize {
+        u32::from_le_bytes(self.0[8..12].try_into().unwrap()) as usize
+    }
+
+    fn prefix(&self) -> u16 {
+        u16::from_le_bytes(self.0[12..14].try_into().unwrap())
+    }
+
+    fn is_occupied(&self) -> bool {
+        self.slab() < SLOT_DELETED
+    }
+
+    // Metadata 为空，由 CompactKeydir::store_metadata 写入
+    fn new(key: KeyRef) -> Slot {
+        let mut slot = Slot([0; SLOT_SIZE]);
+        slot.set_key_ref(key);
+        slot
+    }
+
+    fn set_key_ref(&mut self, key: KeyRef) {
+        self.set_slab(key.slab);
+        self.0[4..8].copy_from_slice(&key.offset.to_le_bytes());
+        self.0[8..12].copy_from_slice(&key.len.to_le_bytes());
+        self.0[12..14].copy_from_slice(&key.prefix.to_le_bytes());
+    }
+
+    fn key_ref(&self) -> KeyRef {
+        KeyRef {
+            slab: self.slab(),
+            offset: self.offset() as u32,
+            len: self.len() as u32,
+            prefix: self.prefix(),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let b = &self.0[14..14 + PACKED_METADATA_SIZE];
+        let mut pos = [0u8; 8];
+        pos[..5].copy_from_slice(&b[2..7]);
+        let mut sz = [0u8; 8];
+        sz[..5].copy_from_slice(&b[7..12]);
+        let mut ts = [0u8; 8];
+        ts[..6].copy_from_slice(&b[12..18]);
+
+        Metadata {
+            file_id: u16::from_le_bytes(b[0..2].try_into().unwrap()),
+            value_pos: u64::from_le_bytes(pos),
+            value_sz: u64::from_le_bytes(sz),
+            tstamp: u64::from_le_bytes(ts),
+        }
+    }
+
+    fn file_id(&self) -> u16 {
+        u16::from_le_bytes(self.0[14..16].try_into().unwrap())
+    }
+
+    fn set_file_id(&mut self, file_id: u16) {
+        self.0[14..16].copy_from_slice(&file_id.to_le_bytes());
+    }
+
+    fn wide_index(&self) -> Option<usize> {
+        let metadata = self.metadata();
+        (metadata.value_sz == WIDE_MARKER).then_some(metadata.value_pos as usize)
+    }
+
+    // 调用方保证 fits(metadata)
+    fn set_metadata(&mut self, metadata: &Metadata) {
+        let tstamp = metadata.tstamp.min(MAX_48_BITS);
+
+        let b = &mut self.0[14..14 + PACKED_METADATA_SIZE];
+        b[0..2].copy_from_slice(&metadata.file_id.to_le_bytes());
+        b[2..7].copy_from_slice(&metadata.value_pos.to_le_bytes()[..5]);
+        b[7..12].copy_from_slice(&metadata.value_sz.to_le_bytes()[..5]);
+        b[12..18].copy_from_slice(&tstamp.to_le_bytes()[..6]);
+    }
+}
+
+fn fits(metadata: &Metadata) -> bool {
+    metadata.value_pos <= MAX_40_BITS && metadata.value_sz < WIDE_MARKER
+}
+
+// key 在 arena 中的位置
+#[derive(Clone, Copy)]
+struct KeyRef {
+    slab: u32,
+    offset: u32,
+    len: u32,
+    prefix: u16,
+}
+
+pub struct CompactKeydir {
+    slots: Vec<Slot>,
+    len: usize,
+    deleted: usize,
+    slabs: Vec<Box<[u8]>>,
+    current_slab: Option<(usize, usize)>, // 当前可写 slab 及已使用字节
+    arena_live: usize,
+    arena_garbage: usize,
+    prefix_compression: bool,
+    prefixes: Vec<Vec<u8>>,
+    prefix_ids: HashMap<u64, Vec<u16>>, // 前缀哈希 => 前缀编号
+    wide: Vec<Metadata>,                // 不能压缩的 Metadata
+    wide_free: Vec<usize>,
+}
+
+impl CompactKeydir {
+    pub fn with_prefix_compression(prefix_compression: bool) -> CompactKeydir {
+        CompactKeydir {
+            slots: Vec::new(),
+            len: 0,
+            deleted: 0,
+            slabs: Vec::new(),
+            current_slab: None,
+            arena_live: 0,
+            arena_garbage: 0,
+            prefix_compression,
+            // 0 号前缀为空前缀
+            prefixes: vec![Vec::new()],
+            prefix_ids: HashMap::new(),
+            wide: Vec::new(),
+            wide_free: Vec::new(),
+        }
+    }
+
+    fn hash(key: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        hasher.finish()
+    }
+
+    // 拆分前缀，返回 (前缀编号, 后缀)
+    fn split_key<'a>(&mut self, key: &'a [u8]) -> (u16, &'a [u8]) {
+        if !self.prefix_compression {
+            return (NO_PREFIX, key);
+        }
+
+        let pos = match key.iter().position(|b| PREFIX_DELIMITERS.contains(b)) {
+            Some(pos) => pos + 1,
+            None => return (NO_PREFIX, key),
+        };
+        let (prefix, suffix) = key.split_at(pos);
+
+        let hash = Self::hash(prefix);
+        if let Some(ids) = self.prefix_ids.get(&hash) {
+            if let Some(id) = ids.iter().find(|id| self.prefixes[**id as usize] == prefix) {
+                return (*id, suffix);
+            }
+        }
+        if self.prefixes.len() >= MAX_PREFIXES {
+            // 前缀表已满，保存完整 key
+            return (NO_PREFIX, key);
+        }
+
+        let id = self.prefixes.len() as u16;
+        self.prefixes.push(prefix.to_vec());
+        self.prefix_ids.entry(hash).or_default().push(id);
+        (id, suffix)
+    }
+
+    fn metadata_of(&self, slot: &Slot) -> Metadata {
+        match slot.wide_index() {
+            Some(i) => Metadata {
+                file_id: slot.file_id(),
+                ..self.wide[i].clone()
+            },
+            None => slot.metadata(),
+        }
+    }
+
+    // 能压缩时保存在槽位中，否则保存在 wide 中
+    fn store_metadata(&mut self, idx: usize, metadata: &Metadata) {
+        let old = self.slots[idx].wide_index();
+        if fits(metadata) {
+            if let Some(i) = old {
+                self.wide_free.push(i);
+            }
+            self.slots[idx].set_metadata(metadata);
+            return;
+        }
+
+        let i = match old.or_else(|| self.wide_free.pop()) {
+            Some(i) => {
+                self.wide[i] = metadata.clone();
+                i
+            }
+            None => {
+                self.wide.push(metadata.clone());
+                self.wide.len() - 1
+            }
+        };
+        self.slots[idx].set_metadata(&Metadata {
+            file_id: metadata.file_id,
+            value_pos: i as u64,
+            value_sz: WIDE_MARKER,
+            tstamp: metadata.tstamp,
+        });
+    }
+
+    fn suffix(&self, key: KeyRef) -> &[u8] {
+        let start = key.offset as usize;
+        &self.slabs[key.slab as usize][start..start + key.len as usize]
+    }
+
+    fn key_of(&self, key: KeyRef) -> Vec<u8> {
+        let prefix = &self.prefixes[key.prefix as usize];
+        let mut result = Vec::with_capacity(prefix.len() + key.len as usize);
+        result.extend_from_slice(prefix);
+        result.extend_from_slice(self.suffix(key));
+        result
+    }
+
+    fn key_eq(&self, key_ref: KeyRef, key: &[u8]) -> bool {
+        let prefix = &self.prefixes[key_ref.prefix as usize];
+        if prefix.len() + key_ref.len as usize != key.len() {
+            return false;
+        }
+        let (head, tail) = key.split_at(prefix.len());
+        head == prefix.as_slice() && tail == self.suffix(key_ref)
+    }
+
+    // 将后缀写入 arena
+    fn alloc(&mut self, prefix: u16, suffix: &[u8]) -> KeyRef {
+        let (slab, offset) = push_arena(&mut self.slabs, &mut self.current_slab, suffix);
+        self.arena_live += suffix.len();
+
+        KeyRef {
+            slab,
+            offset,
+            len: suffix.len() as u32,
+            prefix,
+        }
+    }
+
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut idx = Self::hash(key) as usize & mask;
+        loop {
+            let slot = &self.slots[idx];
+            match slot.slab() {
+                SLOT_EMPTY => return None,
+                SLOT_DELETED => {}
+                _ => {
+                    if self.key_eq(slot.key_ref(), key) {
+                        return Some(idx);
+                    }
+                }
+            }
+            idx = (idx + 1) & mask;
+        }
+    }
+
+    fn reserve_one(&mut self) {
+        let needed = self.len + self.deleted + 1;
+        if (needed as f64) <= self.slots.len() as f64 * MAX_LOAD_FACTOR {
+            return;
+        }
+        // 仅因删除标记触发时原容量重建
+        let mut capacity = self.slots.len().max(MIN_CAPACITY);
+        while (self.len + 1) as f64 > capacity as f64 * MAX_LOAD_FACTOR {
+            capacity *= 2;
+        }
+        self.rehash(capacity);
+    }
+
+    fn rehash(&mut self, capacity: usize) {
+        let old = std::mem::replace(&mut self.slots, vec![Slot::empty(); capacity]);
+        let mask = capacity - 1;
+        for slot in old.into_iter().filter(|s| s.is_occupied()) {
+            let key = self.key_of(slot.key_ref());
+            let mut idx = Self::hash(&key) as usize & mask;
+            while self.slots[idx].is_occupied() {
+                idx = (idx + 1) & mask;
+            }
+            self.slots[idx] = slot;
+        }
+        self.deleted = 0;
+    }
+
+    // 删除的 key 过多时重新整理 arena
+    fn compact_arena(&mut self) {
+        let mut slabs: Vec<Box<[u8]>> = Vec::new();
+        let mut current = None;
+
+        for idx in 0..self.slots.len() {
+            let slot = self.slots[idx];
+            if !slot.is_occupied() {
+                continue;
+            }
+            let key_ref = slot.key_ref();
+            let (slab, offset) = push_arena(&mut slabs, &mut current, self.suffix(key_ref));
+            self.slots[idx].set_key_ref(KeyRef {
+                slab,
+                offset,
+                ..key_ref
+            });
+        }
+
+        self.slabs = slabs;
+        self.current_slab = current;
+        self.arena_garbage = 0;
+    }
+}
+
+// 写入 slab，返回 (slab, offset)；超过 slab 大小的 key 独占一个 slab
+fn push_arena(
+    slabs: &mut Vec<Box<[u8]>>,
+    current: &mut Option<(usize, usize)>,
+    bytes: &[u8],
+) -> (u32, u32) {
+    if bytes.len() > SLAB_SIZE {
+        slabs.push(bytes.to_vec().into_boxed_slice());
+        return ((slabs.len() - 1) as u32, 0);
+    }
+
+    let (slab, used) = match *current {
+        Some((slab, used)) if used + bytes.len() <= SLAB_SIZE => (slab, used),
+        _ => {
+            slabs.push(vec![0u8; SLAB_SIZE].into_boxed_slice());
+            (slabs.len() - 1, 0)
+        }
+    };
+    slabs[slab][used..used + bytes.len()].copy_from_slice(bytes);
+    *current = Some((slab, used + bytes.len()));
+
+    (slab as u32, used as u32)
+}
+
+impl OpKeydir for CompactKeydir {
+    fn new() -> CompactKeydir {
+        CompactKeydir::with_prefix_compression(false)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Metadata, OpError> {
+        self.find(key)
+            .map(|idx| self.metadata_of(&self.slots[idx]))
+            .ok_or(OpError::KeyNotFound)
+    }
+
+    fn set(&mut self, key: &[u8], metadata: Metadata) -> io::Result<()> {
+        if let Some(idx) = self.find(key) {
+            self.store_metadata(idx, &metadata);
+            return Ok(());
+        }
+
+        self.reserve_one();
+        let (prefix, suffix) = self.split_key(key);
+        let key_ref = self.alloc(prefix, suffix);
+
+        let mask = self.slots.len() - 1;
+        let mut idx = Self::hash(key) as usize & mask;
+        while self.slots[idx].is_occupied() {
+            idx = (idx + 1) & mask;
+        }
+        if self.slots[idx].slab() == SLOT_DELETED {
+            self.deleted -= 1;
+        }
+        self.slots[idx] = Slot::new(key_ref);
+        self.store_metadata(idx, &metadata);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> io::Result<()> {
+        let idx = match self.find(key) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+
+        let len = self.slots[idx].len();
+        if let Some(i) = self.slots[idx].wide_index() {
+            self.wide_free.push(i);
+        }
+        self.slots[idx].set_slab(SLOT_DELETED);
+        self.len -= 1;
+        self.deleted += 1;
+        self.arena_live -= len;
+        self.arena_garbage += len;
+
+        if self.arena_garbage > SLAB_SIZE && self.arena_garbage > self.arena_live {
+            self.compact_arena();
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn extend<I>(&mut self, iter: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Metadata)>,
+    {
+        for (key, metadata) in iter {
+            self.set(&key, metadata)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Metadata)> + '_> {
+        Box::new(
+            self.slots
+                .iter()
+                .filter(|slot| slot.is_occupied())
+                .map(|slot| (self.key_of(slot.key_ref()), self.metadata_of(slot))),
+        )
+    }
+
+    fn update_key(&mut self, file_id: u16) -> io::Result<()> {
+        for slot in self.slots.iter_mut().filter(|s| s.is_occupied()) {
+            if slot.file_id() == ACTIVE_FILE_SEQ {
+                slot.set_file_id(file_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|(k, _)| k).collect()
+    }
+
+    fn memory_usage(&self) -> usize {
+        let slots = self.slots.capacity() * std::mem::size_of::<Slot>();
+        let slabs = self.slabs.capacity() * std::mem::size_of::<Box<[u8]>>()
+            + self.slabs.iter().map(|s| s.len()).sum::<usize>();
+        let prefixes = self.prefixes.capacity() * std::mem::size_of::<Vec<u8>>()
+            + self.prefixes.iter().map(|p| p.capacity()).sum::<usize>();
+        let prefix_ids = self.prefix_ids.capacity() * (std::mem::size_of::<(u64, Vec<u16>)>() + 1)
+            + self
+                .prefix_ids
+                .values()
+                .map(|ids| ids.capacity() * 2)
+                .sum::<usize>();
+        let wide = self.wide.capacity() * std::mem::size_of::<Metadata>()
+            + self.wide_free.capacity() * std::mem::size_of::<usize>();
+
+        slots + slabs + prefixes + prefix_ids + wide
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompactKeydir;
+    use crate::store::store::{Keydir, Metadata, OpKeydir};
+
+    fn metadata(n: u64) -> Metadata {
+        Metadata {
+            file_id: (n % 7) as u16,
+            value_sz: n + 25,
+            value_pos: n * 100,
+            tstamp: 1_700_000_000_000 + n,
+        }
+    }
+
+    #[test]
+    fn compact_keydir_set_get_remove() {
+        for prefix_compression in [false, true] {
+            let mut keydir = CompactKeydir::with_prefix_compression(prefix_compression);
+            for n in 0..10_000u64 {
+                let key = format!("user:{}:name", n).into_bytes();
+                keydir.set(&key, metadata(n)).unwrap();
+            }
+            assert_eq!(10_000, keydir.len());
+
+            for n in (0..10_000u64).step_by(2) {
+                let key = format!("user:{}:name", n).into_bytes();
+                keydir.remove(&key).unwrap();
+            }
+            assert_eq!(5_000, keydir.len());
+
+            for n in 0..10_000u64 {
+                let key = format!("user:{}:name", n).into_bytes();
+                match keydir.get(&key) {
+                    Ok(m) => {
+                        assert_eq!(1, n % 2);
+                        assert_eq!(n * 100, m.value_pos);
+                        assert_eq!(n + 25, m.value_sz);
+                        assert_eq!(1_700_000_000_000 + n, m.tstamp);
+                    }
+                    Err(_) => assert_eq!(0, n % 2),
+                }
+            }
+
+            if prefix_compression {
+                // 按第一个分隔符拆分，所有 key 共用 "user:" 前缀
+                assert_eq!(2, keydir.prefixes.len());
+            }
+
+            let mut keys = keydir.keys();
+            keys.sort();
+            assert_eq!(5_000, keys.len());
+            assert!(keys.contains(&b"user:1:name".to_vec()));
+
+            keydir.update_key(9).unwrap();
+            for (_, m) in keydir.iter() {
+                assert_ne!(0, m.file_id);
+            }
+        }
+    }
+
+    #[test]
+    fn compact_keydir_large_key() {
+        let mut keydir = CompactKeydir::new();
+        keydir.set(b"small", metadata(1)).unwrap();
+        let big = vec![b'x'; 100 * 1024];
+        keydir.set(&big, metadata(2)).unwrap();
+        keydir.set(b"after", metadata(3)).unwrap();
+        assert_eq!(200, keydir.get(&big).unwrap().value_pos);
+        assert_eq!(100, keydir.get(b"small").unwrap().value_pos);
+        assert_eq!(300, keydir.get(b"after").unwrap().value_pos);
+    }
+
+    #[test]
+    fn compact_keydir_wide_metadata() {
+        let mut keydir = CompactKeydir::new();
+        let wide = Metadata {
+            file_id: 0,
+            value_sz: 1 << 41,
+            value_pos: 3 << 40,
+            tstamp: 1,
+        };
+        keydir.set(b"big", wide.clone()).unwrap();
+        keydir.set(b"small", metadata(1)).unwrap();
+        assert_eq!(wide, keydir.get(b"big").unwrap());
+
+        keydir.update_key(5).unwrap();
+        assert_eq!(5, keydir.get(b"big").unwrap().file_id);
+        assert_eq!(3 << 40, keydir.get(b"big").unwrap().value_pos);
+
+        // 改为可以压缩的值后释放 wide 中的位置
+        keydir.set(b"big", metadata(2)).unwrap();
+        assert_eq!(metadata(2), keydir.get(b"big").unwrap());
+        keydir.set(b"other", wide.clone()).unwrap();
+        assert_eq!(1, keydir.wide.len());
+        keydir.remove(b"other").unwrap();
+        assert!(keydir.get(b"other").is_err());
+        assert_eq!(metadata(1), keydir.get(b"small").unwrap());
+    }
+
+    #[test]
+    fn compact_keydir_memory_usage() {
+        let mut compact = CompactKeydir::with_prefix_compression(true);
+        let mut keydir = Keydir::new();
+        for n in 0..50_000u64 {
+            let key = format!("session:{:08}", n).into_bytes();
+            compact.set(&key, metadata(n)).unwrap();
+            keydir.set(&key, metadata(n)).unwrap();
+        }
+        assert!(compact.memory_usage() < keydir.memory_usage());
+    }
+}
@@ -0,0 +1,878 @@
+use super::store::{Metadata, OpKeydir, ACTIVE_FILE_SEQ};
+use crate::util::fnv1a;
+use crate::OpError;
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// 磁盘型 keydir
+//
+// 索引保存在线性哈希表文件中，通过有界的页缓存访问，key 数量可以超过内存容量。
+// 目录下包含三个文件：
+//   index    第 0 页为文件头，之后每页对应一个桶的主页
+//   overflow 桶的溢出页，释放的溢出页通过空闲链表复用
+//   large    超过 MAX_INLINE_KEY 的 key，常驻内存，关闭时写入
+// 活跃文件(file_id = 0)中的 key 只保存在内存中，归档时再写入磁盘索引，
+// 重启时由活跃文件重放恢复。
+
+const PAGE_SIZE: usize = 4096;
+// count (2 bytes) | used (2 bytes) | next overflow page (4 bytes)
+const PAGE_HEADER: usize = 8;
+// file_id (2 bytes) | value_sz (8 bytes) | value_pos (8 bytes) | tstamp (8 bytes)
+const RECORD_META: usize = 26;
+const MAX_INLINE_KEY: usize = 1024;
+const INITIAL_BUCKETS: u64 = 16;
+// 数据量超过桶容量的比例后分裂
+const SPLIT_FILL: f64 = 0.75;
+
+const MAGIC: &[u8; 8] = b"MINKVIDX";
+const VERSION: u32 = 1;
+
+const INDEX_FILE: &str = "index";
+const OVERFLOW_FILE: &str = "overflow";
+const LARGE_FILE: &str = "large";
+
+pub const DEFAULT_CACHE_PAGES: usize = 4096;
+
+fn bucket_page(bucket: u64) -> u64 {
+    (bucket + 1) << 1
+}
+
+fn overflow_page(idx: u32) -> u64 {
+    ((idx as u64) << 1) | 1
+}
+
+fn record_len(key: &[u8]) -> usize {
+    2 + key.len() + RECORD_META
+}
+
+// --- page helpers
+fn page_count(page: &[u8]) -> u16 {
+    u16::from_le_bytes(page[0..2].try_into().unwrap())
+}
+
+fn page_used(page: &[u8]) -> usize {
+    u16::from_le_bytes(page[2..4].try_into().unwrap()) as usize
+}
+
+fn page_next(page: &[u8]) -> u32 {
+    u32::from_le_bytes(page[4..8].try_into().unwrap())
+}
+
+fn set_page_header(page: &mut [u8], count: u16, used: usize) {
+    page[0..2].copy_from_slice(&count.to_le_bytes());
+    page[2..4].copy_from_slice(&(used as u16).to_le_bytes());
+}
+
+fn set_page_next(page: &mut [u8], next: u32) {
+    page[4..8].copy_from_slice(&next.to_le_bytes());
+}
+
+fn page_fits(page: &[u8], len: usize) -> bool {
+    PAGE_HEADER + page_used(page) + len <= PAGE_SIZE
+}
+
+fn encode_meta(buf: &mut [u8], metadata: &Metadata) {
+    buf[0..2].copy_from_slice(&metadata.file_id.to_le_bytes());
+    buf[2..10].copy_from_slice(&metadata.value_sz.to_le_bytes());
+    buf[10..18].copy_from_slice(&metadata.value_pos.to_le_bytes());
+    buf[18..26].copy_from_slice(&metadata.tstamp.to_le_bytes());
+}
+
+fn decode_meta(buf: &[u8]) -> Metadata {
+    Metadata {
+        file_id: u16::from_le_bytes(buf[0..2].try_into().unwrap()),
+        value_sz: u64::from_le_bytes(buf[2..10].try_into().unwrap()),
+        value_pos: u64::from_le_bytes(buf[10..18].try_into().unwrap()),
+        tstamp: u64::from_le_bytes(buf[18..26].try_into().unwrap()),
+    }
+}
+
+// 遍历页内记录 (offset, key, metadata)
+fn page_records(page: &[u8]) -> Vec<(usize, &[u8], Metadata)> {
+    let mut result = Vec::with_capacity(page_count(page) as usize);
+    let end = PAGE_HEADER + page_used(page);
+    let mut offset = PAGE_HEADER;
+    while offset < end {
+        let key_len = u16::from_le_bytes(page[offset..offset + 2].try_into().unwrap()) as usize;
+        let key = &page[offset + 2..offset + 2 + key_len];
+        let meta = decode_meta(&page[offset + 2 + key_len..offset + 2 + key_len + RECORD_META]);
+        result.push((offset, key, meta));
+        offset += 2 + key_len + RECORD_META;
+    }
+    result
+}
+
+fn find_in_page(page: &[u8], key: &[u8]) -> Option<(usize, Metadata)> {
+    page_records(page)
+        .into_iter()
+        .find(|(_, k, _)| *k == key)
+        .map(|(offset, _, meta)| (offset, meta))
+}
+
+fn append_record(page: &mut [u8], key: &[u8], metadata: &Metadata) {
+    let used = page_used(page);
+    let offset = PAGE_HEADER + used;
+    page[offset..offset + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+    page[offset + 2..offset + 2 + key.len()].copy_from_slice(key);
+    encode_meta(
+        &mut page[offset + 2 + key.len()..offset + record_len(key)],
+        metadata,
+    );
+    let count = page_count(page) + 1;
+    set_page_header(page, count, used + record_len(key));
+}
+
+fn remove_record(page: &mut [u8], offset: usize) -> usize {
+    let key_len = u16::from_le_bytes(page[offset..offset + 2].try_into().unwrap()) as usize;
+    let len = 2 + key_len + RECORD_META;
+    let end = PAGE_HEADER + page_used(page);
+    page.copy_within(offset + len..end, offset);
+    page[end - len..end].fill(0);
+    let count = page_count(page) - 1;
+    set_page_header(page, count, end - len - PAGE_HEADER);
+    len
+}
+
+// --- pager
+struct CachedPage {
+    data: Box<[u8]>,
+    dirty: bool,
+    tick: u64,
+}
+
+// 有界 LRU 页缓存
+struct Pager {
+    index: File,
+    overflow: File,
+    cache: HashMap<u64, CachedPage>,
+    lru: BTreeMap<u64, u64>, // tick => page id
+    tick: u64,
+    capacity: usize,
+}
+
+impl Pager {
+    fn file(&mut self, id: u64) -> (&mut File, u64) {
+        let offset = (id >> 1) * PAGE_SIZE as u64;
+        if id & 1 == 0 {
+            (&mut self.index, offset)
+        } else {
+            (&mut self.overflow, offset)
+        }
+    }
+
+    fn read_page(&mut self, id: u64) -> io::Result<Box<[u8]>> {
+        let mut data = vec![0u8; PAGE_SIZE].into_boxed_slice();
+        let (file, offset) = self.file(id);
+        file.seek(SeekFrom::Start(offset))?;
+        // 超出文件末尾的页视为空页
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match file.read(&mut data[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_page(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        let (file, offset) = self.file(id);
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn load(&mut self, id: u64) -> io::Result<()> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(page) = self.cache.get_mut(&id) {
+            self.lru.remove(&page.tick);
+            page.tick = tick;
+            self.lru.insert(tick, id);
+            return Ok(());
+        }
+
+        while self.cache.len() >= self.capacity {
+            let (_, evict) = self.lru.pop_first().unwrap();
+            let page = self.cache.remove(&evict).unwrap();
+            if page.dirty {
+                self.write_page(evict, &page.data)?;
+            }
+        }
+
+        let data = self.read_page(id)?;
+        self.cache.insert(
+            id,
+            CachedPage {
+                data,
+                dirty: false,
+                tick,
+            },
+        );
+        self.lru.insert(tick, id);
+        Ok(())
+    }
+
+    fn page(&mut self, id: u64) -> io::Result<&[u8]> {
+        self.load(id)?;
+        Ok(&self.cache[&id].data)
+    }
+
+    fn page_mut(&mut self, id: u64) -> io::Result<&mut [u8]> {
+        self.load(id)?;
+        let page = self.cache.get_mut(&id).unwrap();
+        page.dirty = true;
+        Ok(&mut page.data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+        dirty.sort();
+        for id in dirty {
+            let data = std::mem::take(&mut self.cache.get_mut(&id).unwrap().data);
+            self.write_page(id, &data)?;
+            let page = self.cache.get_mut(&id).unwrap();
+            page.data = data;
+            page.dirty = false;
+        }
+        self.index.sync_all()?;
+        self.overflow.sync_all()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.cache.clear();
+        self.lru.clear();
+        self.index.set_len(0)?;
+        self.overflow.set_len(0)
+    }
+}
+
+// --- header
+#[derive(Default)]
+struct Header {
+    clean: bool,
+    level: u32,
+    split: u64,
+    count: u64,
+    data_bytes: u64,
+    overflow_pages: u32,
+    free_head: u32,
+    fingerprint: u64,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.push(self.clean as u8);
+        buf.extend_from_slice(&self.level.to_le_bytes());
+        buf.extend_from_slice(&self.split.to_le_bytes());
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(&self.data_bytes.to_le_bytes());
+        buf.extend_from_slice(&self.overflow_pages.to_le_bytes());
+        buf.extend_from_slice(&self.free_head.to_le_bytes());
+        buf.extend_from_slice(&self.fingerprint.to_le_bytes());
+        buf.resize(PAGE_SIZE, 0);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Header> {
+        if &buf[0..8] != MAGIC || u32::from_le_bytes(buf[8..12].try_into().unwrap()) != VERSION {
+            return None;
+        }
+        Some(Header {
+            clean: buf[12] == 1,
+            level: u32::from_le_bytes(buf[13..17].try_into().unwrap()),
+            split: u64::from_le_bytes(buf[17..25].try_into().unwrap()),
+            count: u64::from_le_bytes(buf[25..33].try_into().unwrap()),
+            data_bytes: u64::from_le_bytes(buf[33..41].try_into().unwrap()),
+            overflow_pages: u32::from_le_bytes(buf[41..45].try_into().unwrap()),
+            free_head: u32::from_le_bytes(buf[45..49].try_into().unwrap()),
+            fingerprint: u64::from_le_bytes(buf[49..57].try_into().unwrap()),
+        })
+    }
+}
+
+// --- linear hash index
+struct Index {
+    pager: Pager,
+    header: Header,
+}
+
+impl Index {
+    fn buckets(&self) -> u64 {
+        (INITIAL_BUCKETS << self.header.level) + self.header.split
+    }
+
+    fn bucket_of(&self, key: &[u8]) -> u64 {
+        let hash = fnv1a(key);
+        let n = INITIAL_BUCKETS << self.header.level;
+        let bucket = hash % n;
+        if bucket < self.header.split {
+            hash % (n * 2)
+        } else {
+            bucket
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let buf = self.header.encode();
+        self.pager.index.seek(SeekFrom::Start(0))?;
+        self.pager.index.write_all(&buf)?;
+        self.pager.index.sync_data()
+    }
+
+    // 开始修改前清除 clean 标记，异常退出后不会误用索引
+    fn mark_dirty(&mut self) -> io::Result<()> {
+        if self.header.clean {
+            self.header.clean = false;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.pager.clear()?;
+        self.header = Header::default();
+        self.write_header()
+    }
+
+    fn find(&mut self, key: &[u8]) -> io::Result<Option<(u64, usize, Metadata)>> {
+        let mut id = bucket_page(self.bucket_of(key));
+        loop {
+            let page = self.pager.page(id)?;
+            if let Some((offset, metadata)) = find_in_page(page, key) {
+                return Ok(Some((id, offset, metadata)));
+            }
+            match page_next(page) {
+                0 => return Ok(None),
+                next => id = overflow_page(next),
+            }
+        }
+    }
+
+    fn chain_records(&mut self, bucket: u64) -> io::Result<Vec<(Vec<u8>, Metadata)>> {
+        let mut result = Vec::new();
+        let mut id = bucket_page(bucket);
+        loop {
+            let page = self.pager.page(id)?;
+            for (_, key, metadata) in page_records(page) {
+                result.push((key.to_vec(), metadata));
+            }
+            match page_next(page) {
+                0 => return Ok(result),
+                next => id = overflow_page(next),
+            }
+        }
+    }
+
+    fn alloc_overflow(&mut self) -> io::Result<u32> {
+        if self.header.free_head != 0 {
+            let idx = self.header.free_head;
+            let page = self.pager.page_mut(overflow_page(idx))?;
+            self.header.free_head = page_next(page);
+            page.fill(0);
+            return Ok(idx);
+        }
+        self.header.overflow_pages += 1;
+        let idx = self.header.overflow_pages;
+        self.pager.page_mut(overflow_page(idx))?.fill(0);
+        Ok(idx)
+    }
+
+    fn free_overflow(&mut self, idx: u32) -> io::Result<()> {
+        let free_head = self.header.free_head;
+        let page = self.pager.page_mut(overflow_page(idx))?;
+        page.fill(0);
+        set_page_next(page, free_head);
+        self.header.free_head = idx;
+        Ok(())
+    }
+
+    // 写入桶链中第一个有空间的页
+    fn place(&mut self, key: &[u8], metadata: &Metadata) -> io::Result<()> {
+        let len = record_len(key);
+        let mut id = bucket_page(self.bucket_of(key));
+        loop {
+            let page = self.pager.page(id)?;
+            if page_fits(page, len) {
+                append_record(self.pager.page_mut(id)?, key, metadata);
+                return Ok(());
+            }
+            match page_next(page) {
+                0 => {
+                    let next = self.alloc_overflow()?;
+                    set_page_next(self.pager.page_mut(id)?, next);
+                    id = overflow_page(next);
+                }
+                next => id = overflow_page(next),
+            }
+        }
+    }
+
+    fn upsert(&mut self, key: &[u8], metadata: &Metadata) -> io::Result<()> {
+        if let Some((id, offset, _)) = self.find(key)? {
+            let page = self.pager.page_mut(id)?;
+            let start = offset + 2 + key.len();
+            encode_meta(&mut page[start..start + RECORD_META], metadata);
+            return Ok(());
+        }
+
+        self.place(key, metadata)?;
+        self.header.count += 1;
+        self.header.data_bytes += record_len(key) as u64;
+
+        let capacity = self.buckets() as f64 * (PAGE_SIZE - PAGE_HEADER) as f64 * SPLIT_FILL;
+        if self.header.data_bytes as f64 > capacity {
+            self.split()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> io::Result<bool> {
+        match self.find(key)? {
+            Some((id, offset, _)) => {
+                let len = remove_record(self.pager.page_mut(id)?, offset);
+                self.header.count -= 1;
+                self.header.data_bytes -= len as u64;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // 分裂 split 指向的桶
+    fn split(&mut self) -> io::Result<()> {
+        let n = INITIAL_BUCKETS << self.header.level;
+        let bucket = self.header.split;
+        let records = self.chain_records(bucket)?;
+
+        // 释放旧桶的溢出页
+        let mut next = page_next(self.pager.page(bucket_page(bucket))?);
+        while next != 0 {
+            let following = page_next(self.pager.page(overflow_page(next))?);
+            self.free_overflow(next)?;
+            next = following;
+        }
+        self.pager.page_mut(bucket_page(bucket))?.fill(0);
+        self.pager.page_mut(bucket_page(bucket + n))?.fill(0);
+
+        self.header.split += 1;
+        if self.header.split == n {
+            self.header.level += 1;
+            self.header.split = 0;
+        }
+
+        for (key, metadata) in records {
+            self.place(&key, &metadata)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DiskKeydir {
+    dir: PathBuf,
+    index: Mutex<Index>,
+    active: HashMap<Vec<u8>, Metadata>, // 活跃文件中的 key
+    large: HashMap<Vec<u8>, Metadata>,  // 超长 key
+    restorable: bool,
+    _tmp: Option<tempfile::TempDir>,
+}
+
+impl DiskKeydir {
+    // 打开索引目录，需调用 restore 决定是否沿用已有内容
+    pub fn open(dir: &Path, cache_pages: usize) -> io::Result<DiskKeydir> {
+        fs::create_dir_all(dir)?;
+        let open = |name: &str| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(name))
+        };
+
+        let mut pager = Pager {
+            index: open(INDEX_FILE)?,
+            overflow: open(OVERFLOW_FILE)?,
+            cache: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity: cache_pages.max(2),
+        };
+
+        let header = Header::decode(&pager.read_page(0)?);
+        let restorable = header.as_ref().map(|h| h.clean).unwrap_or(false);
+        let mut index = Index {
+            pager,
+            header: header.unwrap_or_default(),
+        };
+        if !restorable {
+            index.reset()?;
+        }
+
+        Ok(DiskKeydir {
+            dir: dir.to_path_buf(),
+            index: Mutex::new(index),
+            active: HashMap::new(),
+            large: HashMap::new(),
+            restorable,
+            _tmp: None,
+        })
+    }
+
+    fn index(&mut self) -> &mut Index {
+        self.index.get_mut().unwrap()
+    }
+
+    fn load_large(&mut self) -> io::Result<()> {
+        let path = self.dir.join(LARGE_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buf = [0u8; 4];
+        loop {
+            match reader.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let mut key = vec![0u8; u32::from_le_bytes(buf) as usize];
+            reader.read_exact(&mut key)?;
+            let mut meta = [0u8; RECORD_META];
+            reader.read_exact(&mut meta)?;
+            self.large.insert(key, decode_meta(&meta));
+        }
+        Ok(())
+    }
+
+    fn save_large(&self) -> io::Result<()> {
+        let path = self.dir.join(LARGE_FILE);
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut meta = [0u8; RECORD_META];
+        for (key, metadata) in self.large.iter() {
+            writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            writer.write_all(key)?;
+            encode_meta(&mut meta, metadata);
+            writer.write_all(&meta)?;
+        }
+        writer.into_inner()?.sync_all()
+    }
+
+    fn disk_set(&mut self, key: &[u8], metadata: &Metadata) -> io::Result<()> {
+        if key.len() > MAX_INLINE_KEY {
+            self.large.insert(key.to_vec(), metadata.clone());
+            return Ok(());
+        }
+        let index = self.index();
+        index.mark_dirty()?;
+        index.upsert(key, metadata)
+    }
+
+    fn disk_remove(&mut self, key: &[u8]) -> io::Result<()> {
+        if key.len() > MAX_INLINE_KEY {
+            self.large.remove(key);
+            return Ok(());
+        }
+        let index = self.index();
+        index.mark_dirty()?;
+        index.remove(key).map(|_| ())
+    }
+}
+
+impl OpKeydir for DiskKeydir {
+    fn new() -> DiskKeydir {
+        let tmp = tempfile::tempdir().expect("create keydir temp dir failed");
+        let mut keydir =
+            DiskKeydir::open(tmp.path(), DEFAULT_CACHE_PAGES).expect("open keydir index failed");
+        keydir._tmp = Some(tmp);
+        keydir
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Metadata, OpError> {
+        if let Some(metadata) = self.active.get(key) {
+            return Ok(metadata.clone());
+        }
+        if key.len() > MAX_INLINE_KEY {
+            return self.large.get(key).cloned().ok_or(OpError::KeyNotFound);
+        }
+
+        let mut index = self.index.lock().unwrap();
+        match index.find(key) {
+            Ok(Some((_, _, metadata))) => Ok(metadata),
+            Ok(None) => Err(OpError::KeyNotFound),
+            // 读取索引失败不能当作 key 不存在，否则读取返回空值、写入误判为新 key
+            Err(e) => {
+                error!("read keydir index failed: {:?}", e);
+                Err(OpError::Io(e))
+            }
+        }
+    }
+
+    fn set(&mut self, key: &[u8], metadata: Metadata) -> io::Result<()> {
+        if metadata.file_id == ACTIVE_FILE_SEQ {
+            if !self.active.contains_key(key) {
+                self.disk_remove(key)?;
+            }
+            self.active.insert(key.to_vec(), metadata);
+            return Ok(());
+        }
+
+        // 活跃文件中的值总是更新
+        if self.active.contains_key(key) {
+            return Ok(());
+        }
+        self.disk_set(key, &metadata)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> io::Result<()> {
+        if self.active.remove(key).is_none() {
+            self.disk_remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        let disk = self.index.lock().unwrap().header.count as usize;
+        disk + self.active.len() + self.large.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn extend<I>(&mut self, iter: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Metadata)>,
+    {
+        for (key, metadata) in iter {
+            self.set(&key, metadata)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Metadata)> + '_> {
+        let buckets = self.index.lock().unwrap().buckets();
+        let memory = self
+            .active
+            .iter()
+            .chain(self.large.iter())
+            .map(|(k, v)| (k.clone(), v.clone()));
+        Box::new(memory.chain(DiskIter {
+            keydir: self,
+            bucket: 0,
+            buckets,
+            buffer: Vec::new().into_iter(),
+        }))
+    }
+
+    // 写入失败时未写入的 key 留在内存中，已归档的保留新的 file_id，下次归档时继续写入
+    fn update_key(&mut self, file_id: u16) -> io::Result<()> {
+        let mut active = std::mem::take(&mut self.active).into_iter();
+        while let Some((key, mut metadata)) = active.next() {
+            if metadata.file_id == ACTIVE_FILE_SEQ {
+                metadata.file_id = file_id;
+            }
+            if let Err(e) = self.disk_set(&key, &metadata) {
+                self.active.insert(key, metadata);
+                for (key, mut metadata) in active {
+                    if metadata.file_id == ACTIVE_FILE_SEQ {
+                        metadata.file_id = file_id;
+                    }
+                    self.active.insert(key, metadata);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|(k, _)| k).collect()
+    }
+
+    fn memory_usage(&self) -> usize {
+        let cache = self.index.lock().unwrap().pager.cache.capacity()
+            * (std::mem::size_of::<(u64, CachedPage)>() + 1 + PAGE_SIZE);
+        let slot = std::mem::size_of::<(Vec<u8>, Metadata)>() + 1;
+        let active =
+            self.active.capacity() * slot + self.active.keys().map(|k| k.capacity()).sum::<usize>();
+        let large =
+            self.large.capacity() * slot + self.large.keys().map(|k| k.capacity()).sum::<usize>();
+        cache + active + large
+    }
+
+    fn restore(&mut self, fingerprint: u64) -> io::Result<bool> {
+        let restored = self.restorable && self.index().header.fingerprint == fingerprint;
+        self.restorable = false;
+
+        let result = if restored {
+            self.load_large().and_then(|_| self.index().mark_dirty())
+        } else {
+            self.large.clear();
+            self.index().reset()
+        };
+        if let Err(e) = result {
+            error!("restore keydir index failed: {:?}", e);
+            self.large.clear();
+            self.index().reset()?;
+            return Ok(false);
+        }
+
+        debug!("keydir index restored: {}", restored);
+        Ok(restored)
+    }
+
+    fn persist(&mut self, fingerprint: u64) -> io::Result<()> {
+        self.save_large()?;
+        let index = self.index();
+        index.pager.flush()?;
+        index.header.clean = true;
+        index.header.fingerprint = fingerprint;
+        index.write_header()?;
+        index.pager.index.sync_all()
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+struct DiskIter<'a> {
+    keydir: &'a DiskKeydir,
+    bucket: u64,
+    buckets: u64,
+    buffer: std::vec::IntoIter<(Vec<u8>, Metadata)>,
+}
+
+impl Iterator for DiskIter<'_> {
+    type Item = (Vec<u8>, Metadata);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buffer.next() {
+                return Some(item);
+            }
+            if self.bucket >= self.buckets {
+                return None;
+            }
+            let mut index = self.keydir.index.lock().unwrap();
+            match index.chain_records(self.bucket) {
+                Ok(records) => self.buffer = records.into_iter(),
+                Err(e) => {
+                    error!("read keydir index failed: {:?}", e);
+                    return None;
+                }
+            }
+            self.bucket += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiskKeydir;
+    use crate::store::store::{Metadata, OpKeydir};
+    use crate::OpError;
+    use std::fs::File;
+
+    fn metadata(file_id: u16, n: u64) -> Metadata {
+        Metadata {
+            file_id,
+            value_sz: n + 25,
+            value_pos: n * 100,
+            tstamp: n,
+        }
+    }
+
+    #[test]
+    fn disk_keydir_set_get_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keydir = DiskKeydir::open(dir.path(), 8).unwrap();
+        assert!(!keydir.restore(1).unwrap());
+
+        for n in 0..20_000u64 {
+            keydir
+                .set(format!("key:{}", n).as_bytes(), metadata(1, n))
+                .unwrap();
+        }
+        for n in (0..20_000u64).step_by(2) {
+            keydir.remove(format!("key:{}", n).as_bytes()).unwrap();
+        }
+        assert_eq!(10_000, keydir.len());
+
+        for n in 0..20_000u64 {
+            let result = keydir.get(format!("key:{}", n).as_bytes());
+            if n % 2 == 0 {
+                assert!(result.is_err());
+            } else {
+                assert_eq!(n * 100, result.unwrap().value_pos);
+            }
+        }
+        assert_eq!(10_000, keydir.iter().count());
+    }
+
+    #[test]
+    fn disk_keydir_active_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = vec![b'x'; 4096];
+        {
+            let mut keydir = DiskKeydir::open(dir.path(), 4).unwrap();
+            keydir.restore(7).unwrap();
+            keydir.set(b"a", metadata(1, 1)).unwrap();
+            keydir.set(b"a", metadata(0, 2)).unwrap();
+            // 归档文件中的旧值不覆盖活跃文件
+            keydir.set(b"a", metadata(2, 3)).unwrap();
+            assert_eq!(200, keydir.get(b"a").unwrap().value_pos);
+            assert_eq!(1, keydir.len());
+
+            keydir.set(&big, metadata(1, 4)).unwrap();
+            keydir.set(b"b", metadata(0, 5)).unwrap();
+            keydir.update_key(3).unwrap();
+            assert_eq!(3, keydir.get(b"b").unwrap().file_id);
+            assert_eq!(3, keydir.len());
+            keydir.persist(7).unwrap();
+        }
+
+        {
+            let mut keydir = DiskKeydir::open(dir.path(), 4).unwrap();
+            assert!(keydir.restore(7).unwrap());
+            assert_eq!(3, keydir.len());
+            assert_eq!(3, keydir.get(b"a").unwrap().file_id);
+            assert_eq!(400, keydir.get(&big).unwrap().value_pos);
+            keydir.persist(9).unwrap();
+        }
+
+        // 指纹不一致时丢弃
+        let mut keydir = DiskKeydir::open(dir.path(), 4).unwrap();
+        assert!(!keydir.restore(7).unwrap());
+        assert_eq!(0, keydir.len());
+    }
+
+    #[test]
+    fn disk_keydir_get_reports_index_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keydir = DiskKeydir::open(dir.path(), 4).unwrap();
+        keydir.restore(1).unwrap();
+        keydir.set(b"a", metadata(1, 1)).unwrap();
+        {
+            // 换成只写句柄并清空缓存，之后读取索引页失败
+            let mut index = keydir.index.lock().unwrap();
+            index.pager.flush().unwrap();
+            index.pager.cache.clear();
+            index.pager.lru.clear();
+            index.pager.index = File::create(dir.path().join("write-only")).unwrap();
+        }
+        assert!(matches!(keydir.get(b"a"), Err(OpError::Io(_))));
+    }
+}
@@ -1,18 +1,106 @@
 use super::super::entry::entry;
 use std::fs::{self, File};
 use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
-// 读写
-pub fn new(filepath: &PathBuf) -> io::Result<File> {
-    let file = fs::OpenOptions::new()
+// O_DIRECT 要求内存地址、写入位置和长度按块对齐
+pub const DIRECT_ALIGN: usize = 4096;
+
+// active file 写入句柄的打开方式
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OpenMode {
+    pub dsync: bool,  // O_DSYNC，每次写入都同步数据
+    pub direct: bool, // O_DIRECT，绕过页缓存
+}
+
+impl OpenMode {
+    #[cfg(unix)]
+    fn flags(&self) -> io::Result<i32> {
+        let mut flags = 0;
+        if self.dsync {
+            flags |= libc::O_DSYNC;
+        }
+        if self.direct {
+            #[cfg(target_os = "linux")]
+            {
+                flags |= libc::O_DIRECT;
+            }
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "O_DIRECT is only supported on linux",
+            ));
+        }
+        Ok(flags)
+    }
+}
+
+// 读写，写入由调用方指定位置，不使用追加模式
+#[cfg(unix)]
+pub fn new(filepath: &PathBuf, mode: OpenMode) -> io::Result<File> {
+    fs::OpenOptions::new()
         .read(true)
+        .write(true)
         .create(true)
-        .append(true)
-        .open(filepath);
+        .custom_flags(mode.flags()?)
+        .open(filepath)
+}
+
+#[cfg(not(unix))]
+pub fn new(filepath: &PathBuf, mode: OpenMode) -> io::Result<File> {
+    if mode != OpenMode::default() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "O_DSYNC and O_DIRECT are only supported on unix",
+        ));
+    }
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(filepath)
+}
+
+// 在 pos 处写入 buf，不改变文件位置
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    file.write_all_at(buf, pos)
+}
+
+// 其它平台没有 pwrite，写入句柄只由写入线程使用，seek 后写入
+#[cfg(not(unix))]
+pub fn write_all_at(mut file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(pos))?;
+    file.write_all(buf)
+}
+
+// 预分配空间到 size，文件大小随之变为 size，未写入的部分为 0
+pub fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    if file.metadata()?.len() < size {
+        fs2::FileExt::allocate(file, size)?;
+    }
+    Ok(())
+}
+
+// 以 O_DIRECT 写入 buf 到 pos，tail 为 pos 所在块中已写入的部分，写入后更新为新的末尾块
+pub fn write_direct(file: &File, tail: &mut Vec<u8>, buf: &[u8], pos: u64) -> io::Result<()> {
+    let start = pos - tail.len() as u64;
+    let len = tail.len() + buf.len();
+    let padded = len.div_ceil(DIRECT_ALIGN) * DIRECT_ALIGN;
+
+    // 多分配一块，从中取出按块对齐的区间，不足一块的部分补 0
+    let mut raw = vec![0u8; padded + DIRECT_ALIGN];
+    let offset = raw.as_ptr().align_offset(DIRECT_ALIGN);
+    let block = &mut raw[offset..offset + padded];
+    block[..tail.len()].copy_from_slice(tail);
+    block[tail.len()..len].copy_from_slice(buf);
+    write_all_at(file, block, start)?;
 
-    file
+    *tail = block[len - len % DIRECT_ALIGN..len].to_vec();
+    Ok(())
 }
 
 pub fn new_writer(filepath: &PathBuf) -> io::Result<io::BufWriter<File>> {
@@ -58,14 +146,18 @@ pub fn read_reader(
 }
 
 // return entry's position and size
-pub fn append(file: &Arc<RwLock<File>>, e: entry::Entry) -> (u64, u64) {
+pub fn append(file: &Arc<RwLock<File>>, e: entry::Entry) -> io::Result<(u64, u64)> {
     let mut file = file.write().unwrap();
     // let mut file = file.borrow_mut();
-    let pos = file.seek(SeekFrom::End(0)).unwrap();
+    let pos = file.seek(SeekFrom::End(0))?;
     let size = e.size() as u64;
     let buf = e.as_bytes();
-    file.write_all(&buf).unwrap();
+    if let Err(err) = file.write_all(&buf) {
+        // 写入失败时截掉不完整的 entry，避免后续写入跟在残缺数据之后
+        let _ = file.set_len(pos);
+        return Err(err);
+    }
     // file.flush().unwrap();
 
-    (pos, size)
+    Ok((pos, size))
 }
@@ -0,0 +1,74 @@
+use super::file;
+use super::loader;
+use super::metrics::Metrics;
+use super::store::{OpKeydir, StFile};
+use crate::config::Config;
+use crate::util::lock::DirLock;
+use crate::OpError;
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+// 暂存目录中的数据文件序号，按写入顺序排列
+pub(crate) fn staged_files(config: &Config) -> io::Result<Vec<u16>> {
+    let dir = config.load_dir();
+    let mut seqs = Vec::new();
+    if !dir.exists() {
+        return Ok(seqs);
+    }
+    for entry in fs::read_dir(dir)? {
+        if let Ok(seq) = entry?.file_name().to_string_lossy().parse::<u16>() {
+            seqs.push(seq);
+        }
+    }
+    seqs.sort_unstable();
+    Ok(seqs)
+}
+
+// 将暂存的数据文件和 hint 文件依次改名为新的归档文件，注册 fd 并更新 keydir，返回导入的记录数
+pub(crate) fn install_load_files<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    files: &RwLock<HashMap<u16, StFile>>,
+    metrics: &Metrics,
+) -> Result<usize, OpError> {
+    // 先在锁外解析 hint，锁内只做改名和更新 keydir
+    let mut staged = Vec::new();
+    for i in staged_files(config)? {
+        let data_path = config.get_load_filepath_by_seq(i);
+        let hint_path = config.get_load_hint_filepath_by_seq(i);
+        let partial = loader::parse_hint_file(i, &hint_path, data_path)?;
+        staged.push((i, partial));
+    }
+
+    // 替换文件期间阻止只读进程扫描目录
+    let _lock = DirLock::exclusive(&config.lock_filepath())?;
+    let mut files = files.write().unwrap();
+    let mut keydir = keydir.write().unwrap();
+    let mut count = 0;
+    for (i, mut partial) in staged {
+        let seq = config.get_next_datafile_seq();
+
+        // 先移动数据文件，中断时没有 hint 的数据文件仍可在启动时解析
+        // 暂存目录在 db_dir 中，改名到 db_dir 不跨设备复制
+        let to = config.get_filepath_in(config.data_dir(), seq);
+        fs::rename(config.get_load_filepath_by_seq(i), &to)?;
+        let hint_to = config.get_hint_filepath_in(config.data_dir(), seq);
+        fs::rename(config.get_load_hint_filepath_by_seq(i), &hint_to)?;
+        debug!("ingest staged file {} => {:?}", i, to);
+
+        Metrics::add(
+            &metrics.disk_bytes,
+            fs::metadata(&to)?.len() + fs::metadata(&hint_to)?.len(),
+        );
+        let fd = file::open_reader(&to)?;
+        files.insert(seq, Arc::new(RwLock::new(fd)));
+        partial.set_file(seq, to);
+        count += partial.apply(&mut *keydir)?;
+    }
+
+    fs::remove_dir_all(config.load_dir())?;
+    Ok(count)
+}
@@ -0,0 +1,278 @@
+use super::file;
+use super::store::{Metadata, OpKeydir};
+use crate::entry::entry::{DamagedRange, EntryFile};
+use crate::entry::hint::{Hint, HintFile};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// 每个线程最多领先合并进度的文件数，限制暂存的解析结果
+const FILES_AHEAD_PER_THREAD: usize = 2;
+
+// 启动时需要加载的归档文件
+pub(crate) struct LoadTask {
+    pub(crate) file_id: u16,
+    pub(crate) data_path: PathBuf,
+    pub(crate) hint_path: Option<PathBuf>,
+}
+
+// 单个文件解析出的索引，None 表示 key 在该文件中被删除或已过期
+pub(crate) struct PartialKeydir {
+    pub(crate) file_id: u16,
+    pub(crate) data_path: PathBuf,
+    pub(crate) entries: HashMap<Vec<u8>, Option<Metadata>>,
+    pub(crate) bytes: u64,
+    pub(crate) end: u64, // 最后一条完整 entry 之后的位置
+    pub(crate) damaged: Vec<DamagedRange>,
+}
+
+impl PartialKeydir {
+    fn new(file_id: u16, data_path: PathBuf) -> PartialKeydir {
+        PartialKeydir {
+            file_id,
+            data_path,
+            entries: HashMap::new(),
+            bytes: 0,
+            end: 0,
+            damaged: Vec::new(),
+        }
+    }
+
+    // 文件改名后更新序号和路径
+    pub(crate) fn set_file(&mut self, file_id: u16, data_path: PathBuf) {
+        self.file_id = file_id;
+        self.data_path = data_path;
+        for metadata in self.entries.values_mut().flatten() {
+            metadata.file_id = file_id;
+        }
+    }
+
+    // 按文件顺序合并到全局 keydir，返回有效 key 数量
+    pub(crate) fn apply<K: OpKeydir>(self, keydir: &mut K) -> io::Result<usize> {
+        let mut count = 0;
+        for (key, metadata) in self.entries {
+            match metadata {
+                Some(metadata) => {
+                    keydir.set(&key, metadata)?;
+                    count += 1;
+                }
+                None => keydir.remove(&key)?,
+            }
+        }
+        Ok(count)
+    }
+}
+
+pub(crate) fn parse_hint_file(
+    file_id: u16,
+    hint_path: &PathBuf,
+    data_path: PathBuf,
+) -> io::Result<PartialKeydir> {
+    let mut partial = PartialKeydir::new(file_id, data_path);
+    partial.bytes = std::fs::metadata(hint_path)?.len();
+
+    let result: Vec<Hint> = HintFile::new(file::open(hint_path)?).into();
+    for hint in result {
+        let metadata = Metadata {
+            file_id,
+            value_sz: hint.value_size,
+            value_pos: hint.value_pos,
+            tstamp: hint.timestamp,
+        };
+        partial.entries.insert(hint.key, Some(metadata));
+    }
+
+    Ok(partial)
+}
+
+// 从 offset 处开始解析数据文件
+pub(crate) fn parse_data_file(
+    file_id: u16,
+    data_path: PathBuf,
+    offset: u64,
+) -> io::Result<PartialKeydir> {
+    let mut partial = PartialKeydir::new(file_id, data_path);
+    let the_file = file::open(&partial.data_path)?;
+    partial.bytes = the_file.metadata()?.len().saturating_sub(offset);
+    partial.end = offset;
+
+    let mut entries = EntryFile::with_offset(the_file, offset);
+    for entry in entries.iter() {
+        partial.end = entry.value_pos + entry.entry.size() as u64;
+        // log replay
+        if entry.entry.is_expired() || entry.entry.is_removed() {
+            partial.entries.insert(entry.entry.key, None);
+        } else {
+            let metadata = Metadata {
+                file_id,
+                value_sz: entry.entry.size() as u64,
+                value_pos: entry.value_pos,
+                tstamp: entry.entry.timestamp,
+            };
+            partial.entries.insert(entry.entry.key, Some(metadata));
+        }
+    }
+
+    partial.damaged = entries.take_damaged();
+    Ok(partial)
+}
+
+fn parse(task: LoadTask) -> io::Result<PartialKeydir> {
+    match &task.hint_path {
+        Some(hint_path) => parse_hint_file(task.file_id, hint_path, task.data_path),
+        None => parse_data_file(task.file_id, task.data_path, 0),
+    }
+}
+
+// 多线程解析文件，按任务顺序依次回调 on_partial
+pub(crate) fn load_parallel<F>(tasks: Vec<LoadTask>, mut on_partial: F) -> io::Result<()>
+where
+    F: FnMut(PartialKeydir) -> io::Result<()>,
+{
+    if tasks.is_empty() {
+        return Ok(());
+    }
+
+    let total_files = tasks.len();
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(total_files);
+    let slots: Vec<_> = tasks
+        .into_iter()
+        .map(|t| std::sync::Mutex::new(Some(t)))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel::<(usize, io::Result<PartialKeydir>)>();
+    // 领取文件前先取得一个额度，合并一个文件后归还，已解析未合并的文件不超过 window 个
+    let window = threads * FILES_AHEAD_PER_THREAD;
+    let (credit_tx, credit_rx) = mpsc::sync_channel::<()>(window);
+    let credit_rx = std::sync::Mutex::new(credit_rx);
+
+    let start = Instant::now();
+    let mut last_log = start;
+    let (mut files, mut keys, mut bytes) = (0usize, 0usize, 0u64);
+
+    std::thread::scope(|scope| {
+        // 出错返回时释放发送端，阻塞等待额度的线程随之退出
+        let credit_tx = credit_tx;
+        for _ in 0..window {
+            credit_tx.send(()).unwrap();
+        }
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let slots = &slots;
+            let next = &next;
+            let credit_rx = &credit_rx;
+            scope.spawn(move || loop {
+                if credit_rx.lock().unwrap().recv().is_err() {
+                    break;
+                }
+                let idx = next.fetch_add(1, Ordering::SeqCst);
+                if idx >= slots.len() {
+                    break;
+                }
+                let task = slots[idx].lock().unwrap().take().unwrap();
+                if tx.send((idx, parse(task))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // 乱序完成的结果暂存，保证按文件序号合并，数量受额度限制
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (idx, result) in rx.iter() {
+            pending.insert(idx, result);
+            while let Some(result) = pending.remove(&expected) {
+                let partial = result?;
+                files += 1;
+                keys += partial.entries.len();
+                bytes += partial.bytes;
+                debug!(
+                    "加载序号 {} 文件，共找到条目 {}",
+                    partial.file_id,
+                    partial.entries.len()
+                );
+                on_partial(partial)?;
+                expected += 1;
+                let _ = credit_tx.send(());
+
+                if last_log.elapsed() >= PROGRESS_INTERVAL {
+                    last_log = Instant::now();
+                    info!(
+                        "loading {}/{} files, {} keys, {} bytes, elapsed {:?}",
+                        files,
+                        total_files,
+                        keys,
+                        bytes,
+                        start.elapsed()
+                    );
+                }
+            }
+        }
+        Ok::<(), io::Error>(())
+    })?;
+
+    info!(
+        "loaded {} files with {} threads, {} keys, {} bytes, elapsed {:?}",
+        files,
+        threads,
+        keys,
+        bytes,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_parallel, LoadTask};
+    use crate::entry::entry::Entry;
+    use crate::store::store::{Keydir, OpKeydir};
+    use std::io::Write;
+
+    #[test]
+    fn load_parallel_keeps_file_order() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tasks = Vec::new();
+        for file_id in 1..=8u16 {
+            let path = dir.path().join(format!("data.{}", file_id));
+            let mut f = std::fs::File::create(&path)?;
+            for n in 0..100 {
+                let key = format!("key{}", n).into_bytes();
+                let entry = Entry::new(key, file_id.to_string().into_bytes(), 0);
+                f.write_all(&entry.as_bytes())?;
+            }
+            // 最后一个文件删除 key0
+            if file_id == 8 {
+                let entry = Entry::new(b"key0".to_vec(), vec![], 0).set_removed();
+                f.write_all(&entry.as_bytes())?;
+            }
+            tasks.push(LoadTask {
+                file_id,
+                data_path: path,
+                hint_path: None,
+            });
+        }
+
+        let mut keydir = Keydir::new();
+        let mut order = Vec::new();
+        load_parallel(tasks, |partial| {
+            order.push(partial.file_id);
+            partial.apply(&mut keydir).map(|_| ())
+        })?;
+
+        assert_eq!((1..=8).collect::<Vec<u16>>(), order);
+        assert_eq!(99, keydir.len());
+        assert!(keydir.get(b"key0").is_err());
+        assert_eq!(8, keydir.get(b"key1").unwrap().file_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,538 @@
+use super::file;
+use super::metrics::Metrics;
+use super::store::{Metadata, OpKeydir, StFile, ACTIVE_FILE_SEQ};
+use crate::config::Config;
+use crate::entry::entry::{Entry, EntryFile};
+use crate::entry::hint::{Hint, HintFile};
+use crate::util::lock::DirLock;
+use crate::util::rate::RateLimiter;
+use crate::OpError;
+use log::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+// 每次持有 keydir 读锁比对的记录数，合并只暂存这么多条记录的 key
+const MERGE_CHUNK: usize = 4096;
+
+// 合并后的文件，新索引在替换时从合并的 hint 文件读取
+struct Merged {
+    last_seq: u16,        // 最后一个合并文件序号
+    active_file_seq: u16, // 小于该序号的归档文件都被合并
+    copied: u64,          // 复制的记录数
+}
+
+struct MergeWriter<'a> {
+    config: &'a Config,
+    seq: u16,
+    max_seq: u16, // 合并文件序号必须小于合并开始时的 active file 序号
+    data: BufWriter<File>,
+    hint: BufWriter<File>,
+    size: usize,
+}
+
+impl<'a> MergeWriter<'a> {
+    fn create(config: &'a Config, seq: u16, max_seq: u16) -> Result<MergeWriter<'a>, OpError> {
+        // 直接写在占用最少的数据目录中，替换时不跨设备复制
+        let dir = config.placement_dir();
+        fs::create_dir_all(config.merge_dir_in(dir))?;
+        let merge_filepath = config.get_merge_filepath_in(dir, seq);
+        let data = file::new_writer(&merge_filepath)?;
+        debug!("[data]create merge file: {:?}", merge_filepath);
+
+        let merge_hint_filepath = config.get_merge_hint_filepath_in(dir, seq);
+        let hint = file::new_writer(&merge_hint_filepath)?;
+        debug!("[hint]create merge hint file: {:?}", merge_hint_filepath);
+
+        Ok(MergeWriter {
+            config,
+            seq,
+            max_seq,
+            data,
+            hint,
+            size: 0,
+        })
+    }
+
+    // 写入提交记录前合并文件必须落盘
+    fn flush(&mut self) -> Result<(), OpError> {
+        self.data.flush()?;
+        self.data.get_ref().sync_data()?;
+        self.hint.flush()?;
+        self.hint.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // 写入一条 entry，返回新的索引
+    fn write(&mut self, bytes: &[u8], metadata: &Metadata) -> Result<Metadata, OpError> {
+        //  splits a new file，序号用完时写入最后一个文件，可以超过 file_max_size
+        if self.size > 0
+            && self.size + bytes.len() > self.config.file_max_size()
+            && self.seq < self.max_seq
+        {
+            self.flush()?;
+            *self = MergeWriter::create(self.config, self.seq + 1, self.max_seq)?;
+        }
+
+        let entry = Entry::try_from(bytes.to_vec()).map_err(|e| {
+            OpError::Corruption(format!(
+                "data file {} pos {}: {}",
+                metadata.file_id, metadata.value_pos, e
+            ))
+        })?;
+
+        // write merge file
+        let offset = self.size as u64;
+        self.data.write_all(bytes)?;
+
+        // write hint file
+        let hint_entry = Hint {
+            timestamp: metadata.tstamp,
+            key_size: entry.key_size,
+            value_size: entry.size() as u64, // 整个entry 大小,
+            value_pos: offset,               // 整个entry的读取位置
+            key: entry.key,
+        };
+        let hint_bytes: Vec<u8> = hint_entry.into();
+        self.hint.write_all(&hint_bytes)?;
+        self.size += bytes.len();
+
+        Ok(Metadata {
+            file_id: self.seq,           // 新 file_seq
+            value_pos: offset,           // 在新文件offset
+            value_sz: metadata.value_sz, // entry 本身大小不变
+            tstamp: metadata.tstamp,
+        })
+    }
+}
+
+// 比对一批记录，返回 keydir 仍指向该位置的 key 及其索引
+// 读取索引失败时中止合并，不能当作无效记录跳过，否则替换后索引会指向被删除的文件
+fn live_records<K: OpKeydir>(
+    keydir: &RwLock<K>,
+    file_id: u16,
+    chunk: Vec<(Vec<u8>, u64)>,
+) -> Result<Vec<(Vec<u8>, Metadata)>, OpError> {
+    let keydir = keydir.read().unwrap();
+    let mut live = Vec::new();
+    for (key, pos) in chunk {
+        match keydir.get(&key) {
+            Ok(metadata) if metadata.file_id == file_id && metadata.value_pos == pos => {
+                live.push((key, metadata))
+            }
+            Ok(_) => {}
+            Err(e) if e.is_not_found() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(live)
+}
+
+// 按文件顺序遍历归档文件，每批记录比对一次 keydir，将有效数据写入合并目录，stop 置位时中止并返回 None
+fn write_merge_files<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    files: &RwLock<HashMap<u16, StFile>>,
+    stop: &AtomicBool,
+    metrics: &Metrics,
+) -> Result<Option<Merged>, OpError> {
+    let active_file_seq = config.get_next_datafile_seq();
+    debug!("archive_file_seq= {:?}", active_file_seq);
+
+    // 跳过 active file 和合并开始后归档的文件，避免合并文件与其序号冲突
+    let mut seqs: Vec<u16> = files
+        .read()
+        .unwrap()
+        .keys()
+        .filter(|i| **i < active_file_seq)
+        .copied()
+        .collect();
+    seqs.sort_unstable();
+
+    let mut writer = MergeWriter::create(config, 1, active_file_seq - 1)?;
+    let mut copied = 0;
+    metrics
+        .merge_keys_total
+        .store(keydir.read().unwrap().len() as u64, Ordering::Relaxed);
+    metrics.merge_keys.store(0, Ordering::Relaxed);
+    let mut limiter = RateLimiter::new(config.get_compaction().rate());
+    for seq in seqs {
+        let archive_file = files
+            .read()
+            .unwrap()
+            .get(&seq)
+            .cloned()
+            .ok_or_else(|| OpError::Corruption(format!("data file {} not registered", seq)))?;
+        // crc 不符的记录也返回位置，仍被引用时读取后报告损坏
+        let mut records = EntryFile::new(file::open(&config.get_filepath_by_seq(seq))?)
+            .keep_crc_mismatch()
+            .map(|r| (r.entry.key, r.value_pos))
+            .peekable();
+        while records.peek().is_some() {
+            let chunk = records.by_ref().take(MERGE_CHUNK).collect();
+            for (key, metadata) in live_records(keydir, seq, chunk)? {
+                // 替换文件前中止合并不影响原数据文件
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                // 从原来的文件读取最新值
+                let bytes =
+                    file::read_reader(&archive_file, metadata.value_pos, metadata.value_sz)?;
+                if !limiter.consume(bytes.len() as u64, stop) {
+                    return Ok(None);
+                }
+                writer.write(&bytes, &metadata)?;
+                debug!("merge key {:?} from file {}", key, seq);
+                copied += 1;
+                Metrics::incr(&metrics.merge_keys);
+            }
+        }
+    }
+
+    // 刷新写盘
+    writer.flush()?;
+
+    Ok(Some(Merged {
+        last_seq: writer.seq,
+        active_file_seq,
+        copied,
+    }))
+}
+
+// 合并目录中的提交记录，存在时说明合并文件已完整写入，需要替换到数据目录
+const MANIFEST: &str = "MANIFEST";
+
+fn manifest_path(config: &Config) -> PathBuf {
+    config.merge_dir().join(MANIFEST)
+}
+
+// 写入提交记录：最后一个合并文件序号和合并开始时的 active file 序号
+fn write_manifest(config: &Config, last_seq: u16, active_file_seq: u16) -> io::Result<()> {
+    let path = manifest_path(config);
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    writeln!(f, "{} {}", last_seq, active_file_seq)?;
+    f.sync_all()?;
+    fs::rename(&tmp, &path)
+}
+
+fn read_manifest(config: &Config) -> io::Result<Option<(u16, u16)>> {
+    let content = match fs::read_to_string(manifest_path(config)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid merge manifest");
+    let mut fields = content.split_whitespace().map(|s| s.parse::<u16>());
+    match (fields.next(), fields.next()) {
+        (Some(Ok(last_seq)), Some(Ok(active_file_seq))) => Ok(Some((last_seq, active_file_seq))),
+        _ => Err(invalid()),
+    }
+}
+
+// 把合并文件移到所在数据目录并删除多余的旧文件，可重复执行
+// 合并文件覆盖同序号的旧文件，中途失败时重新执行即可完成替换
+fn roll_forward(config: &Config, last_seq: u16, active_file_seq: u16) -> io::Result<()> {
+    for i in 1..=last_seq {
+        let from = config.get_merge_filepath_by_seq(i);
+        if from.exists() {
+            // 合并文件所在的数据目录，其它目录中同序号的旧文件先删除，避免同一序号存在两份
+            let dir = from.parent().and_then(Path::parent).unwrap();
+            for other in config.data_dirs().into_iter().filter(|d| *d != dir) {
+                for path in [
+                    config.get_hint_filepath_in(other, i),
+                    config.get_filepath_in(other, i),
+                ] {
+                    remove_if_exists(&path)?;
+                }
+            }
+            // 同一目录内 rename 原子地替换旧文件
+            let to = config.get_filepath_in(dir, i);
+            fs::rename(&from, &to)?;
+            debug!("{:?} => {:?} File moved successfully!", from, to);
+        }
+
+        let from = config.get_merge_hint_filepath_by_seq(i);
+        if from.exists() {
+            let dir = from.parent().and_then(Path::parent).unwrap();
+            let hint_to = config.get_hint_filepath_in(dir, i);
+            fs::rename(&from, &hint_to)?;
+            debug!("{:?} => {:?} File moved successfully!", from, hint_to);
+        }
+    }
+
+    // 合并后文件数可能变少，删除序号更大的旧文件及其 hint 文件
+    for i in (last_seq + 1)..active_file_seq {
+        // hint 文件按数据文件所在目录查找，先于数据文件确定路径
+        let hint_file = config.get_hint_filepath_by_seq(i);
+        for path in [config.get_filepath_by_seq(i), hint_file] {
+            remove_if_exists(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => debug!("deleted old data file: {:?}", path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+// 合并目录中是否有已提交、尚未完成替换的合并
+pub(crate) fn is_committed(config: &Config) -> bool {
+    manifest_path(config).exists()
+}
+
+// 处理上次遗留的合并目录：已提交的合并继续完成替换并返回 true，未提交的直接丢弃
+pub(crate) fn recover(config: &Config) -> io::Result<bool> {
+    let manifest = read_manifest(config)?;
+    if let Some((last_seq, active_file_seq)) = manifest {
+        info!(
+            "finish interrupted merge, last_seq={}, active_file_seq={}",
+            last_seq, active_file_seq
+        );
+        roll_forward(config, last_seq, active_file_seq)?;
+    }
+    config.merge_cleanup();
+    Ok(manifest.is_some())
+}
+
+// 文件大小，不存在时为 0
+fn file_len(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+// 用合并文件替换旧的归档文件并更新索引
+fn install_merge_files<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    files: &RwLock<HashMap<u16, StFile>>,
+    merged: Merged,
+    metrics: &Metrics,
+) -> Result<(), OpError> {
+    if merged.copied == 0 {
+        return Ok(());
+    }
+
+    // 替换文件期间阻止只读进程扫描目录
+    let _lock = DirLock::exclusive(&config.lock_filepath())?;
+    let mut files = files.write().unwrap();
+
+    // 删除和合并生成的数据、hint 文件大小
+    let (mut removed, mut added) = (0u64, 0u64);
+    let (mut old_data, mut new_data) = (0u64, 0u64);
+    let merged_seqs: Vec<u16> = files
+        .keys()
+        .filter(|i| **i < merged.active_file_seq)
+        .copied()
+        .collect();
+    for &i in &merged_seqs {
+        let len = file_len(&config.get_filepath_by_seq(i));
+        old_data += len;
+        removed += len + file_len(&config.get_hint_filepath_by_seq(i));
+    }
+    for i in 1..=merged.last_seq {
+        let len = file_len(&config.get_merge_filepath_by_seq(i));
+        new_data += len;
+        added += len + file_len(&config.get_merge_hint_filepath_by_seq(i));
+    }
+
+    // 提交记录写入前失败不影响原数据文件
+    write_manifest(config, merged.last_seq, merged.active_file_seq)?;
+
+    // 替换到一半失败时数据目录与内存索引不一致，拒绝写入，重启后继续完成替换
+    // 已打开的旧文件句柄仍可读取，索引和文件句柄保持不变
+    let fail = |e: io::Error| {
+        metrics.enter_readonly(format!("merge install failed: {}", e));
+        OpError::from(e)
+    };
+    metrics.begin_replace();
+    roll_forward(config, merged.last_seq, merged.active_file_seq).map_err(fail)?;
+
+    // 重新打开所有文件句柄，并注册[file_id:fd]
+    let mut readers = Vec::with_capacity(merged.last_seq as usize);
+    for i in 1..=merged.last_seq {
+        let fd = file::open_reader(&config.get_filepath_by_seq(i)).map_err(fail)?;
+        readers.push((i, fd));
+    }
+    for i in merged_seqs {
+        files.remove(&i);
+    }
+    // 合并期间归档的文件仍未合并
+    let unmerged = files.len() as u64;
+    for (i, fd) in readers {
+        files.insert(i, Arc::new(RwLock::new(fd)));
+    }
+
+    // update keydir index，合并期间被更新或删除的 key 保留新值
+    // key 只会改为指向合并开始后的新文件或被删除，仍指向被合并文件的 key 在遍历时都已复制
+    let is_merged =
+        |m: &Metadata| m.file_id != ACTIVE_FILE_SEQ && m.file_id < merged.active_file_seq;
+    let mut keydir = keydir.write().unwrap();
+    for i in 1..=merged.last_seq {
+        let hint_file = file::open(&config.get_hint_filepath_by_seq(i)).map_err(fail)?;
+        for hint in HintFile::new(hint_file) {
+            let current = match keydir.get(&hint.key) {
+                Ok(metadata) => Some(metadata),
+                Err(OpError::Io(e)) => return Err(fail(e)),
+                Err(_) => None,
+            };
+            if current.is_some_and(|m| is_merged(&m)) {
+                let metadata = Metadata {
+                    file_id: i,
+                    value_sz: hint.value_size,
+                    value_pos: hint.value_pos,
+                    tstamp: hint.timestamp,
+                };
+                keydir.set(&hint.key, metadata).map_err(fail)?;
+            }
+        }
+    }
+    metrics.end_replace();
+
+    // 释放锁之前删除提交记录，其它合并请求据此判断替换是否失败
+    fs::remove_file(manifest_path(config))?;
+
+    metrics.unmerged_files.store(unmerged, Ordering::Relaxed);
+    Metrics::sub(&metrics.dead_bytes, old_data.saturating_sub(new_data));
+    Metrics::add(&metrics.disk_bytes, added);
+    Metrics::sub(&metrics.disk_bytes, removed);
+    Ok(())
+}
+
+// 合并归档数据文件，合并线程与 Op::compaction 共用
+pub(crate) fn merge<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    files: &RwLock<HashMap<u16, StFile>>,
+    stop: &AtomicBool,
+    metrics: &Metrics,
+) -> Result<(), OpError> {
+    debug!("\n\n=== COMPACTION BEGIN ===");
+
+    // 创建临时合并目录，已存在说明正在合并，同时开始的合并只有一个能创建成功
+    let merge_dir = config.merge_dir();
+    match fs::create_dir(&merge_dir) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            // 替换期间持有 files 写锁，替换结束后提交记录仍在说明上次替换失败，重启后才能完成
+            let _files = files.read().unwrap();
+            if is_committed(config) {
+                return Err(OpError::Io(io::Error::other(
+                    "previous merge install failed, restart to finish it",
+                )));
+            }
+            debug!("当前已处于工作状态 {:?}", merge_dir);
+            return Ok(());
+        }
+        result => result?,
+    }
+
+    match write_merge_files(config, keydir, files, stop, metrics) {
+        Ok(Some(merged)) => {
+            // 写入提交记录后失败时保留合并目录，重启时继续完成替换
+            if let Err(e) = install_merge_files(config, keydir, files, merged, metrics) {
+                if !is_committed(config) {
+                    let _ = config.remove_merge_dirs();
+                }
+                return Err(e);
+            }
+            Metrics::incr(&metrics.merges);
+        }
+        Ok(None) => info!("merge aborted by shutdown"),
+        Err(e) => {
+            let _ = config.remove_merge_dirs();
+            return Err(e);
+        }
+    }
+
+    // 删除临时合并文件
+    config.remove_merge_dirs()?;
+
+    debug!("\n=== COMPACTION END ===\n");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recover, write_manifest};
+    use crate::config::{test_config, Config};
+    use std::fs;
+
+    #[test]
+    fn recover_finishes_committed_merge() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config::for_dir(dir.path());
+        for i in 1..=3u16 {
+            fs::write(config.get_filepath_by_seq(i), format!("old{}", i))?;
+            fs::write(config.get_hint_filepath_by_seq(i), "hint")?;
+        }
+        // 第一个合并文件已替换，第二个还在合并目录
+        fs::create_dir(config.merge_dir())?;
+        fs::write(config.get_filepath_by_seq(1), "new1")?;
+        fs::write(config.get_merge_filepath_by_seq(2), "new2")?;
+        fs::write(config.get_merge_hint_filepath_by_seq(2), "newhint")?;
+        write_manifest(&config, 2, 4)?;
+
+        assert!(recover(&config)?);
+        assert_eq!(fs::read_to_string(config.get_filepath_by_seq(1))?, "new1");
+        assert_eq!(fs::read_to_string(config.get_filepath_by_seq(2))?, "new2");
+        assert_eq!(
+            fs::read_to_string(config.get_hint_filepath_by_seq(2))?,
+            "newhint"
+        );
+        assert!(!config.get_filepath_by_seq(3).exists());
+        assert!(!config.get_hint_filepath_by_seq(3).exists());
+        assert!(!config.merge_dir().exists());
+
+        // 未提交的合并直接丢弃
+        fs::create_dir(config.merge_dir())?;
+        fs::write(config.get_merge_filepath_by_seq(1), "partial")?;
+        assert!(!recover(&config)?);
+        assert_eq!(fs::read_to_string(config.get_filepath_by_seq(1))?, "new1");
+        assert!(!config.merge_dir().exists());
+        Ok(())
+    }
+
+    #[test]
+    fn recover_moves_merge_files_within_their_dir() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (db, disk) = (dir.path().join("db"), dir.path().join("disk1"));
+        let extra = format!("data_dirs = [{:?}]\n", disk.to_str().unwrap());
+        let config = test_config(&db, &extra)?;
+        for i in 1..=2u16 {
+            fs::write(config.get_filepath_in(&db, i), format!("old{}", i))?;
+            fs::write(config.get_hint_filepath_in(&db, i), "hint")?;
+        }
+        // 合并文件写在另一个目录，上次替换时已删除部分旧文件
+        fs::create_dir(config.merge_dir())?;
+        fs::create_dir(config.merge_dir_in(&disk))?;
+        fs::remove_file(config.get_filepath_in(&db, 1))?;
+        fs::write(config.get_merge_filepath_in(&disk, 1), "new1")?;
+        fs::write(config.get_merge_hint_filepath_in(&disk, 1), "newhint")?;
+        write_manifest(&config, 1, 3)?;
+
+        assert!(recover(&config)?);
+        assert_eq!(
+            fs::read_to_string(config.get_filepath_in(&disk, 1))?,
+            "new1"
+        );
+        assert_eq!(
+            fs::read_to_string(config.get_hint_filepath_in(&disk, 1))?,
+            "newhint"
+        );
+        // 同一序号只保留一份
+        for i in 1..=2u16 {
+            assert!(!config.get_filepath_in(&db, i).exists());
+            assert!(!config.get_hint_filepath_in(&db, i).exists());
+        }
+        assert!(!config.merge_dir().exists());
+        assert!(!config.merge_dir_in(&disk).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// 存储运行指标，INFO 命令输出
+#[derive(Default)]
+pub struct Metrics {
+    pub writes: AtomicU64,                  // 成功写入次数（含删除）
+    pub write_errors: AtomicU64,            // 写入失败次数
+    pub write_batches: AtomicU64,           // 写入线程提交的批次数
+    pub readonly_events: AtomicU64,         // 进入只读状态的次数
+    pub scrub_passes: AtomicU64,            // 完成的校验轮数
+    pub scrub_bytes: AtomicU64,             // 已校验的字节数
+    pub scrub_corrupt_records: AtomicU64,   // 发现的损坏记录数
+    pub scrub_keydir_mismatches: AtomicU64, // 与记录不一致的索引数
+    pub scrub_quarantined_keys: AtomicU64,  // 被隔离的 key 数
+    pub scrub_last_pass: AtomicU64,         // 最近一轮校验完成的时间戳
+    pub merges: AtomicU64,                  // 完成的合并次数
+    pub merge_keys: AtomicU64,              // 当前（或最近一次）合并已处理的 key 数
+    pub merge_keys_total: AtomicU64,        // 当前（或最近一次）合并开始时的 key 总数
+    pub unmerged_files: AtomicU64,          // 上次合并后新归档的文件数
+    pub dead_bytes: AtomicU64,              // 归档文件中被覆盖或删除的数据大小
+    pub write_slowdowns: AtomicU64,         // 因合并落后被减慢的写入批次数
+    pub write_stalls: AtomicU64,            // 因合并落后被暂停的写入批次数
+    pub disk_bytes: AtomicU64, // 数据和 hint 文件占用的空间，active file 按已写入大小计
+    pub quota_rejections: AtomicU64, // 超出 max_disk_bytes 被拒绝的写入数
+    readonly: AtomicBool,
+    readonly_reason: Mutex<Option<String>>,
+    replace_generation: AtomicU64, // 合并替换数据文件时加一，替换期间为奇数
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly.load(Ordering::SeqCst)
+    }
+
+    pub fn readonly_reason(&self) -> Option<String> {
+        self.readonly_reason.lock().unwrap().clone()
+    }
+
+    pub(crate) fn enter_readonly(&self, reason: String) {
+        *self.readonly_reason.lock().unwrap() = Some(reason);
+        if !self.readonly.swap(true, Ordering::SeqCst) {
+            self.readonly_events.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn leave_readonly(&self) {
+        *self.readonly_reason.lock().unwrap() = None;
+        self.readonly.store(false, Ordering::SeqCst);
+    }
+
+    // 合并开始替换数据文件，替换失败时不结束，直到重启前都视为正在替换
+    pub(crate) fn begin_replace(&self) {
+        self.replace_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // 数据文件和索引都已替换完成
+    pub(crate) fn end_replace(&self) {
+        self.replace_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn replace_generation(&self) -> u64 {
+        self.replace_generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub(counter: &AtomicU64, n: u64) {
+        let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+            Some(v.saturating_sub(n))
+        });
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readonly_transition_counts_once() {
+        let metrics = Metrics::new();
+        metrics.enter_readonly("No space left on device".to_string());
+        metrics.enter_readonly("No space left on device".to_string());
+        assert!(metrics.is_readonly());
+        assert_eq!(Metrics::get(&metrics.readonly_events), 1);
+
+        metrics.leave_readonly();
+        assert!(!metrics.is_readonly());
+        assert_eq!(metrics.readonly_reason(), None);
+    }
+}
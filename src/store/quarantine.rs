@@ -0,0 +1,101 @@
+use crate::config::Config;
+use crate::entry::entry::DamagedRange;
+use crate::util;
+use chrono::Utc;
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const REPORT: &str = "report.log";
+
+// 记录损坏区间及可能丢失的 key，便于从其它地方恢复
+pub(crate) fn log_damaged(data_path: &Path, ranges: &[DamagedRange]) {
+    for range in ranges {
+        let key = range
+            .key
+            .as_ref()
+            .map(|k| util::format_bytes_as_str(k))
+            .unwrap_or_else(|| "<unknown>".to_string());
+        error!(
+            "damaged range in {:?} at {} ({} bytes), possibly lost key {:?}",
+            data_path, range.offset, range.len, key
+        );
+    }
+}
+
+// 将损坏的数据区间复制到 quarantine 目录，并在 report.log 中追加一行记录
+// 同一区间已隔离过时跳过，返回新隔离的区间数量
+pub(crate) fn save(
+    config: &Config,
+    data_path: &Path,
+    ranges: &[DamagedRange],
+) -> io::Result<usize> {
+    if ranges.is_empty() {
+        return Ok(0);
+    }
+
+    let dir = config.quarantine_dir();
+    fs::create_dir_all(&dir)?;
+    let name = data_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut saved = 0;
+    let mut source = File::open(data_path)?;
+    for range in ranges {
+        let target = dir.join(format!("{}-{}.bin", name, range.offset));
+        if target.exists() {
+            continue;
+        }
+
+        source.seek(SeekFrom::Start(range.offset))?;
+        let mut out = File::create(&target)?;
+        io::copy(&mut (&mut source).take(range.len), &mut out)?;
+        out.sync_all()?;
+
+        let mut report = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(REPORT))?;
+        writeln!(
+            report,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            Utc::now().to_rfc3339(),
+            data_path.display(),
+            range.offset,
+            range.len,
+            target.display(),
+            range
+                .key
+                .as_ref()
+                .map(|k| util::format_bytes_as_str(k))
+                .unwrap_or_default()
+        )?;
+        saved += 1;
+    }
+
+    if saved > 0 {
+        warn!(
+            "{} damaged ranges of {:?} saved to {:?}",
+            saved, data_path, dir
+        );
+    }
+    Ok(saved)
+}
+
+// 处理加载数据文件时跳过的损坏区间，只读打开时不写入任何文件
+pub(crate) fn handle(config: &Config, data_path: &Path, ranges: &[DamagedRange], read_only: bool) {
+    if read_only {
+        // 末尾不完整的记录可能正在被其它进程写入
+        let ranges: Vec<_> = ranges.iter().filter(|r| !r.tail).cloned().collect();
+        log_damaged(data_path, &ranges);
+        return;
+    }
+
+    log_damaged(data_path, ranges);
+    if let Err(e) = save(config, data_path, ranges) {
+        error!("quarantine damaged ranges of {:?} failed: {}", data_path, e);
+    }
+}
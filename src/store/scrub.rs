@@ -0,0 +1,363 @@
+use super::metrics::Metrics;
+use super::quarantine;
+use super::store::{Metadata, OpKeydir};
+use crate::config::{Config, ScrubAction};
+use crate::entry::entry::{DamagedRange, Entry, RecordError};
+use crate::util;
+use crate::util::rate::{sleep_unless_stopped, RateLimiter};
+use chrono::Utc;
+use log::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+
+// 单条记录的校验结果
+struct Record {
+    key: Option<Vec<u8>>, // 无法解析时为 None
+    size: u64,
+    valid: bool,
+}
+
+// 单个数据文件的校验结果
+struct FileScan {
+    len: u64,
+    bytes: u64,
+    records: HashMap<u64, Record>, // 记录位置 => 校验结果
+    truncated: Option<u64>,        // 从该位置起无法解析记录
+}
+
+impl FileScan {
+    fn corrupt_records(&self) -> impl Iterator<Item = (&u64, &Record)> {
+        self.records.iter().filter(|(_, r)| !r.valid)
+    }
+
+    fn damaged_ranges(&self) -> Vec<DamagedRange> {
+        let mut ranges: Vec<_> = self
+            .corrupt_records()
+            .map(|(pos, record)| DamagedRange {
+                offset: *pos,
+                len: record.size,
+                tail: false,
+                key: None,
+                reason: RecordError::CrcMismatch,
+            })
+            .collect();
+        if let Some(pos) = self.truncated {
+            ranges.push(DamagedRange {
+                offset: pos,
+                len: self.len - pos,
+                tail: true,
+                key: None,
+                reason: RecordError::TruncatedRecord,
+            });
+        }
+        ranges
+    }
+}
+
+// 逐条读取记录并校验 crc，返回 None 表示被中止
+fn scan_file(
+    path: &Path,
+    limiter: &mut RateLimiter,
+    stop: &AtomicBool,
+) -> io::Result<Option<FileScan>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let header_size = Entry::default().header_size() as u64;
+
+    let mut scan = FileScan {
+        len,
+        bytes: 0,
+        records: HashMap::new(),
+        truncated: None,
+    };
+    let mut pos = 0;
+    while pos < len {
+        if stop.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if pos + header_size > len {
+            scan.truncated = Some(pos);
+            break;
+        }
+
+        let mut bytes = vec![0; header_size as usize];
+        reader.read_exact(&mut bytes)?;
+        let key_size = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as u64;
+        let value_size = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        // 长度字段损坏时无法定位下一条记录
+        let size = match (header_size + key_size).checked_add(value_size) {
+            Some(size) if pos + size <= len => size,
+            _ => {
+                scan.truncated = Some(pos);
+                break;
+            }
+        };
+
+        bytes.resize(size as usize, 0);
+        reader.read_exact(&mut bytes[header_size as usize..])?;
+        let record = match Entry::try_from(bytes) {
+            Ok(entry) => Record {
+                valid: entry.is_valid(),
+                key: Some(entry.key),
+                size,
+            },
+            Err(_) => Record {
+                key: None,
+                size,
+                valid: false,
+            },
+        };
+        scan.records.insert(pos, record);
+        scan.bytes += size;
+        pos += size;
+
+        if !limiter.consume(size, stop) {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(scan))
+}
+
+// 每次持有 keydir 读锁查找的 key 数，避免长时间阻塞写入
+const CROSS_CHECK_CHUNK: usize = 4096;
+
+// 按记录中的 key 分批查找 keydir，返回指向损坏记录的 key 和位置不一致的 key
+// 校验开始后合并替换过数据文件时索引已指向新文件，返回 None
+#[allow(clippy::type_complexity)]
+fn cross_check<K: OpKeydir>(
+    keydir: &RwLock<K>,
+    metrics: &Metrics,
+    generation: u64,
+    file_id: u16,
+    scan: &FileScan,
+) -> Option<(Vec<(Vec<u8>, Metadata)>, Vec<Vec<u8>>)> {
+    let mut damaged = Vec::new();
+    let mut mismatched = Vec::new();
+    let records: Vec<_> = scan
+        .records
+        .iter()
+        .filter_map(|(pos, r)| r.key.as_ref().map(|key| (*pos, key, r)))
+        .collect();
+    for chunk in records.chunks(CROSS_CHECK_CHUNK) {
+        // 合并持有 keydir 写锁更新索引后才结束替换
+        let keydir = keydir.read().unwrap();
+        if generation % 2 == 1 || metrics.replace_generation() != generation {
+            return None;
+        }
+        for (pos, key, record) in chunk {
+            let metadata = match keydir.get(key) {
+                Ok(metadata) if metadata.file_id == file_id && metadata.value_pos == *pos => {
+                    metadata
+                }
+                Ok(_) => continue,
+                Err(e) if e.is_not_found() => continue,
+                Err(e) => {
+                    warn!("scrub: look up key in keydir failed: {:?}", e);
+                    return None;
+                }
+            };
+            if !record.valid {
+                damaged.push((key.to_vec(), metadata));
+            } else if record.size != metadata.value_sz {
+                mismatched.push(key.to_vec());
+            }
+        }
+    }
+    Some((damaged, mismatched))
+}
+
+// 比对一个文件并按配置隔离损坏的 key，返回位置不一致的 key 数
+fn check_file<K: OpKeydir>(
+    keydir: &RwLock<K>,
+    metrics: &Metrics,
+    action: ScrubAction,
+    generation: u64,
+    file_id: u16,
+    path: &Path,
+    scan: &FileScan,
+) -> usize {
+    let Some((damaged, mismatched)) = cross_check(keydir, metrics, generation, file_id, scan)
+    else {
+        debug!("scrub: data files replaced during scan, skip keydir check");
+        return 0;
+    };
+    for key in &mismatched {
+        error!(
+            "scrub: keydir entry of key {:?} does not match record in {:?}",
+            util::format_bytes_as_str(key),
+            path
+        );
+    }
+    for (key, metadata) in &damaged {
+        error!(
+            "scrub: key {:?} points to corrupt record in {:?} at {}",
+            util::format_bytes_as_str(key),
+            path,
+            metadata.value_pos
+        );
+    }
+
+    // 隔离：移除仍指向损坏记录的索引，key 重新写入后恢复
+    if action == ScrubAction::Quarantine && !damaged.is_empty() {
+        let mut keydir = keydir.write().unwrap();
+        for (key, metadata) in damaged {
+            if keydir.get(&key).is_ok_and(|m| m == metadata) {
+                if let Err(e) = keydir.remove(&key) {
+                    error!("scrub: quarantine key failed: {:?}", e);
+                    break;
+                }
+                Metrics::incr(&metrics.scrub_quarantined_keys);
+            }
+        }
+    }
+    mismatched.len()
+}
+
+// 校验一遍所有归档文件，返回 false 表示被中止
+pub(crate) fn scrub_pass<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    metrics: &Metrics,
+    stop: &AtomicBool,
+) -> bool {
+    let action = config.get_scrub().action();
+    let mut limiter = RateLimiter::new(config.get_scrub().rate());
+    let start = Instant::now();
+    let (mut corrupt, mut mismatched_keys) = (0, 0);
+
+    for file_id in 1..config.get_next_datafile_seq() {
+        let path = config.get_filepath_by_seq(file_id);
+        if !path.exists() {
+            continue;
+        }
+        let generation = metrics.replace_generation();
+        let scan = match scan_file(&path, &mut limiter, stop) {
+            Ok(Some(scan)) => scan,
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("scrub {:?} failed: {}", path, e);
+                continue;
+            }
+        };
+        Metrics::add(&metrics.scrub_bytes, scan.bytes);
+
+        for (pos, record) in scan.corrupt_records() {
+            error!(
+                "scrub: corrupt record in {:?} at {} ({} bytes)",
+                path, pos, record.size
+            );
+            corrupt += 1;
+        }
+        if let Some(pos) = scan.truncated {
+            error!("scrub: {:?} is unreadable from offset {}", path, pos);
+            corrupt += 1;
+        }
+        if action == ScrubAction::Quarantine {
+            if let Err(e) = quarantine::save(config, &path, &scan.damaged_ranges()) {
+                error!(
+                    "scrub: quarantine damaged ranges of {:?} failed: {}",
+                    path, e
+                );
+            }
+        }
+
+        mismatched_keys += check_file(keydir, metrics, action, generation, file_id, &path, &scan);
+    }
+
+    Metrics::add(&metrics.scrub_corrupt_records, corrupt);
+    Metrics::add(&metrics.scrub_keydir_mismatches, mismatched_keys as u64);
+    Metrics::incr(&metrics.scrub_passes);
+    metrics
+        .scrub_last_pass
+        .store(Utc::now().timestamp() as u64, Ordering::Relaxed);
+    info!(
+        "scrub pass finished in {:?}, {} corrupt records, {} mismatched keys",
+        start.elapsed(),
+        corrupt,
+        mismatched_keys
+    );
+    true
+}
+
+// 后台校验线程，每隔 interval 校验一遍
+pub(crate) fn run<K: OpKeydir>(
+    config: &Config,
+    keydir: &RwLock<K>,
+    metrics: &Metrics,
+    stop: &AtomicBool,
+) {
+    let scrub = config.get_scrub();
+    info!(
+        "scrubber started, rate {} bytes/s, interval {:?}",
+        scrub.rate(),
+        scrub.interval()
+    );
+    while scrub_pass(config, keydir, metrics, stop) {
+        if !sleep_unless_stopped(scrub.interval(), stop) {
+            break;
+        }
+    }
+    info!("scrubber stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::store::loader;
+    use crate::store::store::{new_store, Keydir, Op};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn scrub_quarantines_corrupt_records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [scrub]\nrate = 0\naction = \"quarantine\"\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for i in 0..20 {
+                store.set(format!("key{:02}", i).as_bytes(), b"value", 0)?;
+            }
+            store.close();
+        }
+
+        let path = config.get_filepath_by_seq(1);
+        let keydir = RwLock::new(Keydir::new());
+        loader::parse_data_file(1, path.clone(), 0)?.apply(&mut *keydir.write().unwrap())?;
+        let total = keydir.read().unwrap().len();
+
+        // 修改第一条记录的最后一个字节
+        let first = keydir.read().unwrap().get(b"key00")?;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(first.value_pos + first.value_sz - 1))?;
+        file.write_all(b"X")?;
+
+        let metrics = Metrics::new();
+        assert!(scrub_pass(
+            &config,
+            &keydir,
+            &metrics,
+            &AtomicBool::new(false)
+        ));
+        assert_eq!(Metrics::get(&metrics.scrub_passes), 1);
+        assert_eq!(Metrics::get(&metrics.scrub_corrupt_records), 1);
+        assert_eq!(Metrics::get(&metrics.scrub_keydir_mismatches), 0);
+        assert_eq!(Metrics::get(&metrics.scrub_quarantined_keys), 1);
+        assert!(keydir.read().unwrap().get(b"key00").is_err());
+        assert_eq!(keydir.read().unwrap().len(), total - 1);
+        assert!(config.quarantine_dir().join("data.1-0.bin").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use super::store::Metadata;
+use crc32fast::Hasher;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+// magic(8) | version(4) | active_len(8) | fingerprint(8) | count(8) | records | crc(4)
+// record: key_size(4) | key | file_id(2) | value_sz(8) | value_pos(8) | tstamp(8)
+const MAGIC: &[u8; 8] = b"MINKVSNP";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4 + 8 + 8 + 8;
+const RECORD_FIXED_SIZE: usize = 4 + 2 + 8 + 8 + 8;
+
+// 正常关闭时保存的 keydir 快照
+pub(crate) struct Snapshot {
+    pub(crate) active_len: u64,  // 快照时 active file 的长度
+    pub(crate) fingerprint: u64, // 快照时归档数据文件的指纹
+    pub(crate) entries: Vec<(Vec<u8>, Metadata)>,
+}
+
+// 边写边计算 crc
+struct CrcWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// 先写临时文件再 rename，避免留下半个快照
+pub(crate) fn write<I>(
+    path: &Path,
+    active_len: u64,
+    fingerprint: u64,
+    count: usize,
+    entries: I,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = (Vec<u8>, Metadata)>,
+{
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)?;
+    let mut w = CrcWriter {
+        inner: BufWriter::new(file),
+        hasher: Hasher::new(),
+    };
+
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&active_len.to_le_bytes())?;
+    w.write_all(&fingerprint.to_le_bytes())?;
+    w.write_all(&(count as u64).to_le_bytes())?;
+
+    let mut written = 0;
+    for (key, metadata) in entries {
+        w.write_all(&(key.len() as u32).to_le_bytes())?;
+        w.write_all(&key)?;
+        w.write_all(&metadata.file_id.to_le_bytes())?;
+        w.write_all(&metadata.value_sz.to_le_bytes())?;
+        w.write_all(&metadata.value_pos.to_le_bytes())?;
+        w.write_all(&metadata.tstamp.to_le_bytes())?;
+        written += 1;
+    }
+    if written != count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("snapshot expect {} entries, got {}", count, written),
+        ));
+    }
+
+    let CrcWriter { mut inner, hasher } = w;
+    let crc = hasher.finalize();
+    inner.write_all(&crc.to_le_bytes())?;
+    let file = inner.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u64(buf: &[u8], pos: &mut usize) -> u64 {
+    let v = u64::from_le_bytes(buf[*pos..*pos + 8].try_into().unwrap());
+    *pos += 8;
+    v
+}
+
+// 读取并校验快照，格式或 crc 不对时返回 InvalidData
+pub(crate) fn read(path: &Path) -> io::Result<Snapshot> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < HEADER_SIZE + 4 {
+        return Err(invalid("snapshot too short"));
+    }
+
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    let mut hasher = Hasher::new();
+    hasher.update(body);
+    if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid("snapshot crc mismatch"));
+    }
+    if &body[..8] != MAGIC {
+        return Err(invalid("snapshot bad magic"));
+    }
+    if u32::from_le_bytes(body[8..12].try_into().unwrap()) != VERSION {
+        return Err(invalid("snapshot unsupported version"));
+    }
+
+    let mut pos = 12;
+    let active_len = read_u64(body, &mut pos);
+    let fingerprint = read_u64(body, &mut pos);
+    let count = read_u64(body, &mut pos) as usize;
+
+    let mut entries = Vec::with_capacity(count.min(body.len() / RECORD_FIXED_SIZE));
+    for _ in 0..count {
+        if pos + 4 > body.len() {
+            return Err(invalid("snapshot truncated"));
+        }
+        let key_size = u32::from_le_bytes(body[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        if pos + key_size + RECORD_FIXED_SIZE - 4 > body.len() {
+            return Err(invalid("snapshot truncated"));
+        }
+        let key = body[pos..pos + key_size].to_vec();
+        pos += key_size;
+        let file_id = u16::from_le_bytes(body[pos..pos + 2].try_into().unwrap());
+        pos += 2;
+        let metadata = Metadata {
+            file_id,
+            value_sz: read_u64(body, &mut pos),
+            value_pos: read_u64(body, &mut pos),
+            tstamp: read_u64(body, &mut pos),
+        };
+        entries.push((key, metadata));
+    }
+    if pos != body.len() {
+        return Err(invalid("snapshot has trailing bytes"));
+    }
+
+    Ok(Snapshot {
+        active_len,
+        fingerprint,
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write};
+    use crate::store::store::Metadata;
+
+    fn entries() -> Vec<(Vec<u8>, Metadata)> {
+        (0..100u64)
+            .map(|n| {
+                let metadata = Metadata {
+                    file_id: (n % 3) as u16,
+                    value_sz: n + 30,
+                    value_pos: n * 64,
+                    tstamp: n,
+                };
+                (format!("key{}", n).into_bytes(), metadata)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_roundtrip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("snapshot");
+        write(&path, 4096, 42, 100, entries())?;
+
+        let snapshot = read(&path)?;
+        assert_eq!(4096, snapshot.active_len);
+        assert_eq!(42, snapshot.fingerprint);
+        assert_eq!(100, snapshot.entries.len());
+        assert_eq!(b"key7".to_vec(), snapshot.entries[7].0);
+        assert_eq!(7 * 64, snapshot.entries[7].1.value_pos);
+        assert_eq!(1, snapshot.entries[7].1.file_id);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_detects_corruption() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("snapshot");
+        write(&path, 0, 0, 100, entries())?;
+
+        let mut bytes = std::fs::read(&path)?;
+        bytes[100] ^= 0xff;
+        std::fs::write(&path, &bytes)?;
+        assert!(read(&path).is_err());
+
+        bytes.truncate(50);
+        std::fs::write(&path, &bytes)?;
+        assert!(read(&path).is_err());
+        Ok(())
+    }
+}
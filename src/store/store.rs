@@ -1,34 +1,59 @@
+use super::compact::CompactKeydir;
+use super::disk::DiskKeydir;
 use super::file;
-use crate::config::{self, Config};
-use crate::entry::entry::{self, Entry, EntryFile, EntryParseResult};
-use crate::entry::hint::{Hint, HintFile};
-use crate::util::lock;
+use super::ingest;
+use super::loader::{self, LoadTask};
+use super::merge;
+use super::metrics::Metrics;
+use super::quarantine;
+use super::scrub;
+use super::snapshot;
+use super::writer::{ActiveLog, Submitted, Writer};
+use crate::config::{self, Config, KeydirKind};
+use crate::entry::entry::{self, Entry};
+use crate::util;
+use crate::util::lock::{DirLock, OwnerLock};
 use crate::OpError;
-use chrono::Utc;
 use log::*;
-use std::collections::hash_map::Iter;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufReader, Write};
+use std::io::{self, BufReader, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
 
 pub trait Op: Send + Sync + 'static {
     fn get(&self, key: &[u8]) -> Result<Vec<u8>, OpError>;
     fn get_entry(&self, key: &[u8]) -> Result<Entry, OpError>;
-    fn set(&mut self, key: &[u8], value: &[u8], timestamp: u64);
-    fn delete(&mut self, key: &[u8]);
+    fn set(&self, key: &[u8], value: &[u8], timestamp: u64) -> Result<(), OpError>;
+    fn delete(&self, key: &[u8]) -> Result<(), OpError>;
+    // 交给写入线程后立即返回，服务在异步任务中等待写入完成
+    fn submit_set(&self, key: &[u8], value: &[u8], timestamp: u64)
+        -> Result<PendingWrite, OpError>;
+    fn submit_delete(&self, key: &[u8]) -> Result<PendingWrite, OpError>;
+    // 等待之前提交的写入更新 keydir，读改写的命令持有写锁后在读取前调用
+    fn flush_writes(&self) -> Result<(), OpError>;
     fn len(&self) -> usize;
     fn is_empty(&self) -> bool;
     fn keys(&self) -> Vec<Vec<u8>>;
-    fn compaction(&mut self);
+    fn compaction(&self) -> Result<(), OpError>;
+    fn ingest(&mut self) -> Result<usize, OpError>;
+    fn memory_usage(&self) -> usize;
+    fn metrics(&self) -> &Metrics;
+    // 尝试从只读状态恢复写入
+    fn set_writable(&mut self) -> Result<(), OpError>;
+    // 只读打开时读取其它进程新写入的数据
+    fn refresh(&mut self) -> Result<(), OpError>;
+    fn close(&mut self);
 }
 
-const ACTIVE_FILE_SEQ: u16 = 0;
-type StFile = Arc<RwLock<BufReader<File>>>; // storage File
+pub(crate) const ACTIVE_FILE_SEQ: u16 = 0;
+const PROBE_FILE: &str = ".probe";
+const PROBE_SIZE: usize = 64 * 1024;
+pub(crate) type StFile = Arc<RwLock<BufReader<File>>>; // storage File
 type ReaderFile = Arc<RwLock<File>>;
 type NotifyResult = i32;
 
@@ -41,27 +66,120 @@ where
     config: Arc<config::Config>,
     keydir: Arc<RwLock<K>>,
     files: Arc<RwLock<HashMap<u16, StFile>>>,
-    file_size: AtomicUsize, // 当前写入文件大小
-    merge_file_num: AtomicUsize,
-    updated_key_num: AtomicUsize, // 更新key数量
+    writer: Option<Writer>, // 写入线程，只读模式为 None
     sender: mpsc::Sender<NotifyResult>,
     receiver: Arc<Mutex<mpsc::Receiver<NotifyResult>>>,
+    merge_stop: Arc<AtomicBool>, // 通知合并、校验线程退出
+    merge_handle: Option<JoinHandle<()>>,
+    scrub_handle: Option<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+    read_only: bool,
+    active_offset: u64,             // active file 已加载的位置
+    loaded_fingerprint: u64,        // 只读打开时已加载的归档文件指纹
+    _owner: Option<Arc<OwnerLock>>, // 写入进程持有数据目录的所有者锁
 }
 
 pub fn new_store(
     config: Arc<Config>,
     sender: mpsc::Sender<NotifyResult>,
     receiver: mpsc::Receiver<NotifyResult>,
-) -> Store<Keydir> {
+) -> Result<Store<Keydir>, OpError> {
     let keydir = Keydir::new();
-    let mut s = Store::new(keydir, config, sender, receiver);
-    s.start();
-    s
+    let mut s = Store::new(keydir, config, sender, receiver)?;
+    s.start()?;
+    Ok(s)
 }
 
-fn get_active_data(filepath: PathBuf) -> Arc<RwLock<File>> {
-    let fd = file::new(&filepath).unwrap();
-    Arc::new(RwLock::new(fd))
+// 根据配置的 keydir 类型创建存储，加载数据文件失败时返回错误
+pub fn open_store(
+    config: Arc<Config>,
+    sender: mpsc::Sender<NotifyResult>,
+    receiver: mpsc::Receiver<NotifyResult>,
+) -> Result<Arc<RwLock<dyn Op>>, OpError> {
+    Ok(match config.get_keydir().kind() {
+        KeydirKind::Hash => Arc::new(RwLock::new(new_store(config, sender, receiver)?)),
+        KeydirKind::Compact => {
+            let keydir =
+                CompactKeydir::with_prefix_compression(config.get_keydir().prefix_compression());
+            let mut s = Store::new(keydir, config, sender, receiver)?;
+            s.start()?;
+            Arc::new(RwLock::new(s))
+        }
+        KeydirKind::Disk => {
+            let keydir = DiskKeydir::open(&config.keydir_dir(), config.get_keydir().cache_pages())?;
+            let mut s = Store::new(keydir, config, sender, receiver)?;
+            s.start()?;
+            Arc::new(RwLock::new(s))
+        }
+    })
+}
+
+// 只读打开数据目录，可与运行中的服务同时使用
+pub fn open_read_only_store(config: Arc<Config>) -> io::Result<Store<Keydir>> {
+    Store::open_read_only(Keydir::new(), config)
+}
+
+// 已交给写入线程的写入，wait 返回时已写入并更新 keydir
+pub struct PendingWrite {
+    submitted: Submitted,
+    metrics: Arc<Metrics>,
+}
+
+impl PendingWrite {
+    pub async fn wait(self) -> Result<(), OpError> {
+        let result = self.submitted.wait().await;
+        record_write(&self.metrics, result.map_err(OpError::from))
+    }
+}
+
+// 统计写入结果，磁盘已满或 I/O 错误时切换为只读
+fn record_write(metrics: &Metrics, result: Result<(), OpError>) -> Result<(), OpError> {
+    match &result {
+        Ok(_) => Metrics::incr(&metrics.writes),
+        Err(e) => record_error(metrics, e),
+    }
+    result
+}
+
+fn record_error(metrics: &Metrics, e: &OpError) {
+    Metrics::incr(&metrics.write_errors);
+    if let OpError::Io(err) = e {
+        if is_fatal_write_error(err) {
+            error!("write failed, switch to read-only mode: {}", err);
+            metrics.enter_readonly(err.to_string());
+        }
+    }
+}
+
+// 磁盘已满或底层 I/O 错误时不再接受写入
+fn is_fatal_write_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::StorageFull || is_eio(e)
+}
+
+#[cfg(unix)]
+fn is_eio(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EIO)
+}
+
+// 其它平台没有对应的错误码，只按 StorageFull 判断
+#[cfg(not(unix))]
+fn is_eio(_e: &io::Error) -> bool {
+    false
+}
+
+// 写入并同步一块数据，确认磁盘可以继续写入
+fn probe_write(path: &Path) -> io::Result<()> {
+    let result = File::create(path).and_then(|mut f| {
+        f.write_all(&[0u8; PROBE_SIZE])?;
+        f.sync_all()
+    });
+    let _ = fs::remove_file(path);
+    result
+}
+
+fn get_active_data(filepath: PathBuf) -> io::Result<Arc<RwLock<File>>> {
+    let fd = file::new(&filepath, file::OpenMode::default())?;
+    Ok(Arc::new(RwLock::new(fd)))
 }
 
 impl<K> Store<K>
@@ -73,610 +191,425 @@ where
         conf: Arc<Config>,
         sender: mpsc::Sender<NotifyResult>,
         receiver: mpsc::Receiver<NotifyResult>,
-    ) -> Store<K> {
+    ) -> Result<Store<K>, OpError> {
         // let conf = config::Config::new();
-        let active_file = get_active_data(conf.get_active_filepath());
+        // 其它进程已作为写入进程打开时返回错误
+        let owner = OwnerLock::acquire(&conf.owner_lock_filepath())?;
+        let active_file = get_active_data(conf.get_active_filepath())?;
         let mut s = Store {
             active_file,
             config: conf,
             keydir: Arc::new(RwLock::new(keydir)),
             files: Arc::new(RwLock::new(HashMap::new())),
-            file_size: AtomicUsize::new(0),
-            merge_file_num: AtomicUsize::new(0),
-            updated_key_num: AtomicUsize::new(0),
+            writer: None,
             sender,
             receiver: Arc::new(Mutex::new(receiver)),
+            merge_stop: Arc::new(AtomicBool::new(false)),
+            merge_handle: None,
+            scrub_handle: None,
+            metrics: Arc::new(Metrics::new()),
+            read_only: false,
+            active_offset: 0,
+            loaded_fingerprint: 0,
+            _owner: Some(owner),
         };
         s.notify();
-        s
+        Ok(s)
     }
 
-    fn get_filesize(&self) -> usize {
-        self.file_size.load(Ordering::SeqCst)
+    // 只读模式不创建文件，也不归档和合并，需调用 refresh 读取新写入的数据
+    pub fn open_read_only(keydir: K, conf: Arc<Config>) -> io::Result<Store<K>> {
+        let active_file = file::open(&conf.get_active_filepath())?;
+        let (sender, receiver) = mpsc::channel();
+        let mut s = Store {
+            active_file: Arc::new(RwLock::new(active_file)),
+            config: conf,
+            keydir: Arc::new(RwLock::new(keydir)),
+            files: Arc::new(RwLock::new(HashMap::new())),
+            writer: None,
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            merge_stop: Arc::new(AtomicBool::new(true)),
+            merge_handle: None,
+            scrub_handle: None,
+            metrics: Arc::new(Metrics::new()),
+            read_only: true,
+            active_offset: 0,
+            loaded_fingerprint: 0,
+            _owner: None,
+        };
+        let _lock = DirLock::shared(&s.config.lock_filepath())?;
+        s.reload()?;
+        Ok(s)
     }
 
-    // 从当前目录里读取相关文件，如果未找到任务数据文件
-    pub fn start(&mut self) {
-        // 1. load all hits to keydir
-        self.load_hint_file();
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        // 2. load all datafiles
-        self.rebuild_from_datafile();
-        self.load_active_file();
+    // key 当前在 keydir 中的索引
+    pub(crate) fn metadata(&self, key: &[u8]) -> Option<Metadata> {
+        self.keydir.read().unwrap().get(key).ok()
     }
 
-    // merge
+    // 只读模式下重新加载全部文件，调用方需持有目录共享锁
+    fn reload(&mut self) -> io::Result<()> {
+        let fingerprint = self.datafiles_fingerprint();
+        let active_file = file::open(&self.config.get_active_filepath())?;
+        *self.keydir.write().unwrap() = K::new();
+        self.files.write().unwrap().clear();
+        self.load_archived_files()?;
+        *self.active_file.write().unwrap() = active_file;
+        self.load_active_file(0)?;
+        self.loaded_fingerprint = fingerprint;
+        Ok(())
+    }
 
-    fn rebuild_from_datafile(&mut self) {
-        let current_max_file_seq = self.config.get_next_datafile_seq();
-        let mut keydir = Keydir::new();
+    // 从当前目录里读取相关文件，读取失败时返回错误，由调用方报告后退出
+    pub fn start(&mut self) -> Result<(), OpError> {
+        // 只有写入进程会合并，启动时完成或清理上次遗留的合并
+        merge::recover(&self.config)?;
+
+        // 持久化的索引与数据文件一致时直接使用
+        let fingerprint = self.datafiles_fingerprint();
+        let mut active_offset = 0;
+        if self.keydir.write().unwrap().restore(fingerprint)? {
+            info!("keydir restored from index, skip loading hint files");
+            self.register_datafiles()?;
+        } else if let Some(offset) = self.load_snapshot(fingerprint)? {
+            self.register_datafiles()?;
+            active_offset = offset;
+        } else {
+            self.load_archived_files()?;
+        }
+        self.load_active_file(active_offset)?;
 
-        for idx in 1..current_max_file_seq {
-            let mut count = 0;
+        {
+            let keydir = self.keydir.read().unwrap();
+            info!(
+                "keydir loaded {} keys, memory usage {} bytes",
+                keydir.len(),
+                keydir.memory_usage()
+            );
+        }
+
+        if self.config.get_scrub().enabled() {
+            self.start_scrubber();
+        }
+
+        let log = ActiveLog::new(
+            Arc::clone(&self.config),
+            Arc::clone(&self.active_file),
+            Arc::clone(&self.files),
+            Arc::clone(&self.keydir),
+            Arc::clone(&self.metrics),
+            self.sender.clone(),
+            self.active_offset,
+        )?;
+        self.writer = Some(Writer::start(log));
+        Ok(())
+    }
+
+    // 后台校验归档文件
+    fn start_scrubber(&mut self) {
+        let config = Arc::clone(&self.config);
+        let keydir = Arc::clone(&self.keydir);
+        let metrics = Arc::clone(&self.metrics);
+        let stop = Arc::clone(&self.merge_stop);
+        self.scrub_handle = Some(std::thread::spawn(move || {
+            scrub::run(&config, &keydir, &metrics, &stop);
+        }));
+    }
+
+    // 归档数据文件的序号和大小
+    fn datafiles_fingerprint(&self) -> u64 {
+        let mut buf = Vec::new();
+        for idx in 1..self.config.get_next_datafile_seq() {
+            let the_file = self.config.get_filepath_by_seq(idx);
+            if let Ok(meta) = fs::metadata(&the_file) {
+                buf.extend_from_slice(&idx.to_le_bytes());
+                buf.extend_from_slice(&meta.len().to_le_bytes());
+            }
+        }
+        util::fnv1a(&buf)
+    }
 
-            // read index from hint file
+    // 注册所有归档数据文件 fd
+    fn register_datafiles(&mut self) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        for idx in 1..self.config.get_next_datafile_seq() {
             let the_file = self.config.get_filepath_by_seq(idx);
             if !the_file.exists() {
-                debug!("data file {:?}  not found!", the_file);
                 continue;
-            } else {
-                // 判断是否已通过 hint 文件合并
-                let hint_filename = self.config.get_hint_filepath_by_seq(idx);
-                if hint_filename.exists() {
-                    continue;
-                }
-            }
-            let file = file::open(&the_file).unwrap();
-            let entry_file = EntryFile::new(file);
-            let entry_result: Vec<EntryParseResult> = entry_file.into();
-
-            for entry in entry_result {
-                let metadata = Metadata {
-                    file_id: idx,
-                    value_sz: entry.entry.size() as u64,
-                    value_pos: entry.value_pos,
-                    tstamp: entry.entry.timestamp,
-                };
-
-                // debug!("{:?} {:?}", entry.entry, metadata);
-                // log replay
-                if entry.entry.is_expired() || entry.entry.is_removed() {
-                    info!("expired or deleted {:?}", entry.entry);
-                    keydir.remove(&entry.entry.key);
-                } else {
-                    keydir.set(&entry.entry.key, metadata);
-                    count += 1;
-                }
             }
-
-            // register datafile fd
-            let fd = file::open_reader(&the_file).unwrap();
-            let mut file = self.files.write().unwrap();
-            file.insert(idx, Arc::new(RwLock::new(fd)));
-
-            debug!("加载序号 {}  datafile 文件，共找到条目 {}", idx, count);
+            let fd = file::open_reader(&the_file)?;
+            files.insert(idx, Arc::new(RwLock::new(fd)));
         }
-
-        debug!("共加载数据文件条目 {:?}", keydir.data.len());
-
-        // merge all archived datafile to keydir
-        let mut keydir_global = self.keydir.write().unwrap();
-        keydir_global.extend(keydir.data);
+        Ok(())
     }
-    fn load_hint_file(&mut self) {
-        let current_max_file_seq = self.config.get_next_datafile_seq();
-        let mut total: usize = 0;
-        for idx in 1..current_max_file_seq {
-            // read index from hint file
-            let hint_filename = self.config.get_hint_filepath_by_seq(idx);
-            let archived_filename = self.config.get_filepath_by_seq(idx);
-            if !hint_filename.exists() || !archived_filename.exists() {
-                debug!("hits file {:?}  not found!", hint_filename);
+
+    // 并行解析归档文件（优先 hint 文件），按文件序号依次合并到 keydir
+    fn load_archived_files(&mut self) -> io::Result<()> {
+        let mut tasks = Vec::new();
+        for idx in 1..self.config.get_next_datafile_seq() {
+            let data_path = self.config.get_filepath_by_seq(idx);
+            if !data_path.exists() {
+                debug!("data file {:?}  not found!", data_path);
                 continue;
             }
-            let hint_file_path = file::open(&hint_filename).unwrap();
-
-            let the_file = HintFile::new(hint_file_path);
-            let result: Vec<Hint> = HintFile::into(the_file);
-            let len = result.len();
-
-            for hint in result {
-                let metadata = Metadata {
-                    file_id: idx,
-                    value_sz: hint.value_size,
-                    value_pos: hint.value_pos,
-                    tstamp: hint.timestamp,
-                };
-                debug!("索引加载hint: {:?}", hint);
-                debug!("{:?}", metadata);
-
-                // update keydir
-                let mut keydir = self.keydir.write().unwrap();
-                keydir.set(&hint.key, metadata);
-            }
-
-            debug!("加载序号 {}  hint文件，共计 {}", current_max_file_seq, len);
+            let hint_path = self.config.get_hint_filepath_by_seq(idx);
+            tasks.push(LoadTask {
+                file_id: idx,
+                data_path,
+                hint_path: hint_path.exists().then_some(hint_path),
+            });
+        }
 
+        let mut keydir = self.keydir.write().unwrap();
+        let mut files = self.files.write().unwrap();
+        loader::load_parallel(tasks, |partial| {
+            quarantine::handle(
+                &self.config,
+                &partial.data_path,
+                &partial.damaged,
+                self.read_only,
+            );
             // register datafile fd
-            let fd = file::open_reader(&archived_filename).unwrap();
-            let mut file = self.files.write().unwrap();
-            file.insert(idx, Arc::new(RwLock::new(fd)));
+            let fd = file::open_reader(&partial.data_path)?;
+            files.insert(partial.file_id, Arc::new(RwLock::new(fd)));
+            partial.apply(&mut *keydir).map(|_| ())
+        })
+    }
 
-            total += len;
+    // 加载关闭时保存的快照，成功时返回需要继续回放的 active file 位置
+    fn load_snapshot(&mut self, fingerprint: u64) -> io::Result<Option<u64>> {
+        let path = self.config.snapshot_filepath();
+        if !path.exists() {
+            return Ok(None);
         }
 
-        debug!("从hint文件加载共计 {} 项", total);
-    }
-
-    fn load_active_file(&mut self) {
-        // 读取磁盘 active file, 主要实现从 data 文件实现索引重建
-        let op_file = Arc::clone(&self.active_file);
-        let active_file = op_file.write().unwrap();
-        let the_file = active_file.try_clone().unwrap();
-        let entry_file = EntryFile::new(the_file);
-        let entry_result: Vec<EntryParseResult> = entry_file.into();
-
-        // 临时 keydir
-        let mut keydir = Keydir::new();
-        for entry in entry_result {
-            let metadata = Metadata {
-                file_id: ACTIVE_FILE_SEQ,
-                value_sz: entry.entry.size() as u64,
-                value_pos: entry.value_pos,
-                tstamp: entry.entry.timestamp,
-            };
-
-            // debug!("{:?} {:?}", entry.entry, metadata);
-            // log replay
-            if entry.entry.is_expired() || entry.entry.is_removed() {
-                debug!("expired or deleted {:?}", entry.entry);
-                keydir.remove(&entry.entry.key);
-            } else {
-                keydir.set(&entry.entry.key, metadata);
-            }
+        // 快照只使用一次，之后的写入会使其过期，无法删除时不使用，避免下次启动读到过期的快照
+        let result = snapshot::read(&path);
+        if let Err(e) = fs::remove_file(&path) {
+            warn!(
+                "remove keydir snapshot {:?} failed: {}, load hint files",
+                path, e
+            );
+            return Ok(None);
         }
+        let snapshot = match result {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("invalid keydir snapshot {:?}: {}", path, e);
+                return Ok(None);
+            }
+        };
 
-        debug!("found {} items from active file", keydir.len());
+        let active_len = fs::metadata(self.config.get_active_filepath())
+            .map(|m| m.len())
+            .unwrap_or(0);
+        if snapshot.fingerprint != fingerprint || snapshot.active_len > active_len {
+            warn!("keydir snapshot is stale, fallback to recovery");
+            return Ok(None);
+        }
 
-        // merge temp keydir
-        let mut self_keydir = self.keydir.write().unwrap();
-        self_keydir.extend(keydir.data);
+        let mut keydir = self.keydir.write().unwrap();
+        for (key, metadata) in &snapshot.entries {
+            keydir.set(key, metadata.clone())?;
+        }
+        info!(
+            "keydir restored from snapshot, {} keys, replay active file from {}",
+            snapshot.entries.len(),
+            snapshot.active_len
+        );
+        Ok(Some(snapshot.active_len))
     }
 
-    fn get_fd(&self, seq: u16) -> Result<(Option<ReaderFile>, Option<StFile>), OpError> {
-        if seq == ACTIVE_FILE_SEQ {
-            return Ok((Some(Arc::clone(&self.active_file)), None));
+    // 保存快照，写入期间数据文件发生变化时丢弃
+    fn save_snapshot(&self) -> io::Result<()> {
+        let fingerprint = self.datafiles_fingerprint();
+        let path = self.config.snapshot_filepath();
+        {
+            let keydir = self.keydir.read().unwrap();
+            if keydir.is_persistent() {
+                return Ok(());
+            }
+            let active_len = self.active_file.write().unwrap().metadata()?.len();
+            snapshot::write(&path, active_len, fingerprint, keydir.len(), keydir.iter())?;
         }
 
-        // match self.files.get(&seq)
-        let files = self.files.read().unwrap();
-        match files.get(&seq) {
-            Some(v) => Ok((None, Some(Arc::clone(v)))),
-            None => {
-                debug!("unregister fd for seq {}", seq);
-                Err(OpError::KeyNotFound)
-            }
+        if self.datafiles_fingerprint() != fingerprint {
+            warn!("datafiles changed while saving snapshot, discard it");
+            fs::remove_file(&path)?;
         }
+        Ok(())
     }
 
-    fn active_file_archive(&mut self) {
-        // 归档文件
-        self.archive_file();
-
-        // merge archived datafiles in a new thread
-        self.merge_file_num.fetch_add(1, Ordering::SeqCst);
-        debug!(
-            "{} : {}",
-            self.merge_file_num.load(Ordering::SeqCst),
-            self.config.get_merge_file_num()
+    fn load_active_file(&mut self, offset: u64) -> io::Result<()> {
+        // 读取磁盘 active file, 主要实现从 data 文件实现索引重建
+        let guard = self.active_file.write().unwrap();
+        let partial =
+            loader::parse_data_file(ACTIVE_FILE_SEQ, self.config.get_active_filepath(), offset)?;
+        self.active_offset = partial.end;
+        quarantine::handle(
+            &self.config,
+            &partial.data_path,
+            &partial.damaged,
+            self.read_only,
         );
-        if self.merge_file_num.load(Ordering::SeqCst) >= self.config.get_merge_file_num() {
-            self.merge_file_num.store(0, Ordering::SeqCst);
-
-            // Avoiding duplicate merges caused by generating new files too quickly
-            if lock::Locker::acquire().is_ok() {
-                self.sender.send(0).unwrap();
-            } else {
-                debug!("发现 lock 文件，正在合并中...");
-            }
+        // 截掉末尾写入中断的记录，避免新数据追加在残缺数据之后
+        if !self.read_only && partial.damaged.last().is_some_and(|r| r.tail) {
+            warn!("truncate active file to {}", partial.end);
+            guard.set_len(partial.end)?;
         }
+        let count = partial.apply(&mut *self.keydir.write().unwrap())?;
+        debug!("found {} items from active file", count);
+        Ok(())
     }
 
-    // rename active file to datafiles
-    fn archive_file(&mut self) {
-        let active_filepath = self.config.get_active_filepath();
-
-        let archive_file_seq = self.config.get_next_datafile_seq();
-        let archive_filepath = self.config.get_filepath_by_seq(archive_file_seq);
-
-        // write lock and flush buffer body to disk
-        {
-            let mut files = self.files.write().unwrap();
-            // 1. rename active file name to archive file
-            let mut fd = self.active_file.write().unwrap();
-            fd.flush().unwrap();
-
-            std::fs::rename(&active_filepath, &archive_filepath).unwrap();
-
-            // 2. reopen the file in read-only mode
-            let file = file::open_reader(&archive_filepath).unwrap();
-            files.insert(archive_file_seq, Arc::new(RwLock::new(file)));
-
-            // update keydir
-            let mut keydir = self.keydir.write().unwrap();
-            keydir.update_key(archive_file_seq);
+    // 编码后交给写入线程，返回时已写入并更新 keydir
+    fn write(&self, key: &[u8], entry: Entry, removed: bool) -> Result<(), OpError> {
+        let writer = self.writer.as_ref().ok_or(OpError::ReadOnly)?;
+        let result = writer.write(key, entry.as_bytes(), removed);
+        record_write(&self.metrics, result.map_err(OpError::from))
+    }
 
-            debug!("archive active file => {:?}", archive_filepath);
+    fn submit(&self, key: &[u8], entry: Entry, removed: bool) -> Result<PendingWrite, OpError> {
+        let writer = self.writer.as_ref().ok_or(OpError::ReadOnly)?;
+        match writer.submit(key, entry.as_bytes(), removed) {
+            Ok(submitted) => Ok(PendingWrite {
+                submitted,
+                metrics: Arc::clone(&self.metrics),
+            }),
+            Err(e) => {
+                let e = OpError::from(e);
+                record_error(&self.metrics, &e);
+                Err(e)
+            }
         }
+    }
 
-        // renew active file
-        let archive_file_fd = file::new(&active_filepath).unwrap();
-        self.active_file = Arc::new(RwLock::new(archive_file_fd));
-        debug!("renew active file {:?}", active_filepath);
+    fn check_writable(&self) -> Result<(), OpError> {
+        if self.read_only || self.metrics.is_readonly() {
+            return Err(OpError::ReadOnly);
+        }
+        Ok(())
     }
 
     fn notify(&mut self) {
-        let active_file = Arc::clone(&self.active_file);
         let files = Arc::clone(&self.files);
         let config = Arc::clone(&self.config);
-        // let self = &self.clone();
         let receiver: Arc<Mutex<Receiver<NotifyResult>>> = Arc::clone(&self.receiver);
         let keydir = Arc::clone(&self.keydir);
+        let merge_stop = Arc::clone(&self.merge_stop);
+        let metrics = Arc::clone(&self.metrics);
 
-        std::thread::spawn(move || {
-            fn get_fd(
-                active_file: Arc<RwLock<File>>,
-                files: Arc<RwLock<HashMap<u16, StFile>>>,
-                seq: u16,
-            ) -> Result<(Option<ReaderFile>, Option<StFile>), OpError> {
-                if seq == ACTIVE_FILE_SEQ {
-                    return Ok((Some(active_file), None));
-                }
-
-                let files = files.read().unwrap();
-                match files.get(&seq) {
-                    Some(v) => Ok((None, Some(Arc::clone(v)))),
-                    None => {
-                        debug!("unregister fd for seq {}", seq);
-                        Err(OpError::KeyNotFound)
-                    }
-                }
-            }
-
+        let handle = std::thread::spawn(move || {
             for i in receiver.lock().unwrap().iter() {
-                println!("notify thread iter ======================= {:?}", i);
-                debug!("\n\n=== COMPACTION BEGIN ===");
-
-                let merge_dir = config.merge_dir();
-                if merge_dir.exists() {
-                    debug!("当前已处于工作状态 {:?}", merge_dir);
-                    return;
-                }
-                // 创建临时合并目录
-                fs::create_dir_all(merge_dir).unwrap();
-
-                let mut merge_file_seq: u16 = 1;
-                let mut merge_total_size = 0;
-                let mut merge_keydir = Keydir::new();
-
-                // merge file
-                let merge_filepath = config.get_merge_filepath_by_seq(merge_file_seq);
-                let mut merge_file_fd = file::new_writer(&merge_filepath).unwrap();
-                debug!("create merge file: {:?}", merge_filepath);
-
-                // hint file
-                let merge_hint_filepath = config.get_merge_hint_filepath_by_seq(merge_file_seq);
-                debug!("create merge hint file: {:?}", merge_hint_filepath);
-                let mut merge_hint_file_fd = file::new_writer(&merge_hint_filepath).unwrap();
-
-                let active_file_seq = config.get_next_datafile_seq();
-
-                // 新文件pos
-                let mut offset = 0;
-
-                debug!("archive_file_seq= {:?}", active_file_seq);
-                for (key, metadata) in keydir
-                    .read()
-                    .unwrap()
-                    .iter()
-                    .filter(|(_, metadata)| metadata.file_id > 0)
-                {
-                    let active_file = Arc::clone(&active_file);
-                    let files = Arc::clone(&files);
-                    let old_file_option = get_fd(active_file, files, metadata.file_id).unwrap();
-                    // 从原来的文件读取最新值
-                    let bytes_result = match old_file_option {
-                        (None, Some(archive_file)) => {
-                            file::read_reader(&archive_file, metadata.value_pos, metadata.value_sz)
-                        }
-                        _ => unreachable!(), // 理论上不可能到达这里
-                    };
-
-                    match bytes_result {
-                        Ok(bytes) => {
-                            let body_size = bytes.len();
-                            //  splits a new file
-                            if merge_total_size + body_size > config.file_max_size() {
-                                // 1. close current merge file fd
-                                merge_file_fd.flush().unwrap();
-                                merge_hint_file_fd.flush().unwrap();
-
-                                // 2.1 create a new merge file and hint file
-                                merge_file_seq += 1;
-                                let merge_filepath =
-                                    config.get_merge_filepath_by_seq(merge_file_seq);
-                                merge_file_fd = file::new_writer(&merge_filepath).unwrap();
-                                debug!("[data]create merge file: {:?}", merge_filepath);
-
-                                let merge_hint_filepath =
-                                    config.get_merge_hint_filepath_by_seq(merge_file_seq);
-                                merge_hint_file_fd =
-                                    file::new_writer(&merge_hint_filepath).unwrap();
-                                debug!("[hint]create merge hint file: {:?}", merge_hint_filepath);
-
-                                // 3. reset all variable for next iter
-                                merge_total_size = 0;
-                                offset = 0;
-                            }
-
-                            // write merge file
-                            merge_file_fd.write_all(bytes.as_slice()).unwrap();
-
-                            // write hint file
-                            let entry = match Entry::try_from(bytes.clone()) {
-                                Ok(entry) => entry,
-                                Err(e) => {
-                                    debug!("wrong {:?}", bytes);
-                                    debug!("metadata {:?}", metadata);
-                                    panic!("wrong {:?}", e)
-                                }
-                            };
-                            let hint_entry = Hint {
-                                timestamp: metadata.tstamp,
-                                key_size: entry.key_size,
-                                value_size: entry.size() as u64, // 整个entry 大小,
-                                value_pos: offset,               // 整个entry的读取位置
-                                key: entry.key,
-                            };
-                            debug!("write_hit {:?}", hint_entry);
-                            let hint_bytes: Vec<u8> = hint_entry.into();
-                            merge_hint_file_fd.write_all(&hint_bytes).unwrap();
-
-                            // update keydir index
-                            let merge_metadata = Metadata {
-                                file_id: merge_file_seq,     // 新 file_seq
-                                value_pos: offset,           // 在新文件offset
-                                value_sz: metadata.value_sz, // entry 本身大小不变
-                                tstamp: metadata.tstamp,
-                            };
-
-                            debug!("old_medatdata {:?}", metadata);
-                            debug!("hit_metadata  {:?}", merge_metadata);
-
-                            merge_keydir.data.insert(key.clone(), merge_metadata);
-
-                            // position for next write
-                            offset += metadata.value_sz;
-                            merge_total_size += body_size;
-                        }
-                        Err(_) => error!("err {:?}", metadata.file_id),
-                    }
+                if merge_stop.load(Ordering::SeqCst) {
+                    debug!("merge thread stopped");
+                    break;
                 }
+                debug!("notify thread iter {:?}", i);
 
-                // 刷新写盘
-                merge_file_fd.flush().unwrap();
-                merge_hint_file_fd.flush().unwrap();
-
-                // move
-                if !merge_keydir.data.is_empty() {
-                    // delete old datafiles
-                    let mut files = files.write().unwrap();
-                    for i in files.keys() {
-                        if *i >= active_file_seq {
-                            continue;
-                        }
-                        let delete_file = config.get_filepath_by_seq(*i);
-                        debug!("deleted old data file: {:?}", delete_file);
-                        fs::remove_file(delete_file).unwrap();
-                    }
-                    // 加锁操作
-                    // rename merge files
-                    for i in 1..(merge_file_seq + 1) {
-                        // merge file
-                        let from = config.get_merge_filepath_by_seq(i);
-                        let to = config.get_filepath_by_seq(i);
-                        let src_path = Path::new(&from);
-                        let dst_path = Path::new(&to);
-
-                        debug!("{:?} => {:?} File moved successfully!", from, to);
-                        std::fs::rename(src_path, dst_path).unwrap();
-
-                        // hint file
-                        {
-                            let from = config.get_merge_hint_filepath_by_seq(i);
-                            let to = config.get_hint_filepath_by_seq(i);
-                            let src_path = Path::new(&from);
-                            let dst_path = Path::new(&to);
-
-                            std::fs::rename(src_path, dst_path).unwrap();
-                            debug!("{:?} => {:?} File moved successfully!", from, to);
-                        }
-
-                        // 重新打开所有文件句柄，并注册[file_id:fd]
-                        let fd = file::open_reader(&dst_path.to_path_buf()).unwrap();
-                        files.insert(i, Arc::new(RwLock::new(fd)));
-                    }
-
-                    // update keydir index
-                    let mut keydir = keydir.write().unwrap();
-                    keydir.extend(merge_keydir.data);
-                } else {
-                    // remove empty file
-                    fs::remove_file(merge_filepath).unwrap();
+                if let Err(e) = merge::merge(&config, &keydir, &files, &merge_stop, &metrics) {
+                    error!("merge failed: {}", e);
                 }
-
-                // 删除临时合并文件
-                config.merge_cleanup();
-
-                debug!("\n=== COMPACTION END ===\n");
             }
         });
+        self.merge_handle = Some(handle);
     }
 }
 
 impl<K: OpKeydir> Op for Store<K> {
     // get
     fn get(&self, key: &[u8]) -> Result<Vec<u8>, OpError> {
-        let self_keydir = self.keydir.read().unwrap();
-        if let Ok(metadata) = self_keydir.get(key) {
-            debug!("key:{:?}  {:?}", key, metadata);
-            let op_file_option = self.get_fd(metadata.file_id).unwrap();
-
-            let bytes_result = match op_file_option {
-                (Some(active_file), None) => {
-                    file::read(&active_file, metadata.value_pos, metadata.value_sz)
-                }
-                (None, Some(archive_file)) => {
-                    file::read_reader(&archive_file, metadata.value_pos, metadata.value_sz)
-                }
-                _ => unreachable!(), // 理论上不可能到达这里
-            };
-
-            // debug!(
-            //     "file_id: {},value_pos: {}, value_sz: {}",
-            //     metadata.file_id, metadata.value_pos, metadata.value_sz
-            // );
-            // let bytes = file::read(&op_file, metadata.value_pos, metadata.value_sz);
-            match bytes_result {
-                Ok(bytes) => match Entry::try_from(bytes) {
-                    Ok(entry) => {
-                        debug!("{:?}", entry);
-                        if !entry.is_valid() || entry.is_expired() || entry.is_removed() {
-                            // remove item from key
-                            // self.keydir.remove(key);
-                            return Err(OpError::ValueInvalid);
-                        } else {
-                            return Ok(entry.value);
-                        }
-                    }
-                    Err(e) => {
-                        error!("parse Entry object failed! {:?}", e);
-                        return Err(OpError::ValueInvalid);
-                    }
-                },
-                Err(e) => {
-                    error!("read error: {:?}", e);
-                    return Err(OpError::ValueInvalid);
-                }
-            }
+        let entry = self.get_entry(key)?;
+        if entry.is_expired() || entry.is_removed() {
+            // remove item from key
+            // self.keydir.remove(key);
+            return Err(OpError::ValueInvalid);
         }
-        Err(OpError::KeyNotFound)
+        Ok(entry.value)
     }
 
     fn get_entry(&self, key: &[u8]) -> Result<Entry, OpError> {
-        let self_keydir = self.keydir.read().unwrap();
-        if let Ok(metadata) = self_keydir.get(key) {
-            debug!("key:{:?}  {:?}", key, metadata);
-            let op_file_option = self.get_fd(metadata.file_id).unwrap();
-
-            let bytes_result = match op_file_option {
-                (Some(active_file), None) => {
-                    file::read(&active_file, metadata.value_pos, metadata.value_sz)
-                }
-                (None, Some(archive_file)) => {
-                    file::read_reader(&archive_file, metadata.value_pos, metadata.value_sz)
-                }
-                _ => unreachable!(), // 理论上不可能到达这里
-            };
-
-            // debug!(
-            //     "file_id: {},value_pos: {}, value_sz: {}",
-            //     metadata.file_id, metadata.value_pos, metadata.value_sz
-            // );
-            // let bytes = file::read(&op_file, metadata.value_pos, metadata.value_sz);
-            match bytes_result {
-                Ok(bytes) => match Entry::try_from(bytes) {
-                    Ok(entry) => {
-                        debug!("{:?}", entry);
-                        if entry.is_valid() {
-                            return Ok(entry);
-                        } else {
-                            return Err(OpError::ValueInvalid);
-                        }
-                    }
-                    Err(e) => {
-                        error!("parse Entry object failed! {:?}", e);
-                        return Err(OpError::ValueInvalid);
-                    }
-                },
-                Err(e) => {
-                    error!("read error: {:?}", e);
-                    return Err(OpError::ValueInvalid);
-                }
-            }
+        // 读取完成前一直持有 files 和 keydir 读锁，归档和合并替换文件时先取 files 再取 keydir 写锁
+        // 按相同顺序加锁，读取期间索引指向的文件不会被替换
+        let files = self.files.read().unwrap();
+        let keydir = self.keydir.read().unwrap();
+        let metadata = keydir.get(key)?;
+        debug!("key:{:?}  {:?}", key, metadata);
+        let bytes = if metadata.file_id == ACTIVE_FILE_SEQ {
+            file::read(&self.active_file, metadata.value_pos, metadata.value_sz)?
+        } else {
+            let archive_file = files.get(&metadata.file_id).ok_or_else(|| {
+                OpError::Corruption(format!("data file {} not registered", metadata.file_id))
+            })?;
+            file::read_reader(archive_file, metadata.value_pos, metadata.value_sz)?
+        };
+        drop(keydir);
+        drop(files);
+
+        let entry = Entry::try_from(bytes).map_err(|e| {
+            error!("parse Entry object failed! {:?}", e);
+            OpError::Corruption(format!(
+                "data file {} pos {}: {}",
+                metadata.file_id, metadata.value_pos, e
+            ))
+        })?;
+        debug!("{:?}", entry);
+        if entry.is_valid() {
+            Ok(entry)
+        } else {
+            Err(OpError::Corruption(format!(
+                "data file {} pos {}: crc mismatch",
+                metadata.file_id, metadata.value_pos
+            )))
         }
-        Err(OpError::KeyNotFound)
     }
 
     // set/put
-    fn set(&mut self, key: &[u8], value: &[u8], timestamp: u64) {
+    fn set(&self, key: &[u8], value: &[u8], timestamp: u64) -> Result<(), OpError> {
+        self.check_writable()?;
         debug!(
             "set key:{:?}, value:{:?}, timestamp: {}",
             key, value, timestamp
         );
         let entry = entry::Entry::new(key.to_vec(), value.to_vec(), timestamp);
+        self.write(key, entry, false)
+    }
 
-        // 文件大小分割
-        if self.get_filesize() + entry.size() > self.config.file_max_size() {
-            self.active_file_archive();
-            self.file_size.store(0, Ordering::SeqCst);
-        } else {
-            self.file_size.fetch_add(entry.size(), Ordering::SeqCst);
-        }
+    // delete
+    fn delete(&self, key: &[u8]) -> Result<(), OpError> {
+        self.check_writable()?;
+        let entry = entry::Entry::new(key.to_vec(), vec![], 0).set_removed();
+        debug!("delete {:?}", entry);
+        self.write(key, entry, true)
+    }
 
-        // 2. 写入当前活跃文件
-        let (entry_pos, entry_size) = file::append(&self.active_file, entry);
-        self.updated_key_num.fetch_add(1, Ordering::SeqCst);
-        let config_sync_keys_num = self.config.get_sync_keys_num() as usize;
-        if config_sync_keys_num > 0
-            && self.updated_key_num.load(Ordering::SeqCst) >= config_sync_keys_num
-        {
-            // file flush
-            let mut active_file_fd = self.active_file.write().unwrap();
-            active_file_fd.flush().unwrap();
-        }
+    fn submit_set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u64,
+    ) -> Result<PendingWrite, OpError> {
+        self.check_writable()?;
+        let entry = entry::Entry::new(key.to_vec(), value.to_vec(), timestamp);
+        self.submit(key, entry, false)
+    }
 
-        // 3. update keydir
-        let metadata = Metadata {
-            file_id: ACTIVE_FILE_SEQ,
-            value_sz: entry_size,
-            value_pos: entry_pos,
-            tstamp: Utc::now().timestamp() as u64,
-        };
-        let mut self_keydir = self.keydir.write().unwrap();
-        self_keydir.set(key, metadata);
+    fn submit_delete(&self, key: &[u8]) -> Result<PendingWrite, OpError> {
+        self.check_writable()?;
+        let entry = entry::Entry::new(key.to_vec(), vec![], 0).set_removed();
+        self.submit(key, entry, true)
     }
 
-    // delete
-    fn delete(&mut self, key: &[u8]) {
-        // 先检查是否存在，否则直接返回
-        // self.set(key, &b"".to_vec());
-        let value: Vec<u8> = vec![];
-        let entry = entry::Entry::new(key.to_vec(), value, 0).set_removed();
-        debug!("delete {:?}", entry);
-        self.file_size.fetch_add(entry.size(), Ordering::SeqCst);
-        let (_, _) = file::append(&self.active_file, entry);
-        let config_sync_keys_num = self.config.get_sync_keys_num() as usize;
-        if config_sync_keys_num > 0
-            && self.updated_key_num.load(Ordering::SeqCst) >= config_sync_keys_num
-        {
-            // file flush
-            let mut active_file_fd = self.active_file.write().unwrap();
-            active_file_fd.flush().unwrap();
+    fn flush_writes(&self) -> Result<(), OpError> {
+        match &self.writer {
+            Some(writer) => Ok(writer.flush()?),
+            None => Ok(()),
         }
-        // delete index from keydir
-        let mut self_keydir = self.keydir.write().unwrap();
-        self_keydir.remove(key);
     }
 
     // len
@@ -690,184 +623,103 @@ impl<K: OpKeydir> Op for Store<K> {
 
     fn keys(&self) -> Vec<Vec<u8>> {
         let keydir = self.keydir.read().unwrap(); // 读锁定
-        keydir.keys().into_iter().cloned().collect()
+        keydir.keys()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.keydir.read().unwrap().memory_usage()
+    }
+
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // 释放磁盘空间后恢复写入，先确认已缓存的数据可以落盘
+    fn set_writable(&mut self) -> Result<(), OpError> {
+        if self.read_only {
+            return Err(OpError::ReadOnly);
+        }
+        if !self.metrics.is_readonly() {
+            return Ok(());
+        }
+        self.active_file.write().unwrap().sync_all()?;
+        probe_write(&self.config.data_dir().join(PROBE_FILE))?;
+        self.metrics.leave_readonly();
+        info!("store is writable again");
+        Ok(())
     }
 
-    fn compaction(&mut self) {
-        debug!("\n\n=== COMPACTION BEGIN ===");
+    // 归档文件有变化时重新加载，否则只回放 active file 新追加的数据
+    fn refresh(&mut self) -> Result<(), OpError> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let _lock = DirLock::shared(&self.config.lock_filepath())?;
+        if self.datafiles_fingerprint() != self.loaded_fingerprint {
+            debug!("datafiles changed, reload all files");
+            self.reload()?;
+        } else {
+            self.load_active_file(self.active_offset)?;
+        }
+        Ok(())
+    }
 
-        let merge_dir = self.config.merge_dir();
-        if merge_dir.exists() {
-            debug!("当前已处于工作状态 {:?}", merge_dir);
+    fn close(&mut self) {
+        if self.read_only {
             return;
         }
-        // 创建临时合并目录
-        fs::create_dir_all(merge_dir).unwrap();
-
-        let mut merge_file_seq: u16 = 1;
-        let mut merge_total_size = 0;
-        let mut merge_keydir = Keydir::new();
-
-        // merge file
-        let merge_filepath = self.config.get_merge_filepath_by_seq(merge_file_seq);
-        let mut merge_file_fd = file::new_writer(&merge_filepath).unwrap();
-        debug!("create merge file: {:?}", merge_filepath);
-
-        // hint file
-        let merge_hint_filepath = self.config.get_merge_hint_filepath_by_seq(merge_file_seq);
-        debug!("create merge hint file: {:?}", merge_hint_filepath);
-        let mut merge_hint_file_fd = file::new_writer(&merge_hint_filepath).unwrap();
-
-        let active_file_seq = self.config.get_next_datafile_seq();
-
-        // 新文件pos
-        let mut offset = 0;
-        debug!("archive_file_seq= {:?}", active_file_seq);
-        for (key, metadata) in self
-            .keydir
-            .read()
-            .unwrap()
-            .iter()
-            .filter(|(_, metadata)| metadata.file_id > 0)
-        {
-            let old_file_option = self.get_fd(metadata.file_id).unwrap();
-            // 从原来的文件读取最新值
-            let bytes_result = match old_file_option {
-                // (Some(active_file), None) => {
-                //     // 合并操作，永远不会使用 active_file
-                //     file::read(&active_file, metadata.value_pos, metadata.value_sz)
-                // }
-                (None, Some(archive_file)) => {
-                    file::read_reader(&archive_file, metadata.value_pos, metadata.value_sz)
-                }
-                _ => unreachable!(), // 理论上不可能到达这里
-            };
-
-            match bytes_result {
-                Ok(bytes) => {
-                    let body_size = bytes.len();
-                    //  splits a new file
-                    if merge_total_size + body_size > self.config.file_max_size() {
-                        // 1. close current merge file fd
-                        merge_file_fd.flush().unwrap();
-                        merge_hint_file_fd.flush().unwrap();
-
-                        // 2.1 create a new merge file and hint file
-                        merge_file_seq += 1;
-                        let merge_filepath = self.config.get_merge_filepath_by_seq(merge_file_seq);
-                        merge_file_fd = file::new_writer(&merge_filepath).unwrap();
-                        debug!("[data]create merge file: {:?}", merge_filepath);
-
-                        let merge_hint_filepath =
-                            self.config.get_merge_hint_filepath_by_seq(merge_file_seq);
-                        merge_hint_file_fd = file::new_writer(&merge_hint_filepath).unwrap();
-                        debug!("[hint]create merge hint file: {:?}", merge_hint_filepath);
-
-                        // 3. reset all variable for next iter
-                        merge_total_size = 0;
-                        offset = 0;
-                    }
-
-                    // write merge file
-                    merge_file_fd.write_all(bytes.as_slice()).unwrap();
-
-                    // write hint file
-                    let entry = match Entry::try_from(bytes.clone()) {
-                        Ok(entry) => entry,
-                        Err(e) => {
-                            debug!("wrong {:?}", bytes);
-                            debug!("metadata {:?}", metadata);
-                            panic!("wrong {:?}", e)
-                        }
-                    };
-                    let hint_entry = Hint {
-                        timestamp: metadata.tstamp,
-                        key_size: entry.key_size,
-                        value_size: entry.size() as u64, // 整个entry 大小,
-                        value_pos: offset,               // 整个entry的读取位置
-                        key: entry.key,
-                    };
-                    debug!("write_hit {:?}", hint_entry);
-                    let hint_bytes: Vec<u8> = hint_entry.into();
-                    merge_hint_file_fd.write_all(&hint_bytes).unwrap();
-
-                    // update keydir index
-                    let merge_metadata = Metadata {
-                        file_id: merge_file_seq,     // 新 file_seq
-                        value_pos: offset,           // 在新文件offset
-                        value_sz: metadata.value_sz, // entry 本身大小不变
-                        tstamp: metadata.tstamp,
-                    };
-
-                    debug!("old_medatdata {:?}", metadata);
-                    debug!("hit_metadata  {:?}", merge_metadata);
-
-                    merge_keydir.data.insert(key.clone(), merge_metadata);
-
-                    // position for next write
-                    offset += metadata.value_sz;
-                    merge_total_size += body_size;
-                }
-                Err(_) => error!("err {:?}", metadata.file_id),
+        // 先停止写入线程，排队的写入会先完成
+        if let Some(mut writer) = self.writer.take() {
+            writer.stop();
+        }
+        // 停止合并线程，已开始替换文件的合并会等待其完成
+        self.merge_stop.store(true, Ordering::SeqCst);
+        let _ = self.sender.send(0);
+        if let Some(handle) = self.merge_handle.take() {
+            if handle.join().is_err() {
+                error!("merge thread panicked");
             }
         }
-
-        // 刷新写盘
-        merge_file_fd.flush().unwrap();
-        merge_hint_file_fd.flush().unwrap();
-
-        // move
-        if !merge_keydir.data.is_empty() {
-            // delete old datafiles
-            let mut files = self.files.write().unwrap();
-            for i in files.keys() {
-                if *i >= active_file_seq {
-                    continue;
-                }
-                let delete_file = self.config.get_filepath_by_seq(*i);
-                debug!("deleted old data file: {:?}", delete_file);
-                fs::remove_file(delete_file).unwrap();
+        if let Some(handle) = self.scrub_handle.take() {
+            if handle.join().is_err() {
+                error!("scrub thread panicked");
             }
-            // 加锁操作
-            // rename merge files
-            for i in 1..(merge_file_seq + 1) {
-                // merge file
-                let from = self.config.get_merge_filepath_by_seq(i);
-                let to = self.config.get_filepath_by_seq(i);
-                let src_path = Path::new(&from);
-                let dst_path = Path::new(&to);
-
-                debug!("{:?} => {:?} File moved successfully!", from, to);
-                std::fs::rename(src_path, dst_path).unwrap();
-
-                // hint file
-                {
-                    let from = self.config.get_merge_hint_filepath_by_seq(i);
-                    let to = self.config.get_hint_filepath_by_seq(i);
-                    let src_path = Path::new(&from);
-                    let dst_path = Path::new(&to);
-
-                    std::fs::rename(src_path, dst_path).unwrap();
-                    debug!("{:?} => {:?} File moved successfully!", from, to);
-                }
+        }
 
-                // 重新打开所有文件句柄，并注册[file_id:fd]
-                let fd = file::open_reader(&dst_path.to_path_buf()).unwrap();
-                files.insert(i, Arc::new(RwLock::new(fd)));
-            }
+        if let Err(e) = self.active_file.write().unwrap().sync_all() {
+            error!("sync active file failed: {:?}", e);
+        }
 
-            // update keydir index
-            let mut keydir = self.keydir.write().unwrap();
-            keydir.extend(merge_keydir.data);
-        } else {
-            // remove empty file
-            fs::remove_file(merge_filepath).unwrap();
+        let fingerprint = self.datafiles_fingerprint();
+        if let Err(e) = self.keydir.write().unwrap().persist(fingerprint) {
+            error!("persist keydir failed: {:?}", e);
+        }
+        if let Err(e) = self.save_snapshot() {
+            error!("save keydir snapshot failed: {:?}", e);
         }
+    }
 
-        // 删除临时合并文件
-        self.config.merge_cleanup();
+    fn compaction(&self) -> Result<(), OpError> {
+        if self.read_only {
+            return Err(OpError::ReadOnly);
+        }
+        merge::merge(
+            &self.config,
+            &self.keydir,
+            &self.files,
+            &self.merge_stop,
+            &self.metrics,
+        )
+    }
 
-        debug!("\n=== COMPACTION END ===\n");
+    // 导入 minkv load 暂存的文件，先归档 active file 使导入的数据比已有数据新
+    fn ingest(&mut self) -> Result<usize, OpError> {
+        self.check_writable()?;
+        if ingest::staged_files(&self.config)?.is_empty() {
+            return Ok(0);
+        }
+        self.writer.as_ref().ok_or(OpError::ReadOnly)?.archive()?;
+        ingest::install_load_files(&self.config, &self.keydir, &self.files, &self.metrics)
     }
 }
 
@@ -881,18 +733,37 @@ pub trait OpKeydir: Sync + Send {
     fn new() -> Self;
     fn get(&self, key: &[u8]) -> Result<Metadata, OpError>;
 
-    fn set(&mut self, key: &[u8], metadata: Metadata);
-    fn remove(&mut self, key: &[u8]);
+    // 写入索引失败时返回错误（disk 实现），调用方不应 panic
+    fn set(&mut self, key: &[u8], metadata: Metadata) -> io::Result<()>;
+    fn remove(&mut self, key: &[u8]) -> io::Result<()>;
     fn len(&self) -> usize;
     fn is_empty(&self) -> bool;
-    fn extend<I>(&mut self, iter: I)
+    fn extend<I>(&mut self, iter: I) -> io::Result<()>
     where
         I: IntoIterator<Item = (Vec<u8>, Metadata)>;
 
-    fn iter(&self) -> Iter<Vec<u8>, Metadata>;
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Metadata)> + '_>;
+
+    fn update_key(&mut self, file_id: u16) -> io::Result<()>;
+    fn keys(&self) -> Vec<Vec<u8>>;
+
+    // 索引占用的堆内存字节数
+    fn memory_usage(&self) -> usize;
 
-    fn update_key(&mut self, file_id: u16);
-    fn keys(&self) -> Vec<&Vec<u8>>;
+    // 启动时尝试沿用持久化的索引，返回 false 时需从 hint 和数据文件重建
+    fn restore(&mut self, _fingerprint: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    // 正常关闭时持久化索引
+    fn persist(&mut self, _fingerprint: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    // 索引自身已持久化时不再需要写快照
+    fn is_persistent(&self) -> bool {
+        false
+    }
 }
 
 impl OpKeydir for Keydir {
@@ -902,19 +773,21 @@ impl OpKeydir for Keydir {
         }
     }
 
-    fn iter(&self) -> Iter<Vec<u8>, Metadata> {
-        self.data.iter()
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Metadata)> + '_> {
+        Box::new(self.data.iter().map(|(k, v)| (k.clone(), v.clone())))
     }
     fn get(&self, key: &[u8]) -> Result<Metadata, OpError> {
         self.data.get(key).cloned().ok_or(OpError::KeyNotFound)
     }
 
-    fn set(&mut self, key: &[u8], metadata: Metadata) {
+    fn set(&mut self, key: &[u8], metadata: Metadata) -> io::Result<()> {
         self.data.insert(key.to_vec(), metadata);
+        Ok(())
     }
 
-    fn remove(&mut self, key: &[u8]) {
+    fn remove(&mut self, key: &[u8]) -> io::Result<()> {
         self.data.remove(key);
+        Ok(())
     }
 
     fn len(&self) -> usize {
@@ -925,28 +798,37 @@ impl OpKeydir for Keydir {
         self.data.is_empty()
     }
 
-    fn keys(&self) -> Vec<&Vec<u8>> {
-        let mut result: Vec<&Vec<u8>> = Vec::with_capacity(self.data.len());
+    fn keys(&self) -> Vec<Vec<u8>> {
+        let mut result: Vec<Vec<u8>> = Vec::with_capacity(self.data.len());
         for k in self.data.keys() {
-            result.push(k);
+            result.push(k.clone());
         }
         result
     }
 
     // 定义 extend 方法，接收一个实现了 IntoIterator trait 的类型
-    fn extend<I>(&mut self, iter: I)
+    fn extend<I>(&mut self, iter: I) -> io::Result<()>
     where
         I: IntoIterator<Item = (Vec<u8>, Metadata)>,
     {
         self.data.extend(iter);
+        Ok(())
     }
 
-    fn update_key(&mut self, file_id: u16) {
+    fn update_key(&mut self, file_id: u16) -> io::Result<()> {
         for metadata in self.data.values_mut() {
             if metadata.file_id == ACTIVE_FILE_SEQ {
                 metadata.file_id = file_id;
             }
         }
+        Ok(())
+    }
+
+    // hashbrown 每个槽位额外占用 1 字节控制位
+    fn memory_usage(&self) -> usize {
+        let slot = std::mem::size_of::<(Vec<u8>, Metadata)>() + 1;
+        let keys: usize = self.data.keys().map(|k| k.capacity()).sum();
+        self.data.capacity() * slot + keys
     }
 }
 
@@ -1033,16 +915,17 @@ impl OpKeydir for Keydir {
 // }
 
 //------ metadata
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Metadata {
-    file_id: u16,
-    value_sz: u64,  // entry size
-    value_pos: u64, // entry pos
-    tstamp: u64,
+    pub(crate) file_id: u16,
+    pub(crate) value_sz: u64,  // entry size
+    pub(crate) value_pos: u64, // entry pos
+    pub(crate) tstamp: u64,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config::test_config;
     use env_logger;
     use std::sync::Once;
 
@@ -1058,6 +941,321 @@ mod tests {
         });
     }
 
+    #[test]
+    fn store_restart_from_snapshot() -> anyhow::Result<()> {
+        use super::{new_store, Op};
+        use crate::entry::entry::Entry;
+        use std::io::Write;
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            store.set(b"a", b"1", 0)?;
+            store.set(b"b", b"2", 0)?;
+            store.set(b"c", b"3", 0)?;
+            store.delete(b"b")?;
+            store.close();
+        }
+        assert!(config.snapshot_filepath().exists());
+
+        // 快照之后追加的数据需要回放
+        let mut active = std::fs::OpenOptions::new()
+            .append(true)
+            .open(config.get_active_filepath())?;
+        active.write_all(&Entry::new(b"d".to_vec(), b"4".to_vec(), 0).as_bytes())?;
+        active.write_all(
+            &Entry::new(b"a".to_vec(), vec![], 0)
+                .set_removed()
+                .as_bytes(),
+        )?;
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert!(!config.snapshot_filepath().exists());
+        assert_eq!(2, store.len());
+        assert!(store.get(b"a").is_err());
+        assert!(store.get(b"b").is_err());
+        assert_eq!(b"3".to_vec(), store.get(b"c")?);
+        assert_eq!(b"4".to_vec(), store.get(b"d")?);
+        Ok(())
+    }
+
+    #[test]
+    fn store_ignores_unremovable_snapshot() -> anyhow::Result<()> {
+        use super::{new_store, Op};
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            store.set(b"a", b"1", 0)?;
+            store.close();
+        }
+        // 快照无法删除时从数据文件加载
+        std::fs::remove_file(config.snapshot_filepath())?;
+        std::fs::create_dir(config.snapshot_filepath())?;
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(b"1".to_vec(), store.get(b"a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn store_get_during_archive_and_merge() -> anyhow::Result<()> {
+        use super::{new_store, Op};
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 512\nmerge_file_num = 4\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        for n in 0..20 {
+            store.set(
+                format!("key{}", n).as_bytes(),
+                format!("key{}-0", n).as_bytes(),
+                0,
+            )?;
+        }
+        // 读取与归档、合并并发时不能读到其它 key 的值
+        std::thread::scope(|s| -> anyhow::Result<()> {
+            let reader = s.spawn(|| -> anyhow::Result<()> {
+                for round in 0..2000 {
+                    let key = format!("key{}", round % 20);
+                    let value = store.get(key.as_bytes())?;
+                    assert!(value.starts_with(format!("{}-", key).as_bytes()));
+                }
+                Ok(())
+            });
+            for round in 1..50 {
+                for n in 0..20 {
+                    let value = format!("key{}-{}", n, round);
+                    store.set(format!("key{}", n).as_bytes(), value.as_bytes(), 0)?;
+                }
+            }
+            reader.join().unwrap()
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn store_read_only_refresh() -> anyhow::Result<()> {
+        use super::{new_store, open_read_only_store, Op};
+        use crate::OpError;
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+
+        let (tx, rx) = mpsc::channel();
+        let writer = new_store(Arc::clone(&config), tx, rx)?;
+        writer.set(b"a", b"1", 0)?;
+
+        let mut reader = open_read_only_store(Arc::clone(&config))?;
+        assert_eq!(b"1".to_vec(), reader.get(b"a")?);
+        assert!(matches!(reader.set(b"b", b"2", 0), Err(OpError::ReadOnly)));
+
+        // 只回放 active file 新追加的数据
+        writer.set(b"b", b"2", 0)?;
+        writer.delete(b"a")?;
+        assert!(reader.get(b"b").is_err());
+        reader.refresh()?;
+        assert!(reader.get(b"a").is_err());
+        assert_eq!(b"2".to_vec(), reader.get(b"b")?);
+
+        // 写入进程归档 active file 后重新加载
+        for i in 0..20 {
+            writer.set(format!("key{}", i).as_bytes(), b"value", 0)?;
+        }
+        assert!(config.get_next_datafile_seq() > 1);
+        reader.refresh()?;
+        assert_eq!(writer.len(), reader.len());
+        assert_eq!(b"value".to_vec(), reader.get(b"key0")?);
+        assert_eq!(b"value".to_vec(), reader.get(b"key19")?);
+        Ok(())
+    }
+
+    #[test]
+    fn store_quarantines_damaged_active_records() -> anyhow::Result<()> {
+        use super::{new_store, Op, OpKeydir};
+        use std::io::{Seek, SeekFrom, Write};
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+
+        let (tx, rx) = mpsc::channel();
+        let mut store = new_store(Arc::clone(&config), tx, rx)?;
+        store.set(b"a", b"1", 0)?;
+        store.set(b"b", b"2", 0)?;
+        store.set(b"c", b"3", 0)?;
+        let damaged = store.keydir.read().unwrap().get(b"b")?;
+        store.close();
+        std::fs::remove_file(config.snapshot_filepath())?;
+
+        // 损坏 b 的 value，并在末尾追加不完整的记录
+        let mut active = std::fs::OpenOptions::new()
+            .write(true)
+            .open(config.get_active_filepath())?;
+        let len = active.metadata()?.len();
+        active.seek(SeekFrom::Start(damaged.value_pos + damaged.value_sz - 1))?;
+        active.write_all(b"X")?;
+        active.seek(SeekFrom::End(0))?;
+        active.write_all(&[1, 2, 3])?;
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(b"1".to_vec(), store.get(b"a")?);
+        assert!(store.get(b"b").is_err());
+        assert_eq!(b"3".to_vec(), store.get(b"c")?);
+        assert_eq!(len, std::fs::metadata(config.get_active_filepath())?.len());
+
+        let quarantine = config.quarantine_dir();
+        assert!(quarantine
+            .join(format!("data-{}.bin", damaged.value_pos))
+            .exists());
+        assert!(quarantine.join(format!("data-{}.bin", len)).exists());
+        assert!(std::fs::read_to_string(quarantine.join("report.log"))?.contains("\tb\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn store_compaction_keeps_latest_values() -> anyhow::Result<()> {
+        use super::{new_store, Op};
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        for round in 0..5 {
+            for n in 0..20 {
+                let value = format!("{}-{}", n, round).into_bytes();
+                store.set(format!("key{}", n).as_bytes(), &value, 0)?;
+            }
+        }
+        store.delete(b"key0")?;
+        assert!(config.get_next_datafile_seq() > 10);
+
+        store.compaction()?;
+        assert!(!config.merge_dir().exists());
+        assert_eq!(19, store.len());
+        assert!(store.get(b"key0").is_err());
+        for n in 1..20 {
+            let value = format!("{}-4", n).into_bytes();
+            assert_eq!(value, store.get(format!("key{}", n).as_bytes())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn store_start_reports_recovery_error() -> anyhow::Result<()> {
+        use super::new_store;
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+        // 无法解析的合并提交记录在启动时返回错误，而不是 panic
+        std::fs::create_dir(config.merge_dir())?;
+        std::fs::write(config.merge_dir().join("MANIFEST"), "garbage")?;
+        let (tx, rx) = mpsc::channel();
+        assert!(matches!(
+            new_store(Arc::clone(&config), tx, rx),
+            Err(crate::OpError::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn store_reports_database_in_use() -> anyhow::Result<()> {
+        use super::new_store;
+        use fs2::FileExt;
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(&dir.path().join("db"), "")?);
+        // 模拟其它写入进程持有所有者锁
+        let other = std::fs::File::create(config.owner_lock_filepath())?;
+        other.try_lock_exclusive()?;
+        let (tx, rx) = mpsc::channel();
+        let err = new_store(Arc::clone(&config), tx, rx).err().unwrap();
+        assert!(err.to_string().contains("database is in use"));
+        Ok(())
+    }
+
+    #[test]
+    fn store_spreads_files_across_data_dirs() -> anyhow::Result<()> {
+        use super::{new_store, Op};
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir()?;
+        let dirs: Vec<_> = ["db", "disk1", "disk2"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let extra = format!(
+            "data_dirs = [{:?}, {:?}]\nfile_max_size = 256\nmerge_file_num = 1000\n",
+            dirs[1].to_str().unwrap(),
+            dirs[2].to_str().unwrap()
+        );
+        let config = Arc::new(test_config(&dirs[0], &extra)?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for round in 0..5 {
+                for n in 0..20 {
+                    let value = format!("{}-{}", n, round).into_bytes();
+                    store.set(format!("key{}", n).as_bytes(), &value, 0)?;
+                }
+            }
+            store.close();
+        }
+        // 新归档的文件分布在所有目录
+        assert!(config.get_next_datafile_seq() > 10);
+        for dir in &dirs {
+            assert!((1..config.get_next_datafile_seq())
+                .any(|seq| config.get_filepath_by_seq(seq).parent() == Some(dir.as_path())));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut store = new_store(Arc::clone(&config), tx, rx)?;
+        store.compaction()?;
+        // 合并生成的 hint 文件与数据文件在同一目录
+        for seq in 1..config.get_next_datafile_seq() {
+            let path = config.get_filepath_by_seq(seq);
+            if path.exists() {
+                assert_eq!(path.parent(), config.get_hint_filepath_by_seq(seq).parent());
+                assert!(config.get_hint_filepath_by_seq(seq).exists());
+            }
+        }
+        store.close();
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(20, store.len());
+        for n in 0..20 {
+            let value = format!("{}-4", n).into_bytes();
+            assert_eq!(value, store.get(format!("key{}", n).as_bytes())?);
+        }
+        Ok(())
+    }
+
     // #[test]
     // fn store_new() {
     //     let store = super::Store::new();
@@ -0,0 +1,899 @@
+use super::file;
+use super::metrics::Metrics;
+use super::store::{Metadata, OpKeydir, StFile, ACTIVE_FILE_SEQ};
+use crate::config::{Config, Durability};
+use crate::util::lock::{self, DirLock};
+use chrono::Utc;
+use log::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+// 一批最多合并的请求数
+const MAX_BATCH: usize = 1024;
+// 合并落后时每批写入的延迟
+const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+// 暂停写入的最长时间，超时后返回错误
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const STALL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+// 请求的合并未完成时再次请求的间隔
+const MERGE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+// 占用达到 max_disk_bytes 的该比例时提前请求合并
+const QUOTA_MERGE_PERCENT: u64 = 90;
+// 归档推迟时 active file 最多增长到 file_max_size 的倍数
+const ARCHIVE_DEFER_FACTOR: u64 = 2;
+
+// 同步调用方阻塞等待结果，服务在异步任务中通过 oneshot 等待，不占用 tokio worker
+enum Reply {
+    Sync(SyncSender<io::Result<()>>),
+    Async(oneshot::Sender<io::Result<()>>),
+}
+
+impl Reply {
+    // 调用方已经放弃等待时丢弃结果
+    fn send(self, result: io::Result<()>) {
+        match self {
+            Reply::Sync(sender) => {
+                let _ = sender.send(result);
+            }
+            Reply::Async(sender) => {
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+// 编码好的 entry，写入并更新 keydir 后通过 reply 返回结果
+struct Request {
+    key: Vec<u8>,
+    bytes: Vec<u8>,
+    removed: bool,
+    reply: Reply,
+}
+
+enum Command {
+    Write(Request),
+    Archive(SyncSender<io::Result<()>>),
+    Flush(SyncSender<io::Result<()>>),
+}
+
+// io::Error 不能 clone，同一批的请求各自返回一份
+fn clone_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("writer thread stopped")
+}
+
+// 阈值为 0 表示不启用
+fn over(value: u64, limit: u64) -> bool {
+    limit > 0 && value >= limit
+}
+
+// 按配置打开 active file 的写入句柄并预分配空间
+fn open_active(config: &Config, path: &PathBuf) -> io::Result<File> {
+    let file = file::new(path, config.open_mode())?;
+    if config.preallocate() {
+        file::preallocate(&file, config.file_max_size() as u64)?;
+    }
+    Ok(file)
+}
+
+// 写入线程持有的状态，active file 只由该线程追加
+pub(crate) struct ActiveLog<K: OpKeydir> {
+    pub(crate) config: Arc<Config>,
+    pub(crate) active_file: Arc<RwLock<File>>,
+    pub(crate) files: Arc<RwLock<HashMap<u16, StFile>>>,
+    pub(crate) keydir: Arc<RwLock<K>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) merge_sender: Sender<i32>,
+    file: File,                              // 写入句柄，active_file 只用于读取
+    tail: Vec<u8>,                           // O_DIRECT 时末尾不足一块的数据
+    size: u64,                               // 当前写入文件大小，预分配时小于文件长度
+    merge_file_num: usize,                   // 上次合并后归档的文件数
+    unsynced: usize,                         // 上次同步后写入的 key 数量
+    active_dead: u64,                        // active file 中的无效数据，归档后计入 dead_bytes
+    merge_requested: Option<(u64, Instant)>, // 背压请求合并时的合并次数和时间
+}
+
+impl<K: OpKeydir + 'static> ActiveLog<K> {
+    pub(crate) fn new(
+        config: Arc<Config>,
+        active_file: Arc<RwLock<File>>,
+        files: Arc<RwLock<HashMap<u16, StFile>>>,
+        keydir: Arc<RwLock<K>>,
+        metrics: Arc<Metrics>,
+        merge_sender: Sender<i32>,
+        size: u64,
+    ) -> io::Result<ActiveLog<K>> {
+        let file = open_active(&config, &config.get_active_filepath())?;
+        let mut tail = Vec::new();
+        if config.open_mode().direct {
+            let start = size - size % file::DIRECT_ALIGN as u64;
+            tail = file::read(&active_file, start, size - start)?;
+        }
+        let mut log = ActiveLog {
+            config,
+            active_file,
+            files,
+            keydir,
+            metrics,
+            merge_sender,
+            file,
+            tail,
+            size,
+            merge_file_num: 0,
+            unsynced: 0,
+            active_dead: 0,
+            merge_requested: None,
+        };
+        log.count_usage()?;
+        Ok(log)
+    }
+
+    // 启动时统计占用空间，并根据索引统计各文件中的无效数据，没有 hint 文件的归档文件视为未合并
+    fn count_usage(&mut self) -> io::Result<()> {
+        let mut live: HashMap<u16, u64> = HashMap::new();
+        for (_, metadata) in self.keydir.read().unwrap().iter() {
+            *live.entry(metadata.file_id).or_default() += metadata.value_sz;
+        }
+
+        let mut dead = 0;
+        let mut unmerged = 0;
+        let mut disk = self.size;
+        for seq in self.files.read().unwrap().keys() {
+            let len = fs::metadata(self.config.get_filepath_by_seq(*seq))?.len();
+            dead += len.saturating_sub(live.get(seq).copied().unwrap_or(0));
+            disk += len;
+            match fs::metadata(self.config.get_hint_filepath_by_seq(*seq)) {
+                Ok(hint) => disk += hint.len(),
+                Err(_) => unmerged += 1,
+            }
+        }
+        let active_live = live.get(&ACTIVE_FILE_SEQ).copied().unwrap_or(0);
+        self.active_dead = self.size.saturating_sub(active_live);
+        self.metrics.dead_bytes.store(dead, Ordering::Relaxed);
+        self.metrics.disk_bytes.store(disk, Ordering::Relaxed);
+        self.metrics
+            .unmerged_files
+            .store(unmerged, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // 取出已经排队的请求一起提交，归档命令之前的写入先提交
+    fn run(mut self, receiver: Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            let mut batch = Vec::new();
+            let mut next = Some(command);
+            while let Some(command) = next.take() {
+                match command {
+                    Command::Write(request) => batch.push(request),
+                    Command::Archive(reply) => {
+                        self.commit(std::mem::take(&mut batch));
+                        let result = if self.size > 0 {
+                            self.archive(true).map(|_| ())
+                        } else {
+                            Ok(())
+                        };
+                        let _ = reply.send(result);
+                    }
+                    Command::Flush(reply) => {
+                        self.commit(std::mem::take(&mut batch));
+                        let _ = reply.send(Ok(()));
+                    }
+                }
+                if batch.len() < MAX_BATCH {
+                    next = receiver.try_recv().ok();
+                }
+            }
+            self.commit(batch);
+        }
+
+        // 截掉预分配和补齐的部分，正常关闭后文件长度与数据一致
+        if let Err(e) = self.file.set_len(self.size) {
+            error!("truncate active file failed: {:?}", e);
+        }
+        debug!("writer thread stopped");
+    }
+
+    // 按文件大小切分写入，成功写入的请求返回成功，其余返回错误
+    fn commit(&mut self, batch: Vec<Request>) {
+        let batch = self.check_quota(batch);
+        if batch.is_empty() {
+            return;
+        }
+        let max = self.config.file_max_size() as u64;
+        let mut written = 0;
+        let mut result = self.throttle();
+        while result.is_ok() && written < batch.len() {
+            if self.size > 0 && self.size + batch[written].bytes.len() as u64 > max {
+                // 只读进程扫描目录时推迟归档，继续写入当前文件，超过两倍大小后才等待
+                let wait = self.size >= max.saturating_mul(ARCHIVE_DEFER_FACTOR);
+                match self.archive(wait) {
+                    Ok(true) => self.trigger_merge(),
+                    Ok(false) => {}
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            // 当前文件可以容纳的请求，至少一个
+            let mut end = written + 1;
+            let mut len = batch[written].bytes.len() as u64;
+            while end < batch.len() && self.size + len + batch[end].bytes.len() as u64 <= max {
+                len += batch[end].bytes.len() as u64;
+                end += 1;
+            }
+            if let Err(e) = self.append(&batch[written..end]) {
+                result = Err(e);
+                break;
+            }
+            written = end;
+        }
+
+        Metrics::incr(&self.metrics.write_batches);
+        for (i, request) in batch.into_iter().enumerate() {
+            let reply = match &result {
+                Err(e) if i >= written => Err(clone_error(e)),
+                _ => Ok(()),
+            };
+            request.reply.send(reply);
+        }
+    }
+
+    // 一次写入多条 entry，按配置同步后依次更新 keydir
+    fn append(&mut self, requests: &[Request]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(requests.iter().map(|r| r.bytes.len()).sum());
+        for request in requests {
+            buf.extend_from_slice(&request.bytes);
+        }
+
+        let mut pos = self.size;
+        let result = if self.config.open_mode().direct {
+            file::write_direct(&self.file, &mut self.tail, &buf, pos)
+        } else {
+            file::write_all_at(&self.file, &buf, pos)
+        };
+        if let Err(err) = result {
+            // 写入失败时截掉不完整的数据，避免后续写入跟在残缺数据之后
+            let _ = self.file.set_len(pos);
+            return Err(err);
+        }
+
+        // O_DSYNC 打开时每次写入已经同步
+        if !self.config.open_mode().dsync {
+            self.unsynced += requests.len();
+            let sync_keys = self.config.get_sync_keys_num() as usize;
+            if self.config.durability() == Durability::Always
+                || (sync_keys > 0 && self.unsynced >= sync_keys)
+            {
+                self.file.sync_data()?;
+                self.unsynced = 0;
+            }
+        }
+        self.size = pos + buf.len() as u64;
+        Metrics::add(&self.metrics.disk_bytes, buf.len() as u64);
+
+        let tstamp = Utc::now().timestamp() as u64;
+        let mut keydir = self.keydir.write().unwrap();
+        for request in requests {
+            // 被覆盖或删除的旧记录成为无效数据
+            if let Ok(old) = keydir.get(&request.key) {
+                if old.file_id == ACTIVE_FILE_SEQ {
+                    self.active_dead += old.value_sz;
+                } else {
+                    Metrics::add(&self.metrics.dead_bytes, old.value_sz);
+                }
+            }
+            let updated = if request.removed {
+                self.active_dead += request.bytes.len() as u64;
+                keydir.remove(&request.key)
+            } else {
+                let metadata = Metadata {
+                    file_id: ACTIVE_FILE_SEQ,
+                    value_sz: request.bytes.len() as u64,
+                    value_pos: pos,
+                    tstamp,
+                };
+                keydir.set(&request.key, metadata)
+            };
+            // 索引与数据文件已不一致，拒绝后续写入，重启后从数据文件恢复
+            if let Err(e) = updated {
+                self.metrics
+                    .enter_readonly(format!("keydir index write failed: {}", e));
+                return Err(e);
+            }
+            pos += request.bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    // rename active file to datafiles，wait 为 false 且目录锁被占用时不归档，返回 false
+    fn archive(&mut self, wait: bool) -> io::Result<bool> {
+        let active_filepath = self.config.get_active_filepath();
+
+        // 替换文件期间阻止只读进程扫描目录
+        let lock_filepath = self.config.lock_filepath();
+        let _lock = if wait {
+            DirLock::exclusive(&lock_filepath)?
+        } else {
+            match DirLock::try_exclusive(&lock_filepath) {
+                Ok(lock) => lock,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("data dir is being scanned, defer archiving");
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        // 归档文件不保留预分配的空间
+        self.file.set_len(self.size)?;
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        // write lock and flush buffer body to disk
+        let mut files = self.files.write().unwrap();
+
+        // 持有锁后再确定序号，避免与正在替换文件的合并使用同一序号
+        let archive_file_seq = self.config.get_next_datafile_seq();
+        // 在 active file 所在目录内改名，不跨设备复制
+        let active_dir = active_filepath.parent().unwrap_or(self.config.data_dir());
+        let archive_filepath = self.config.get_filepath_in(active_dir, archive_file_seq);
+        // 1. rename active file name to archive file
+        let mut fd = self.active_file.write().unwrap();
+
+        fs::rename(&active_filepath, &archive_filepath)?;
+
+        // 2. reopen the file in read-only mode and renew active file
+        // 新的 active file 创建在占用最少的数据目录，以后在该目录归档
+        let new_filepath = self.config.get_new_active_filepath();
+        let reopened = file::open_reader(&archive_filepath).and_then(|reader| {
+            let active_fd = file::new(&new_filepath, file::OpenMode::default())?;
+            Ok((reader, active_fd, open_active(&self.config, &new_filepath)?))
+        });
+        let (reader, active_fd, writer_fd) = match reopened {
+            Ok(v) => v,
+            Err(e) => {
+                // 恢复原来的 active file，仍可继续写入
+                let _ = fs::remove_file(&new_filepath);
+                fs::rename(&archive_filepath, &active_filepath)?;
+                return Err(e);
+            }
+        };
+        files.insert(archive_file_seq, Arc::new(RwLock::new(reader)));
+
+        // update keydir
+        // 文件已经替换，索引写入失败也要完成切换，只拒绝后续写入
+        let updated = self.keydir.write().unwrap().update_key(archive_file_seq);
+        if let Err(e) = &updated {
+            self.metrics
+                .enter_readonly(format!("keydir index write failed: {}", e));
+        }
+        debug!("archive active file => {:?}", archive_filepath);
+
+        *fd = active_fd;
+        self.file = writer_fd;
+        self.tail.clear();
+        self.size = 0;
+        Metrics::add(&self.metrics.dead_bytes, self.active_dead);
+        self.active_dead = 0;
+        Metrics::incr(&self.metrics.unmerged_files);
+        debug!("renew active file {:?}", new_filepath);
+        updated.map(|_| true)
+    }
+
+    // active file 在磁盘上占用的空间，预分配时至少为 file_max_size
+    fn allocated(&self, size: u64) -> u64 {
+        if self.config.preallocate() {
+            size.max(self.config.file_max_size() as u64)
+        } else {
+            size
+        }
+    }
+
+    // 拒绝会使占用超过 max_disk_bytes 的写入，删除记录不受限制以便释放空间
+    // disk_bytes 中 active file 按已写入大小计，这里按预分配后实际占用的大小计算
+    fn check_quota(&mut self, batch: Vec<Request>) -> Vec<Request> {
+        let quota = self.config.max_disk_bytes();
+        if quota == 0 {
+            return batch;
+        }
+        let max = self.config.file_max_size() as u64;
+        let mut size = self.size;
+        let mut used =
+            Metrics::get(&self.metrics.disk_bytes).saturating_sub(size) + self.allocated(size);
+        // 接近上限且有可回收的数据时提前合并
+        if used >= quota / 100 * QUOTA_MERGE_PERCENT && Metrics::get(&self.metrics.dead_bytes) > 0 {
+            self.request_merge();
+        }
+
+        let mut accepted = Vec::with_capacity(batch.len());
+        for request in batch {
+            let len = request.bytes.len() as u64;
+            // 写满时归档，归档文件截掉预分配的空间，新的 active file 重新预分配
+            let (next_size, next_used) = if size > 0 && size + len > max {
+                (
+                    len,
+                    used - self.allocated(size) + size + self.allocated(len),
+                )
+            } else {
+                (
+                    size + len,
+                    used - self.allocated(size) + self.allocated(size + len),
+                )
+            };
+            if request.removed || next_used <= quota {
+                size = next_size;
+                used = next_used;
+                accepted.push(request);
+                continue;
+            }
+            Metrics::incr(&self.metrics.quota_rejections);
+            request.reply.send(Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "disk quota exceeded: {} bytes used, writing {} bytes would exceed max_disk_bytes {}",
+                    used, len, quota
+                ),
+            )));
+        }
+        accepted
+    }
+
+    // 合并落后时减慢或暂停写入，暂停超时返回错误
+    fn throttle(&mut self) -> io::Result<()> {
+        let config = Arc::clone(&self.config);
+        let c = config.get_compaction();
+        let files = Metrics::get(&self.metrics.unmerged_files);
+        let dead = Metrics::get(&self.metrics.dead_bytes);
+        if over(files, c.stall_files()) || over(dead, c.stall_dead_bytes()) {
+            Metrics::incr(&self.metrics.write_stalls);
+            warn!(
+                "write stalled: {} unmerged files, {} dead bytes",
+                files, dead
+            );
+            let deadline = Instant::now() + STALL_TIMEOUT;
+            loop {
+                self.request_merge();
+                let files = Metrics::get(&self.metrics.unmerged_files);
+                let dead = Metrics::get(&self.metrics.dead_bytes);
+                if !over(files, c.stall_files()) && !over(dead, c.stall_dead_bytes()) {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(io::Error::other(format!(
+                        "write stalled: {} unmerged files, {} dead bytes, waiting for compaction",
+                        files, dead
+                    )));
+                }
+                thread::sleep(STALL_CHECK_INTERVAL);
+            }
+        } else if over(files, c.slowdown_files()) || over(dead, c.slowdown_dead_bytes()) {
+            Metrics::incr(&self.metrics.write_slowdowns);
+            self.request_merge();
+            thread::sleep(SLOWDOWN_DELAY);
+        }
+        Ok(())
+    }
+
+    // 上次请求的合并完成或超时后才再次请求，避免重复合并
+    fn request_merge(&mut self) {
+        let merges = Metrics::get(&self.metrics.merges);
+        if let Some((requested, at)) = self.merge_requested {
+            if merges == requested && at.elapsed() < MERGE_RETRY_INTERVAL {
+                return;
+            }
+        }
+        if self.merge_sender.send(0).is_err() {
+            warn!("merge thread exited, skip merge");
+            return;
+        }
+        self.merge_requested = Some((merges, Instant::now()));
+    }
+
+    // merge archived datafiles in a new thread
+    fn trigger_merge(&mut self) {
+        self.merge_file_num += 1;
+        debug!(
+            "{} : {}",
+            self.merge_file_num,
+            self.config.get_merge_file_num()
+        );
+        if self.merge_file_num >= self.config.get_merge_file_num() {
+            self.merge_file_num = 0;
+
+            // Avoiding duplicate merges caused by generating new files too quickly
+            if lock::Locker::acquire().is_ok() {
+                if self.merge_sender.send(0).is_err() {
+                    warn!("merge thread exited, skip merge");
+                }
+            } else {
+                debug!("发现 lock 文件，正在合并中...");
+            }
+        }
+    }
+}
+
+// 已排队的写入，在异步任务中等待结果
+pub(crate) struct Submitted(oneshot::Receiver<io::Result<()>>);
+
+impl Submitted {
+    pub(crate) async fn wait(self) -> io::Result<()> {
+        self.0.await.map_err(|_| stopped())?
+    }
+}
+
+// 所有写入经由单独的线程按到达顺序批量追加到 active file，每批只写一次、最多同步一次
+pub(crate) struct Writer {
+    sender: Option<Sender<Command>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    pub(crate) fn start<K: OpKeydir + 'static>(log: ActiveLog<K>) -> Writer {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || log.run(receiver));
+        Writer {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.sender
+            .as_ref()
+            .ok_or_else(stopped)?
+            .send(command)
+            .map_err(|_| stopped())
+    }
+
+    // 所在批次写入（durability = always 时同步）并更新 keydir 后返回
+    pub(crate) fn write(&self, key: &[u8], bytes: Vec<u8>, removed: bool) -> io::Result<()> {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(Command::Write(Request {
+            key: key.to_vec(),
+            bytes,
+            removed,
+            reply: Reply::Sync(reply),
+        }))?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+
+    // 只排队不等待，所在批次写入并更新 keydir 后 Submitted 完成
+    pub(crate) fn submit(
+        &self,
+        key: &[u8],
+        bytes: Vec<u8>,
+        removed: bool,
+    ) -> io::Result<Submitted> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(Command::Write(Request {
+            key: key.to_vec(),
+            bytes,
+            removed,
+            reply: Reply::Async(reply),
+        }))?;
+        Ok(Submitted(receiver))
+    }
+
+    // 归档当前 active file，之前排队的写入先提交
+    pub(crate) fn archive(&self) -> io::Result<()> {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(Command::Archive(reply))?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+
+    // 之前排队的写入都提交并更新 keydir 后返回，写入结果仍由各自的 reply 返回
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(Command::Flush(reply))?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+
+    // 处理完已排队的请求后退出
+    pub(crate) fn stop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("writer thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::compact::disk_usage;
+    use crate::config::test_config;
+    use crate::entry::entry::EntryFile;
+    use crate::store::metrics::Metrics;
+    use crate::store::store::{new_store, open_store, Op, ACTIVE_FILE_SEQ};
+    use crate::util::lock::DirLock;
+    use crate::OpError;
+    use std::fs::File;
+    use std::io;
+    use std::sync::atomic::Ordering;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_writes_share_batches() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 4096\nmerge_file_num = 1000\ndurability = \"always\"\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let store = open_store(Arc::clone(&config), tx, rx)?;
+
+        // 只持有读锁并发写入
+        thread::scope(|s| {
+            for t in 0..8 {
+                let store = &store;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let key = format!("key{}-{}", t, i);
+                        store
+                            .read()
+                            .unwrap()
+                            .set(key.as_bytes(), key.as_bytes(), 0)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        store.read().unwrap().delete(b"key0-0")?;
+
+        let store = store.read().unwrap();
+        assert_eq!(399, store.len());
+        for t in 0..8 {
+            for i in 0..50 {
+                let key = format!("key{}-{}", t, i);
+                if (t, i) == (0, 0) {
+                    assert!(store.get(key.as_bytes()).is_err());
+                } else {
+                    assert_eq!(key.as_bytes(), store.get(key.as_bytes())?);
+                }
+            }
+        }
+        let metrics = store.metrics();
+        assert_eq!(401, metrics.writes.load(Ordering::SeqCst));
+        assert!(metrics.write_batches.load(Ordering::SeqCst) <= 401);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn submitted_writes_complete_in_order() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 4096\nmerge_file_num = 1000\ndurability = \"always\"\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let store = open_store(Arc::clone(&config), tx, rx)?;
+
+        // 先全部提交再等待，同一个 key 以最后提交的为准
+        let mut writes = Vec::new();
+        {
+            let store = store.read().unwrap();
+            for i in 0..100 {
+                writes.push(store.submit_set(b"key", format!("v{}", i).as_bytes(), 0)?);
+            }
+            writes.push(store.submit_set(b"gone", b"v", 0)?);
+            writes.push(store.submit_delete(b"gone")?);
+        }
+        for write in writes {
+            write.wait().await?;
+        }
+
+        let store = store.read().unwrap();
+        assert_eq!(b"v99".to_vec(), store.get(b"key")?);
+        assert!(store.get(b"gone").is_err());
+        assert_eq!(102, store.metrics().writes.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn archive_deferred_while_dir_is_scanned() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+
+        // 只读进程持有共享锁时继续写入 active file
+        let lock = DirLock::shared(&config.lock_filepath())?;
+        for n in 0..8 {
+            store.set(format!("key{}", n).as_bytes(), &[b'x'; 40], 0)?;
+        }
+        assert_eq!(1, config.get_next_datafile_seq());
+        drop(lock);
+
+        store.set(b"key8", &[b'x'; 40], 0)?;
+        assert_eq!(2, config.get_next_datafile_seq());
+        for n in 0..9 {
+            assert_eq!(vec![b'x'; 40], store.get(format!("key{}", n).as_bytes())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn backpressure_waits_for_compaction() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\n\
+             [compaction]\nrate = 2048\nslowdown_files = 2\nstall_files = 4\n",
+        )?);
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            for round in 0..10 {
+                for n in 0..20 {
+                    let value = format!("{}-{}", n, round).into_bytes();
+                    store.set(format!("key{}", n).as_bytes(), &value, 0)?;
+                }
+            }
+            let metrics = store.metrics();
+            assert!(Metrics::get(&metrics.write_slowdowns) > 0);
+            assert!(Metrics::get(&metrics.write_stalls) > 0);
+            assert!(Metrics::get(&metrics.merges) > 0);
+            assert!(Metrics::get(&metrics.unmerged_files) < 4);
+            store.close();
+        }
+
+        // 重新统计的无效数据在合并后全部回收
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert!(Metrics::get(&store.metrics().dead_bytes) > 0);
+        store.compaction()?;
+        assert_eq!(0, Metrics::get(&store.metrics().dead_bytes));
+        assert_eq!(0, Metrics::get(&store.metrics().unmerged_files));
+        for n in 0..20 {
+            let value = format!("{}-9", n).into_bytes();
+            assert_eq!(value, store.get(format!("key{}", n).as_bytes())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn quota_rejects_writes_until_space_is_freed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 256\nmerge_file_num = 1000\nmax_disk_bytes = 2048\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let mut store = new_store(Arc::clone(&config), tx, rx)?;
+
+        let value = [b'v'; 32];
+        let mut n = 0;
+        let err = loop {
+            match store.set(format!("key{}", n).as_bytes(), &value, 0) {
+                Ok(_) => n += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(&err, OpError::Io(e) if e.kind() == io::ErrorKind::QuotaExceeded));
+        assert!(!store.metrics().is_readonly());
+        assert_eq!(1, Metrics::get(&store.metrics().quota_rejections));
+        assert!(Metrics::get(&store.metrics().disk_bytes) <= 2048);
+
+        // 删除不受配额限制，合并回收空间后可以继续写入
+        for i in 1..n {
+            store.delete(format!("key{}", i).as_bytes())?;
+        }
+        // 合并线程可能正在执行提前请求的合并，此时 compaction 直接返回
+        for _ in 0..100 {
+            store.compaction()?;
+            if Metrics::get(&store.metrics().disk_bytes) < 1024 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        store.set(format!("key{}", n).as_bytes(), &value, 0)?;
+        store.close();
+        assert_eq!(
+            disk_usage(&config)?.bytes,
+            Metrics::get(&store.metrics().disk_bytes)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quota_counts_preallocated_space() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 1024\nmerge_file_num = 1000\npreallocate = true\nmax_disk_bytes = 1500\n",
+        )?);
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+
+        // 预分配的 active file 已占用 file_max_size，写满后归档再预分配会超出上限
+        let value = [b'v'; 32];
+        let mut n = 0;
+        let err = loop {
+            match store.set(format!("key{}", n).as_bytes(), &value, 0) {
+                Ok(_) => n += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(&err, OpError::Io(e) if e.kind() == io::ErrorKind::QuotaExceeded));
+        assert!(n > 0);
+        assert!(disk_usage(&config)?.bytes <= 1500);
+        Ok(())
+    }
+
+    #[test]
+    fn preallocated_tail_is_ignored() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Arc::new(test_config(
+            &dir.path().join("db"),
+            "file_max_size = 4096\nmerge_file_num = 1000\npreallocate = true\no_dsync = true\n",
+        )?);
+        let active = config.get_active_filepath();
+        {
+            let (tx, rx) = mpsc::channel();
+            let mut store = new_store(Arc::clone(&config), tx, rx)?;
+            store.set(b"a", b"1", 0)?;
+            store.set(b"b", b"2", 0)?;
+            assert_eq!(4096, std::fs::metadata(&active)?.len());
+            store.close();
+        }
+        // 关闭后手动补回预分配的 0 并删除快照，模拟进程崩溃时末尾仍有预分配的空间
+        let len = std::fs::metadata(&active)?.len();
+        assert!(len < 4096);
+        std::fs::remove_file(config.snapshot_filepath())?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&active)?
+            .set_len(4096)?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut store = new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(2, store.len());
+        // 新写入紧接在有效数据之后，不在补齐的 0 之后
+        store.set(b"c", b"3", 0)?;
+        let metadata = store.metadata(b"c").unwrap();
+        assert_eq!(
+            (ACTIVE_FILE_SEQ, len),
+            (metadata.file_id, metadata.value_pos)
+        );
+        for i in 0..200 {
+            store.set(format!("key{}", i).as_bytes(), b"value", 0)?;
+        }
+        assert_eq!(b"1".to_vec(), store.get(b"a")?);
+        assert_eq!(b"3".to_vec(), store.get(b"c")?);
+        store.close();
+
+        // 归档文件和正常关闭后的 active file 都不含补齐的 0
+        let archived = config.get_filepath_by_seq(1);
+        assert!(std::fs::metadata(&archived)?.len() < 4096);
+        let mut entries = EntryFile::new(File::open(&active)?);
+        let count = entries.iter().count() as u64;
+        assert!(count > 0 && entries.damaged().is_empty());
+
+        let (tx, rx) = mpsc::channel();
+        let store = new_store(Arc::clone(&config), tx, rx)?;
+        assert_eq!(203, store.len());
+        assert_eq!(b"value".to_vec(), store.get(b"key199")?);
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ use regex::Regex;
 use std::fs;
 use std::path::{Path, PathBuf};
 pub mod lock;
+pub mod rate;
 pub mod time;
 
 pub fn get_files_in_directory(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
@@ -68,7 +69,7 @@ pub fn parse_seq_from_filename(filename: PathBuf) -> usize {
     let main_filename = filename.file_stem().unwrap().to_string_lossy();
 
     // 提取数字部分
-    let number_str = main_filename.split('-').last().unwrap(); // 取最后一个部分
+    let number_str = main_filename.split('-').next_back().unwrap(); // 取最后一个部分
     let number: u32 = number_str.parse().unwrap();
 
     println!("main_filename: {:?}", main_filename);
@@ -92,3 +93,13 @@ pub fn match_key(search_str: &str, key: &str) -> bool {
         Err(_) => false,
     }
 }
+
+// FNV-1a 哈希，结果在不同 Rust 版本间保持稳定，可用于持久化
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -1,6 +1,8 @@
 use fs2::FileExt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Error, ErrorKind};
+use std::io::{self, Error};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
 
 pub struct Locker(File);
 
@@ -17,7 +19,7 @@ impl Locker {
         // 尝试获取独占锁
         match file.try_lock_exclusive() {
             Ok(_) => Ok(Locker(file)),
-            Err(_) => Err(Error::new(ErrorKind::Other, "The lock has been used")),
+            Err(_) => Err(Error::other("The lock has been used")),
         }
     }
 
@@ -27,6 +29,90 @@ impl Locker {
     }
 }
 
+// 数据目录锁，只读进程扫描文件时持有共享锁，写入进程替换数据文件时持有独占锁
+pub struct DirLock(File);
+
+impl DirLock {
+    pub fn shared(path: &Path) -> io::Result<Self> {
+        let file = open_lock_file(path)?;
+        file.lock_shared()?;
+        Ok(DirLock(file))
+    }
+
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        let file = open_lock_file(path)?;
+        file.lock_exclusive()?;
+        Ok(DirLock(file))
+    }
+
+    // 不等待，其它进程持有锁时返回 WouldBlock
+    pub fn try_exclusive(path: &Path) -> io::Result<Self> {
+        let file = open_lock_file(path)?;
+        file.try_lock_exclusive()?;
+        Ok(DirLock(file))
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+// 数据目录的所有者锁，服务和离线写入工具在整个运行期间持有
+// flock 对同一进程的不同 fd 也互斥，因此同一进程内按路径共享
+pub struct OwnerLock(File);
+
+static OWNERS: Mutex<Vec<(PathBuf, Weak<OwnerLock>)>> = Mutex::new(Vec::new());
+
+impl OwnerLock {
+    // 不等待，其它进程持有锁时返回 WouldBlock
+    pub fn acquire(path: &Path) -> io::Result<Arc<OwnerLock>> {
+        let mut owners = OWNERS.lock().unwrap();
+        owners.retain(|(_, owner)| owner.strong_count() > 0);
+        if let Some(owner) = owners
+            .iter()
+            .find(|(p, _)| p == path)
+            .and_then(|(_, owner)| owner.upgrade())
+        {
+            return Ok(owner);
+        }
+
+        let file = open_lock_file(path)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() != fs2::lock_contended_error().kind() {
+                return Err(e);
+            }
+            return Err(Error::new(
+                e.kind(),
+                format!(
+                    "database is in use: {} is held by another process",
+                    path.display()
+                ),
+            ));
+        }
+        let owner = Arc::new(OwnerLock(file));
+        owners.push((path.to_path_buf(), Arc::downgrade(&owner)));
+        Ok(owner)
+    }
+}
+
+impl Drop for OwnerLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+// 只读进程可能没有写权限，锁文件已存在时只读打开
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            OpenOptions::new().append(true).create(true).open(path)
+        }
+        result => result,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,4 +142,19 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn owner_lock_shared_in_process() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("OWNER");
+        let owner = OwnerLock::acquire(&path)?;
+        assert!(Arc::ptr_eq(&owner, &OwnerLock::acquire(&path)?));
+
+        // 其它进程打开的 fd 无法获取
+        let other = open_lock_file(&path)?;
+        assert!(other.try_lock_exclusive().is_err());
+        drop(owner);
+        other.try_lock_exclusive()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// 按配置的速率（字节/秒）限制读写，0 表示不限速
+pub struct RateLimiter {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    // 返回 false 表示等待期间收到退出通知
+    pub fn consume(&mut self, n: u64, stop: &AtomicBool) -> bool {
+        self.bytes += n;
+        if self.rate == 0 {
+            return true;
+        }
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            return sleep_unless_stopped(expected - elapsed, stop);
+        }
+        true
+    }
+}
+
+// 分段休眠以便及时响应退出，返回 false 表示收到退出通知
+pub fn sleep_unless_stopped(duration: Duration, stop: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::sleep((deadline - now).min(STOP_CHECK_INTERVAL));
+    }
+    false
+}